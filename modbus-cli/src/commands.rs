@@ -0,0 +1,389 @@
+use std::io::Write;
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+use tokio::time::Instant;
+use tweakable_modbus::{
+    DeviceInventory, ModbusAddress, ModbusDataType, ModbusMasterConnection, Pacing, PollGroup,
+    Poller, Quality, ScanOptions, Scanner,
+};
+
+use crate::output::{Format, Report};
+use crate::values::{parse_bit, Table, ValueArgs};
+
+fn values_report() -> Report {
+    Report::new(vec!["unit", "table", "address", "value"])
+}
+
+//Coils and discrete inputs as bits, registers grouped into values of the asked type
+pub async fn read(
+    master: &mut ModbusMasterConnection,
+    unit: u8,
+    table: Table,
+    address: u16,
+    count: u16,
+    value: &ValueArgs,
+) -> Result<Report> {
+    let mut report = values_report();
+
+    if table.is_bits() {
+        let bits = match table {
+            Table::Coil => master.read_coils(unit, address, count).await?,
+            _ => master.read_discrete_inputs(unit, address, count).await?,
+        };
+        for (offset, bit) in bits.into_iter().enumerate() {
+            report.push(vec![
+                json!(unit),
+                json!(table.name()),
+                json!(address as usize + offset),
+                json!(bit),
+            ]);
+        }
+        return Ok(report);
+    }
+
+    let width = value.ty.registers();
+    let ammount = count
+        .checked_mul(width)
+        .ok_or_else(|| anyhow!("{} values of {:?} don't fit in a table", count, value.ty))?;
+    let registers = match table {
+        Table::Holding => {
+            master
+                .read_holding_registers(unit, address, ammount)
+                .await?
+        }
+        _ => master.read_input_registers(unit, address, ammount).await?,
+    };
+
+    for (index, registers) in registers.chunks(width as usize).enumerate() {
+        let address = address as usize + index * width as usize;
+        let decoded = value.ty.decode(registers, value.order);
+        report.push(vec![
+            json!(unit),
+            json!(table.name()),
+            json!(address),
+            decoded,
+        ]);
+    }
+    Ok(report)
+}
+
+//A single value gets a single write, more than one a multiple write. Reports what was written
+pub async fn write(
+    master: &mut ModbusMasterConnection,
+    unit: u8,
+    table: Table,
+    address: u16,
+    values: &[String],
+    value: &ValueArgs,
+) -> Result<Report> {
+    let mut report = values_report();
+
+    match table {
+        Table::Coil => {
+            let bits = values
+                .iter()
+                .map(|bit| parse_bit(bit))
+                .collect::<Result<Vec<bool>>>()?;
+            match bits.as_slice() {
+                [bit] => master.write_single_coil(unit, address, *bit).await?,
+                _ => {
+                    master
+                        .write_multiple_coils(unit, address, bits.clone())
+                        .await?
+                }
+            }
+            for (offset, bit) in bits.into_iter().enumerate() {
+                report.push(vec![
+                    json!(unit),
+                    json!(table.name()),
+                    json!(address as usize + offset),
+                    json!(bit),
+                ]);
+            }
+        }
+        Table::Holding => {
+            let mut registers = vec![];
+            for text in values {
+                let encoded = value.ty.encode(text, value.order)?;
+                let written = value.ty.decode(&encoded, value.order);
+                let address = address as usize + registers.len();
+                report.push(vec![
+                    json!(unit),
+                    json!(table.name()),
+                    json!(address),
+                    written,
+                ]);
+                registers.extend(encoded);
+            }
+            match registers.as_slice() {
+                [register] => {
+                    master
+                        .write_single_register(unit, address, *register)
+                        .await?
+                }
+                _ => {
+                    master
+                        .write_multiple_registers(unit, address, registers)
+                        .await?
+                }
+            }
+        }
+        _ => return Err(anyhow!("Only coils and holding registers can be written")),
+    }
+
+    Ok(report)
+}
+
+pub struct PollRead {
+    pub unit: u8,
+    pub table: Table,
+    pub address: u16,
+    pub count: u16,
+    pub value: ValueArgs,
+}
+
+fn quality_name(quality: &Quality) -> String {
+    match quality {
+        Quality::Good => "good".to_string(),
+        Quality::Exception(code) => format!("exception {:?}", code),
+        quality => format!("{:?}", quality).to_lowercase(),
+    }
+}
+
+//Polls until the cycles are done, printing a report every cycle with the last known values
+pub async fn poll(
+    master: ModbusMasterConnection,
+    read: PollRead,
+    interval: Duration,
+    cycles: Option<u64>,
+    format: Format,
+    out: &mut (dyn Write + Send),
+) -> Result<()> {
+    let width = if read.table.is_bits() {
+        1
+    } else {
+        read.value.ty.registers()
+    };
+    let ammount = read.count.checked_mul(width).ok_or_else(|| {
+        anyhow!(
+            "{} values of {:?} don't fit in a table",
+            read.count,
+            read.value.ty
+        )
+    })?;
+    if read.address as u32 + ammount as u32 > u16::MAX as u32 + 1 {
+        return Err(anyhow!(
+            "{} values of {:?} from address {} go past address {}",
+            read.count,
+            read.value.ty,
+            read.address,
+            u16::MAX
+        ));
+    }
+
+    let mut group = PollGroup::new(interval);
+    group.add_read(read.unit, read.table.modbus_table(), read.address, ammount);
+    let mut poller = Poller::new(master);
+    poller.add_group(group);
+    let snapshot = poller.snapshot();
+
+    let started = Instant::now();
+    let mut cycle = 0;
+    while cycles.is_none_or(|cycles| cycle < cycles) {
+        poller.poll_next().await;
+
+        let mut report = Report::new(vec![
+            "elapsed", "unit", "table", "address", "value", "quality",
+        ]);
+        let elapsed = format!("{:.3}", started.elapsed().as_secs_f64());
+
+        for index in 0..read.count {
+            let address = read.address as u32 + (index * width) as u32;
+            let polled: Vec<_> = (address..address + width as u32)
+                .map(|address| {
+                    snapshot.get(&ModbusAddress::new(
+                        read.unit,
+                        read.table.modbus_table(),
+                        address as u16,
+                    ))
+                })
+                .collect();
+
+            let quality = polled
+                .iter()
+                .map(|polled| polled.map_or(Quality::CommFailure, |polled| polled.quality))
+                .find(|quality| *quality != Quality::Good)
+                .unwrap_or(Quality::Good);
+            let words: Option<Vec<ModbusDataType>> = polled
+                .iter()
+                .map(|polled| polled.and_then(|polled| polled.value))
+                .collect();
+
+            let value = match words {
+                Some(words) if read.table.is_bits() => {
+                    json!(words[0] == ModbusDataType::Coil(true))
+                }
+                Some(words) => {
+                    let registers: Vec<u16> = words
+                        .iter()
+                        .map(ModbusDataType::get_representation)
+                        .collect();
+                    read.value.ty.decode(&registers, read.value.order)
+                }
+                None => Value::Null,
+            };
+
+            report.push(vec![
+                json!(elapsed),
+                json!(read.unit),
+                json!(read.table.name()),
+                json!(address),
+                value,
+                json!(quality_name(&quality)),
+            ]);
+        }
+
+        report.print(format, out, cycle == 0)?;
+        out.flush()?;
+        cycle += 1;
+    }
+
+    Ok(())
+}
+
+pub struct ScanSettings {
+    pub units: RangeInclusive<u8>,
+    //Table and address of the read each unit is probed with
+    pub table: Table,
+    pub address: u16,
+    //Tables whose valid address ranges are searched on every unit found
+    pub tables: Vec<Table>,
+    pub concurrency: usize,
+    pub min_gap: Option<Duration>,
+}
+
+//Units that answer the probe read, an exception counts as an answer unless it comes from a gateway
+//that couldn't reach the unit. Losing the endpoint ends the scan
+pub async fn scan(mut master: ModbusMasterConnection, settings: ScanSettings) -> Result<Report> {
+    //Units scanned at once only overlap on the wire if the master lets them
+    let mut params = master.get_params();
+    params.max_simultaneous_transactions = settings.concurrency.max(1) as u32;
+    master.set_params(params);
+
+    let options = ScanOptions {
+        units: settings.units,
+        probe_table: settings.table.modbus_table(),
+        probe_address: settings.address,
+        tables: settings
+            .tables
+            .iter()
+            .map(|table| table.modbus_table())
+            .collect(),
+        max_concurrent_units: settings.concurrency,
+        pacing: settings.min_gap.map(|min_request_gap| Pacing {
+            min_request_gap,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let inventory = Scanner::new(master, options).scan().await?;
+
+    Ok(scan_report(inventory, &settings.tables))
+}
+
+//A column per searched table holds its valid ranges
+fn scan_report(inventory: DeviceInventory, tables: &[Table]) -> Report {
+    let columns = ["unit", "status"]
+        .into_iter()
+        .chain(tables.iter().map(|table| table.name()))
+        .collect();
+    let mut report = Report::new(columns);
+
+    for unit in inventory.units {
+        let status = match unit.probe_exception {
+            Some(code) => format!("exception {:?}", code),
+            None => "ok".to_string(),
+        };
+        let mut row = vec![json!(unit.slave_id), json!(status)];
+
+        for table in tables {
+            let ranges = match unit.tables.get(&table.modbus_table()) {
+                Some(Ok(ranges)) => ranges
+                    .iter()
+                    .map(|range| format!("{}-{}", range.start(), range.end()))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                Some(Err(err)) => err.to_string(),
+                None => String::new(),
+            };
+            row.push(json!(ranges));
+        }
+        report.push(row);
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::values::ValueType;
+    use std::collections::BTreeMap;
+    use tweakable_modbus::{ExceptionCode, ModbusError, ModbusTable, ScannedUnit};
+
+    #[test]
+    fn test_scan_report_lists_ranges_by_table() {
+        let tables = BTreeMap::from([
+            (ModbusTable::HoldingRegisters, Ok(vec![0..=9, 100..=119])),
+            (
+                ModbusTable::InputRegisters,
+                Err(ModbusError::Exception(ExceptionCode::IllegalFunction)),
+            ),
+        ]);
+        let inventory = DeviceInventory {
+            units: vec![ScannedUnit {
+                slave_id: 1,
+                probe_exception: None,
+                tables,
+            }],
+        };
+
+        let report = scan_report(inventory, &[Table::Holding, Table::Input]);
+        let mut out = vec![];
+        report.print(Format::Csv, &mut out, true).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let mut lines = out.lines();
+        assert_eq!(lines.next(), Some("unit,status,hr,ir"));
+        assert!(lines.next().unwrap().starts_with("1,ok,0-9 100-119,"));
+    }
+
+    #[tokio::test]
+    async fn test_poll_refuses_reads_past_the_last_address() {
+        let master = ModbusMasterConnection::new_tcp("127.0.0.1:502".parse().unwrap());
+        let read = PollRead {
+            unit: 1,
+            table: Table::Holding,
+            address: 65535,
+            count: 1,
+            value: ValueArgs {
+                ty: ValueType::F32,
+                ..Default::default()
+            },
+        };
+
+        let mut out = vec![];
+        let polled = poll(
+            master,
+            read,
+            Duration::from_secs(1),
+            Some(1),
+            Format::Csv,
+            &mut out,
+        );
+        assert!(polled.await.is_err());
+        assert!(out.is_empty());
+    }
+}
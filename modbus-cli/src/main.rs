@@ -0,0 +1,334 @@
+use std::io::Write;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand};
+
+use crate::output::Format;
+use crate::target::{parse_duration, LinkArgs, Target};
+use crate::values::{Table, ValueArgs};
+
+mod commands;
+mod output;
+mod serve;
+mod target;
+mod values;
+
+//Field tool for Modbus devices, every command goes through the tweakable-modbus master and server
+#[derive(Parser, Debug)]
+#[command(
+    name = "modbus",
+    version,
+    about = "Reads, writes, polls, scans and serves Modbus devices"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+
+    #[arg(long, global = true, value_enum, default_value_t = Format::Table)]
+    format: Format,
+
+    #[command(flatten)]
+    link: LinkArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    //Reads values once, e.g. `read hr 1@192.168.1.10:502 100 10 --type f32`
+    #[command(about = "Read values once")]
+    Read {
+        table: Table,
+        target: Target,
+        address: u16,
+        #[arg(default_value_t = 1)]
+        count: u16,
+        #[command(flatten)]
+        value: ValueArgs,
+    },
+    //Writes the values starting at the address, more than one gives a multiple write
+    #[command(about = "Write one or more values")]
+    Write {
+        table: Table,
+        target: Target,
+        address: u16,
+        #[arg(required = true, num_args = 1.., allow_negative_numbers = true)]
+        values: Vec<String>,
+        #[command(flatten)]
+        value: ValueArgs,
+    },
+    #[command(about = "Read values over and over")]
+    Poll {
+        table: Table,
+        target: Target,
+        address: u16,
+        #[arg(default_value_t = 1)]
+        count: u16,
+        #[arg(long, default_value = "500ms", value_parser = parse_duration)]
+        interval: Duration,
+        //Polls until interrupted when not given
+        #[arg(long)]
+        cycles: Option<u64>,
+        #[command(flatten)]
+        value: ValueArgs,
+    },
+    #[command(about = "Find the unit ids that answer on an endpoint")]
+    Scan {
+        endpoint: String,
+        #[arg(long, default_value = "1-247", value_parser = parse_units)]
+        units: RangeInclusive<u8>,
+        //Table and address of the read each unit is probed with
+        #[arg(long, default_value = "hr")]
+        table: Table,
+        #[arg(long, default_value_t = 0)]
+        address: u16,
+        //Tables whose valid address ranges are searched on every unit found, e.g. `--tables hr,ir`
+        #[arg(long, value_delimiter = ',')]
+        tables: Vec<Table>,
+        //Units scanned at once, serial gateways should be left at 1
+        #[arg(long, default_value_t = 1)]
+        concurrency: usize,
+        //Quiet time before every request, for gateways that can't keep up
+        #[arg(long, value_parser = parse_duration)]
+        min_gap: Option<Duration>,
+    },
+    #[command(about = "Serve the values of a map file")]
+    Serve {
+        endpoint: String,
+        #[arg(long)]
+        map: PathBuf,
+    },
+}
+
+fn parse_units(units: &str) -> Result<RangeInclusive<u8>> {
+    let (first, last) = units.split_once('-').unwrap_or((units, units));
+    let (first, last): (u8, u8) = (first.trim().parse()?, last.trim().parse()?);
+
+    if first > last {
+        return Err(anyhow!("{} comes after {}", first, last));
+    }
+    Ok(first..=last)
+}
+
+async fn run(cli: Cli, out: &mut (dyn Write + Send)) -> Result<()> {
+    let Cli {
+        command,
+        format,
+        link,
+    } = cli;
+
+    match command {
+        Command::Read {
+            table,
+            target,
+            address,
+            count,
+            value,
+        } => {
+            let mut master = link.master(&target.endpoint)?;
+            let report =
+                commands::read(&mut master, target.unit, table, address, count, &value).await?;
+            report.print(format, out, true)?;
+        }
+        Command::Write {
+            table,
+            target,
+            address,
+            values,
+            value,
+        } => {
+            let mut master = link.master(&target.endpoint)?;
+            let report =
+                commands::write(&mut master, target.unit, table, address, &values, &value).await?;
+            report.print(format, out, true)?;
+        }
+        Command::Poll {
+            table,
+            target,
+            address,
+            count,
+            interval,
+            cycles,
+            value,
+        } => {
+            let master = link.master(&target.endpoint)?;
+            let read = commands::PollRead {
+                unit: target.unit,
+                table,
+                address,
+                count,
+                value,
+            };
+            commands::poll(master, read, interval, cycles, format, out).await?;
+        }
+        Command::Scan {
+            endpoint,
+            units,
+            table,
+            address,
+            tables,
+            concurrency,
+            min_gap,
+        } => {
+            let master = link.master(&endpoint)?;
+            let settings = commands::ScanSettings {
+                units,
+                table,
+                address,
+                tables,
+                concurrency,
+                min_gap,
+            };
+            let report = commands::scan(master, settings).await?;
+            report.print(format, out, true)?;
+        }
+        Command::Serve { endpoint, map } => {
+            let map = serve::MapFile::load(&map)?;
+            serve::serve(&link, &endpoint, map).await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+
+    if let Err(err) = run(cli, &mut std::io::stdout()).await {
+        eprintln!("Error: {:#}", err);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn run_args(args: &[&str]) -> Result<String> {
+        let cli = Cli::try_parse_from(std::iter::once("modbus").chain(args.iter().copied()))?;
+        let mut out = vec![];
+        run(cli, &mut out).await?;
+        Ok(String::from_utf8(out)?)
+    }
+
+    #[test]
+    fn test_unit_ranges() {
+        assert_eq!(parse_units("1-10").unwrap(), 1..=10);
+        assert_eq!(parse_units("7").unwrap(), 7..=7);
+        assert!(parse_units("10-1").is_err());
+        assert!(parse_units("1-300").is_err());
+    }
+
+    //Serves a map file and works on it with the other commands, so both ends of the library are used
+    #[tokio::test]
+    async fn test_commands_against_a_served_map() {
+        let map = std::env::temp_dir().join(format!("modbus-cli-{}.toml", std::process::id()));
+        std::fs::write(
+            &map,
+            r#"
+            units = [1]
+
+            [[holding]]
+            addr = 100
+            type = "f32"
+            order = "CDAB"
+            value = 12.5
+
+            [[holding]]
+            addr = 102
+            value = 7
+
+            [[coil]]
+            addr = 5
+            value = true
+            "#,
+        )
+        .unwrap();
+
+        //Serve binds the endpoint it's given, so a free port is found first
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let endpoint = format!("127.0.0.1:{}", port);
+        let served = endpoint.clone();
+        let map_path = map.to_str().unwrap().to_string();
+        tokio::spawn(async move {
+            let args = ["serve", served.as_str(), "--map", map_path.as_str()];
+            run_args(&args).await
+        });
+
+        let target = format!("1@{}", endpoint);
+        let target = target.as_str();
+        let read = [
+            "read", "hr", target, "100", "--type", "f32", "--order", "CDAB",
+        ];
+        assert_eq!(
+            run_args(&[&read[..], &["--format", "csv"]].concat())
+                .await
+                .unwrap(),
+            "unit,table,address,value\n1,hr,100,12.5\n"
+        );
+
+        run_args(&[
+            "write", "hr", target, "100", "-3.5", "--type", "f32", "--order", "CDAB",
+        ])
+        .await
+        .unwrap();
+        run_args(&["write", "coil", target, "5", "off"])
+            .await
+            .unwrap();
+        assert_eq!(
+            run_args(&[&read[..], &["--format", "json"]].concat())
+                .await
+                .unwrap(),
+            "[{\"address\":100,\"table\":\"hr\",\"unit\":1,\"value\":-3.5}]\n"
+        );
+        assert_eq!(
+            run_args(&["read", "coil", target, "5"]).await.unwrap(),
+            "unit  table  address  value\n1     coil   5        false\n"
+        );
+
+        let polled = run_args(&[
+            "poll",
+            "hr",
+            target,
+            "102",
+            "--interval",
+            "10ms",
+            "--cycles",
+            "2",
+            "--format",
+            "csv",
+        ])
+        .await
+        .unwrap();
+        assert_eq!(polled.lines().count(), 3);
+        assert!(polled.lines().all(|line| !line.contains("Timeout")));
+
+        let scanned = run_args(&[
+            "scan",
+            endpoint.as_str(),
+            "--units",
+            "1-3",
+            "--address",
+            "100",
+            "--concurrency",
+            "2",
+            "--min-gap",
+            "5ms",
+            "--timeout",
+            "500ms",
+            "--format",
+            "csv",
+        ])
+        .await
+        .unwrap();
+        assert_eq!(scanned, "unit,status\n1,ok\n");
+
+        let _ = std::fs::remove_file(&map);
+    }
+}
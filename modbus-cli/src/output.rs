@@ -0,0 +1,145 @@
+use std::io::{self, Write};
+
+use clap::ValueEnum;
+use serde_json::{Map, Value};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    Table,
+    //One array per report on a single line, so polls give a line per cycle
+    Json,
+    Csv,
+}
+
+//Rows with the same columns, printed in any of the formats
+#[derive(Clone, Debug, PartialEq)]
+pub struct Report {
+    columns: Vec<&'static str>,
+    rows: Vec<Vec<Value>>,
+}
+
+fn cell(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        Value::Null => String::new(),
+        value => value.to_string(),
+    }
+}
+
+fn csv_cell(value: &Value) -> String {
+    let text = cell(value);
+    if text.contains([',', '"', '\n']) {
+        return format!("\"{}\"", text.replace('"', "\"\""));
+    }
+    text
+}
+
+impl Report {
+    pub fn new(columns: Vec<&'static str>) -> Self {
+        Report {
+            columns,
+            rows: vec![],
+        }
+    }
+
+    pub fn push(&mut self, row: Vec<Value>) {
+        self.rows.push(row);
+    }
+
+    //The header is left out for the reports that follow the first one of a poll
+    pub fn print(&self, format: Format, out: &mut dyn Write, header: bool) -> io::Result<()> {
+        match format {
+            Format::Table => self.print_table(out, header),
+            Format::Json => {
+                let rows: Vec<Value> = self
+                    .rows
+                    .iter()
+                    .map(|row| {
+                        let object: Map<String, Value> = self
+                            .columns
+                            .iter()
+                            .map(|column| column.to_string())
+                            .zip(row.iter().cloned())
+                            .collect();
+                        Value::Object(object)
+                    })
+                    .collect();
+                writeln!(out, "{}", Value::Array(rows))
+            }
+            Format::Csv => {
+                if header {
+                    writeln!(out, "{}", self.columns.join(","))?;
+                }
+                for row in &self.rows {
+                    let cells: Vec<String> = row.iter().map(csv_cell).collect();
+                    writeln!(out, "{}", cells.join(","))?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn print_table(&self, out: &mut dyn Write, header: bool) -> io::Result<()> {
+        let rows: Vec<Vec<String>> = self
+            .rows
+            .iter()
+            .map(|row| row.iter().map(cell).collect())
+            .collect();
+        let widths: Vec<usize> = self
+            .columns
+            .iter()
+            .enumerate()
+            .map(|(index, column)| {
+                rows.iter()
+                    .map(|row| row[index].len())
+                    .fold(column.len(), usize::max)
+            })
+            .collect();
+
+        let line = |cells: Vec<&str>| {
+            let padded: Vec<String> = cells
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                .collect();
+            padded.join("  ").trim_end().to_string()
+        };
+
+        if header {
+            writeln!(out, "{}", line(self.columns.clone()))?;
+        }
+        for row in &rows {
+            writeln!(out, "{}", line(row.iter().map(String::as_str).collect()))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn report() -> Report {
+        let mut report = Report::new(vec!["unit", "value"]);
+        report.push(vec![json!(1), json!("a,b")]);
+        report.push(vec![json!(12), Value::Null]);
+        report
+    }
+
+    fn printed(format: Format) -> String {
+        let mut out = vec![];
+        report().print(format, &mut out, true).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_formats() {
+        assert_eq!(printed(Format::Table), "unit  value\n1     a,b\n12\n");
+        assert_eq!(printed(Format::Csv), "unit,value\n1,\"a,b\"\n12,\n");
+        assert_eq!(
+            printed(Format::Json),
+            "[{\"unit\":1,\"value\":\"a,b\"},{\"unit\":12,\"value\":null}]\n"
+        );
+    }
+}
@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use tokio::net::TcpListener;
+use tweakable_modbus::registers::RegisterOrder;
+use tweakable_modbus::{
+    ExceptionCode, ModbusAddress, ModbusCallBack, ModbusDataType, ModbusSlaveConnection,
+    ModbusSlaveConnectionParameters, ModbusSubprotocol, ModbusTable,
+};
+
+use crate::target::{socket_address, LinkArgs, Transport};
+use crate::values::ValueType;
+
+const CONNECTION_TIME_TO_LIVE: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BitEntry {
+    addr: u16,
+    #[serde(default)]
+    value: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegisterEntry {
+    addr: u16,
+    #[serde(default, rename = "type")]
+    ty: ValueType,
+    #[serde(default)]
+    order: Option<String>,
+    value: toml::Value,
+}
+
+//Values a served device starts with. Only the listed addresses exist, the rest answer with an
+//illegal data address exception
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MapFile {
+    //Unit ids answered, every one when left out
+    units: Option<Vec<u8>>,
+    #[serde(default)]
+    coil: Vec<BitEntry>,
+    #[serde(default)]
+    discrete: Vec<BitEntry>,
+    #[serde(default)]
+    holding: Vec<RegisterEntry>,
+    #[serde(default)]
+    input: Vec<RegisterEntry>,
+}
+
+impl MapFile {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Can't read {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("{} isn't a valid map", path.display()))
+    }
+
+    fn values(&self) -> Result<HashMap<(ModbusTable, u16), ModbusDataType>> {
+        let mut values = HashMap::new();
+
+        for (table, entries) in [
+            (ModbusTable::Coils, &self.coil),
+            (ModbusTable::DiscreteInput, &self.discrete),
+        ] {
+            for entry in entries {
+                values.insert((table, entry.addr), ModbusDataType::Coil(entry.value));
+            }
+        }
+
+        for (table, entries) in [
+            (ModbusTable::HoldingRegisters, &self.holding),
+            (ModbusTable::InputRegisters, &self.input),
+        ] {
+            for entry in entries {
+                let order: RegisterOrder = entry.order.as_deref().unwrap_or("ABCD").parse()?;
+                let registers = entry
+                    .ty
+                    .encode(&entry.value.to_string(), order)
+                    .with_context(|| format!("Value of register {}", entry.addr))?;
+
+                for (offset, register) in registers.into_iter().enumerate() {
+                    let address = entry.addr.checked_add(offset as u16).ok_or_else(|| {
+                        anyhow!("Value of register {} runs past the table", entry.addr)
+                    })?;
+                    values.insert((table, address), ModbusDataType::Register(register));
+                }
+            }
+        }
+
+        Ok(values)
+    }
+}
+
+//Every unit sees the same values, writes are logged to stderr
+struct MapCallBack {
+    values: Mutex<HashMap<(ModbusTable, u16), ModbusDataType>>,
+}
+
+#[async_trait::async_trait]
+impl ModbusCallBack for MapCallBack {
+    async fn on_read(&self, addr: ModbusAddress) -> Result<ModbusDataType, ExceptionCode> {
+        let values = self.values.lock().unwrap();
+        values
+            .get(&(addr.table, addr.address))
+            .copied()
+            .ok_or(ExceptionCode::IllegalDataAddress)
+    }
+
+    async fn on_write(
+        &self,
+        addr: ModbusAddress,
+        value: ModbusDataType,
+    ) -> Result<(), ExceptionCode> {
+        let mut values = self.values.lock().unwrap();
+        let current = values
+            .get_mut(&(addr.table, addr.address))
+            .ok_or(ExceptionCode::IllegalDataAddress)?;
+
+        eprintln!(
+            "Unit {} wrote {:?} to {:?} {}",
+            addr.slave_id, value, addr.table, addr.address
+        );
+        *current = value;
+        Ok(())
+    }
+}
+
+//Serves until the endpoint fails
+pub async fn serve(link: &LinkArgs, endpoint: &str, map: MapFile) -> Result<()> {
+    let callback = Box::new(MapCallBack {
+        values: Mutex::new(map.values()?),
+    });
+
+    let mut server = match link.transport(endpoint) {
+        Transport::Rtu => {
+            ModbusSlaveConnection::new_rtu(endpoint, link.serial_settings(), callback)
+        }
+        Transport::RtuOverTcp => {
+            let listener = TcpListener::bind(socket_address(endpoint)?).await?;
+            let incoming = futures::stream::unfold(listener, |listener| async {
+                let stream = listener.accept().await.map(|(stream, _)| stream);
+                Some((stream, listener))
+            });
+            ModbusSlaveConnection::new_with_acceptor(
+                Box::pin(incoming),
+                ModbusSubprotocol::ModbusRTUOverTCP,
+                callback,
+            )
+        }
+        _ => ModbusSlaveConnection::new_tcp(socket_address(endpoint)?, callback),
+    };
+
+    let params = ModbusSlaveConnectionParameters::new(map.units, None, CONNECTION_TIME_TO_LIVE);
+    server.server_with_parameters(params).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_map_values() {
+        let map: MapFile = toml::from_str(
+            r#"
+            [[holding]]
+            addr = 10
+            type = "u32"
+            order = "CDAB"
+            value = 65537
+
+            [[input]]
+            addr = 3
+            type = "i16"
+            value = -1
+
+            [[discrete]]
+            addr = 1
+            value = true
+            "#,
+        )
+        .unwrap();
+
+        let values = map.values().unwrap();
+        assert_eq!(
+            values[&(ModbusTable::HoldingRegisters, 10)],
+            ModbusDataType::Register(1)
+        );
+        assert_eq!(
+            values[&(ModbusTable::HoldingRegisters, 11)],
+            ModbusDataType::Register(1)
+        );
+        assert_eq!(
+            values[&(ModbusTable::InputRegisters, 3)],
+            ModbusDataType::Register(0xFFFF)
+        );
+        assert_eq!(
+            values[&(ModbusTable::DiscreteInput, 1)],
+            ModbusDataType::Coil(true)
+        );
+        assert_eq!(values.len(), 4);
+
+        let map: MapFile = toml::from_str("[[holding]]\naddr = 1\nvalue = 1.5\n").unwrap();
+        assert!(map.values().is_err());
+    }
+}
@@ -0,0 +1,230 @@
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use clap::{Args, ValueEnum};
+use tweakable_modbus::{
+    DataBits, ModbusMasterConnection, ModbusMasterConnectionParams, Parity, ReconnectPolicy,
+    SerialSettings, StopBits,
+};
+
+const DEFAULT_PORT: u16 = 502;
+
+//Unit and endpoint of a device as `unit@endpoint`, the unit defaults to 1
+#[derive(Clone, Debug, PartialEq)]
+pub struct Target {
+    pub unit: u8,
+    pub endpoint: String,
+}
+
+impl FromStr for Target {
+    type Err = anyhow::Error;
+
+    fn from_str(target: &str) -> Result<Self> {
+        let (unit, endpoint) = match target.split_once('@') {
+            Some((unit, endpoint)) => (unit.parse().context("Unit id must be 0 to 255")?, endpoint),
+            None => (1, target),
+        };
+
+        if endpoint.is_empty() {
+            return Err(anyhow!("Endpoint is missing"));
+        }
+        Ok(Target {
+            unit,
+            endpoint: endpoint.to_string(),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Transport {
+    //Serial paths are RTU, anything else is TCP
+    Auto,
+    Tcp,
+    Rtu,
+    RtuOverTcp,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ParityArg {
+    None,
+    Even,
+    Odd,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct LinkArgs {
+    #[arg(long, global = true, value_enum, default_value_t = Transport::Auto)]
+    pub transport: Transport,
+
+    #[arg(long, global = true, default_value_t = 9600)]
+    pub baud: u32,
+
+    //Line settings default to 8E1 as the Modbus serial line spec asks for
+    #[arg(long, global = true, value_enum, default_value_t = ParityArg::Even)]
+    pub parity: ParityArg,
+
+    #[arg(long, global = true, default_value_t = 8, value_parser = clap::value_parser!(u8).range(5..=8))]
+    pub data_bits: u8,
+
+    #[arg(long, global = true, default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=2))]
+    pub stop_bits: u8,
+
+    //How long a device gets to answer, and to accept a connection
+    #[arg(long, global = true, default_value = "1s", value_parser = parse_duration)]
+    pub timeout: Duration,
+}
+
+impl LinkArgs {
+    pub fn transport(&self, endpoint: &str) -> Transport {
+        match self.transport {
+            Transport::Auto if is_serial_path(endpoint) => Transport::Rtu,
+            Transport::Auto => Transport::Tcp,
+            transport => transport,
+        }
+    }
+
+    pub fn serial_settings(&self) -> SerialSettings {
+        SerialSettings {
+            data_bits: match self.data_bits {
+                5 => DataBits::Five,
+                6 => DataBits::Six,
+                7 => DataBits::Seven,
+                _ => DataBits::Eight,
+            },
+            parity: match self.parity {
+                ParityArg::None => Parity::None,
+                ParityArg::Even => Parity::Even,
+                ParityArg::Odd => Parity::Odd,
+            },
+            stop_bits: match self.stop_bits {
+                2 => StopBits::Two,
+                _ => StopBits::One,
+            },
+            ..SerialSettings::new(self.baud)
+        }
+    }
+
+    //A device that can't be reached fails the command instead of being retried for long
+    pub fn master(&self, endpoint: &str) -> Result<ModbusMasterConnection> {
+        let mut master = match self.transport(endpoint) {
+            Transport::Rtu => ModbusMasterConnection::new_rtu(endpoint, self.serial_settings()),
+            Transport::RtuOverTcp => {
+                ModbusMasterConnection::new_rtu_over_tcp(socket_address(endpoint)?)
+            }
+            _ => ModbusMasterConnection::new_tcp(socket_address(endpoint)?),
+        };
+
+        master.set_params(ModbusMasterConnectionParams {
+            max_response_time: self.timeout,
+            reconnect_policy: ReconnectPolicy {
+                connect_timeout: self.timeout,
+                max_downtime: self.timeout,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        Ok(master)
+    }
+}
+
+fn is_serial_path(endpoint: &str) -> bool {
+    endpoint.starts_with('/') || endpoint.to_ascii_uppercase().starts_with("COM")
+}
+
+//Host names are resolved and the port defaults to 502
+pub fn socket_address(endpoint: &str) -> Result<SocketAddr> {
+    let with_port = match endpoint.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() => endpoint.to_string(),
+        _ => format!("{}:{}", endpoint, DEFAULT_PORT),
+    };
+
+    with_port
+        .to_socket_addrs()
+        .with_context(|| format!("Can't resolve {}", endpoint))?
+        .next()
+        .ok_or_else(|| anyhow!("{} has no address", endpoint))
+}
+
+//Durations like 500ms, 1s, 1.5s or 2m, a bare number is taken as seconds
+pub fn parse_duration(duration: &str) -> Result<Duration> {
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(duration.len());
+    let (number, unit) = duration.split_at(split);
+    let number: f64 = number
+        .parse()
+        .with_context(|| format!("{} isn't a duration", duration))?;
+
+    let seconds = match unit {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        _ => return Err(anyhow!("Unknown unit {}, use ms, s or m", unit)),
+    };
+    Duration::try_from_secs_f64(seconds).with_context(|| format!("{} isn't a duration", duration))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_targets() {
+        let target: Target = "3@192.168.1.10:502".parse().unwrap();
+        assert_eq!(
+            (target.unit, target.endpoint.as_str()),
+            (3, "192.168.1.10:502")
+        );
+
+        let target: Target = "/dev/ttyUSB0".parse().unwrap();
+        assert_eq!((target.unit, target.endpoint.as_str()), (1, "/dev/ttyUSB0"));
+        assert!("300@host".parse::<Target>().is_err());
+
+        assert_eq!(
+            socket_address("127.0.0.1").unwrap(),
+            "127.0.0.1:502".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_serial_settings() {
+        use clap::Parser;
+
+        #[derive(Parser)]
+        struct Cli {
+            #[command(flatten)]
+            link: LinkArgs,
+        }
+
+        let settings = Cli::parse_from(["modbus"]).link.serial_settings();
+        assert_eq!(settings, SerialSettings::default());
+
+        let args = [
+            "modbus",
+            "--parity",
+            "none",
+            "--data-bits",
+            "7",
+            "--stop-bits",
+            "2",
+        ];
+        let settings = Cli::parse_from(args).link.serial_settings();
+        assert_eq!(
+            (settings.data_bits, settings.parity, settings.stop_bits),
+            (DataBits::Seven, Parity::None, StopBits::Two)
+        );
+        assert!(Cli::try_parse_from(["modbus", "--stop-bits", "3"]).is_err());
+    }
+
+    #[test]
+    fn test_durations() {
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("1.5s").unwrap(), Duration::from_millis(1500));
+        assert_eq!(parse_duration("2").unwrap(), Duration::from_secs(2));
+        assert_eq!(parse_duration("1m").unwrap(), Duration::from_secs(60));
+        assert!(parse_duration("1h").is_err());
+        assert!(parse_duration("99999999999999999999999s").is_err());
+    }
+}
@@ -0,0 +1,163 @@
+use anyhow::{anyhow, Context, Result};
+use clap::{Args, ValueEnum};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tweakable_modbus::registers::{RegisterOrder, RegisterValue};
+use tweakable_modbus::ModbusTable;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Table {
+    #[value(alias = "coils")]
+    Coil,
+    #[value(name = "di", alias = "discrete")]
+    Discrete,
+    #[value(name = "hr", alias = "holding")]
+    Holding,
+    #[value(name = "ir", alias = "input")]
+    Input,
+}
+
+impl Table {
+    pub fn name(self) -> &'static str {
+        match self {
+            Table::Coil => "coil",
+            Table::Discrete => "di",
+            Table::Holding => "hr",
+            Table::Input => "ir",
+        }
+    }
+
+    pub fn modbus_table(self) -> ModbusTable {
+        match self {
+            Table::Coil => ModbusTable::Coils,
+            Table::Discrete => ModbusTable::DiscreteInput,
+            Table::Holding => ModbusTable::HoldingRegisters,
+            Table::Input => ModbusTable::InputRegisters,
+        }
+    }
+
+    pub fn is_bits(self) -> bool {
+        matches!(self, Table::Coil | Table::Discrete)
+    }
+}
+
+//Type of the values in register tables, wider ones take several registers each
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueType {
+    #[default]
+    U16,
+    I16,
+    U32,
+    I32,
+    U64,
+    I64,
+    F32,
+    F64,
+}
+
+//Runs the body with T set to the Rust type of the value type
+macro_rules! with_type {
+    ($value_type:expr, $t:ident => $body:expr) => {
+        match $value_type {
+            ValueType::U16 => {
+                type $t = u16;
+                $body
+            }
+            ValueType::I16 => {
+                type $t = i16;
+                $body
+            }
+            ValueType::U32 => {
+                type $t = u32;
+                $body
+            }
+            ValueType::I32 => {
+                type $t = i32;
+                $body
+            }
+            ValueType::U64 => {
+                type $t = u64;
+                $body
+            }
+            ValueType::I64 => {
+                type $t = i64;
+                $body
+            }
+            ValueType::F32 => {
+                type $t = f32;
+                $body
+            }
+            ValueType::F64 => {
+                type $t = f64;
+                $body
+            }
+        }
+    };
+}
+
+impl ValueType {
+    pub fn registers(self) -> u16 {
+        with_type!(self, T => T::REGISTERS)
+    }
+
+    //Floats that aren't numbers come out as null
+    pub fn decode(self, registers: &[u16], order: RegisterOrder) -> Value {
+        with_type!(self, T => json!(T::from_registers(registers, order)))
+    }
+
+    pub fn encode(self, value: &str, order: RegisterOrder) -> Result<Vec<u16>> {
+        with_type!(self, T => {
+            let value: T = value
+                .parse()
+                .with_context(|| format!("{} isn't a valid {:?}", value, self))?;
+            Ok(value.to_registers(order))
+        })
+    }
+}
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct ValueArgs {
+    //Ignored for coils and discrete inputs
+    #[arg(long = "type", value_enum, default_value_t = ValueType::U16)]
+    pub ty: ValueType,
+
+    //Order of the bytes of values wider than a register
+    #[arg(long, default_value = "ABCD")]
+    pub order: RegisterOrder,
+}
+
+pub fn parse_bit(value: &str) -> Result<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "1" | "true" | "on" => Ok(true),
+        "0" | "false" | "off" => Ok(false),
+        _ => Err(anyhow!(
+            "{} isn't a bit, use 1/0, true/false or on/off",
+            value
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_values_round_trip() {
+        let registers = ValueType::F32.encode("12.5", RegisterOrder::CDAB).unwrap();
+        assert_eq!(registers, vec![0x0000, 0x4148]);
+        assert_eq!(
+            ValueType::F32.decode(&registers, RegisterOrder::CDAB),
+            json!(12.5)
+        );
+
+        assert_eq!(
+            ValueType::I16.encode("-2", RegisterOrder::ABCD).unwrap(),
+            vec![0xFFFE]
+        );
+        assert!(ValueType::U16.encode("70000", RegisterOrder::ABCD).is_err());
+        assert_eq!(ValueType::U64.registers(), 4);
+        assert!(parse_bit("on").unwrap());
+        assert!(parse_bit("2").is_err());
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use anyhow::Result;
+use tokio::runtime::Runtime;
+
+use crate::blocking::new_runtime;
+use crate::common::{Address, ModbusAddress, ModbusError, ModbusResult, SlaveId};
+use crate::communication::SerialSettings;
+use crate::master::{self, ModbusMasterConnectionParams, QueryOptions, RetryPolicy};
+
+//Async methods of the wrapped master, run to completion on the private runtime
+macro_rules! block_on {
+    ($(fn $name:ident(&mut self $(, $arg:ident: $ty:ty)*) -> $ret:ty;)*) => {
+        $(
+            pub fn $name(&mut self $(, $arg: $ty)*) -> $ret {
+                self.runtime.block_on(self.master.$name($($arg),*))
+            }
+        )*
+    };
+}
+
+//Methods of the wrapped master that don't touch the connection
+macro_rules! delegate {
+    ($(fn $name:ident(&mut self $(, $arg:ident: $ty:ty)*) -> $ret:ty;)*) => {
+        $(
+            pub fn $name(&mut self $(, $arg: $ty)*) -> $ret {
+                self.master.$name($($arg),*)
+            }
+        )*
+    };
+}
+
+//Same API as the async master, every call blocks the calling thread until it's done
+pub struct ModbusMasterConnection {
+    runtime: Runtime,
+    master: master::ModbusMasterConnection,
+}
+
+impl ModbusMasterConnection {
+    pub fn new_tcp(address: SocketAddr) -> Result<Self> {
+        Ok(ModbusMasterConnection {
+            runtime: new_runtime()?,
+            master: master::ModbusMasterConnection::new_tcp(address),
+        })
+    }
+
+    pub fn new_rtu(device: &str, settings: SerialSettings) -> Result<Self> {
+        Ok(ModbusMasterConnection {
+            runtime: new_runtime()?,
+            master: master::ModbusMasterConnection::new_rtu(device, settings),
+        })
+    }
+
+    pub fn set_params(&mut self, params: ModbusMasterConnectionParams) {
+        self.master.set_params(params);
+    }
+
+    pub fn get_params(&self) -> ModbusMasterConnectionParams {
+        self.master.get_params()
+    }
+
+    pub fn set_query_options(&mut self, query_options: QueryOptions) {
+        self.master.set_query_options(query_options);
+    }
+
+    pub fn get_query_options(&self) -> QueryOptions {
+        self.master.get_query_options()
+    }
+
+    pub fn set_device_retry_policy(&mut self, slave_id: u8, retry_policy: Option<RetryPolicy>) {
+        self.master.set_device_retry_policy(slave_id, retry_policy);
+    }
+
+    pub fn query(&mut self) -> Result<HashMap<ModbusAddress, ModbusResult>> {
+        self.runtime.block_on(self.master.query())
+    }
+
+    delegate! {
+        fn add_read_coils_query(&mut self, slave_id: u8, address: u16, ammount: u16) -> Result<()>;
+        fn add_read_discrete_inputs_query(&mut self, slave_id: u8, address: u16, ammount: u16) -> Result<()>;
+        fn add_read_holding_registers_query(&mut self, slave_id: u8, address: u16, ammount: u16) -> Result<()>;
+        fn add_read_input_registers_query(&mut self, slave_id: u8, address: u16, ammount: u16) -> Result<()>;
+        fn add_write_coil_query(&mut self, slave_id: u8, address: u16, value: bool) -> Result<()>;
+        fn add_write_holding_register_query(&mut self, slave_id: u8, address: u16, value: u16) -> Result<()>;
+        fn add_write_multiple_coils_query(&mut self, slave_id: u8, address: u16, values: Vec<bool>) -> Result<()>;
+        fn add_write_multiple_holding_registers_query(&mut self, slave_id: u8, address: u16, values: Vec<u16>) -> Result<()>;
+        fn add_multiple_read_write_holding_registers_query(&mut self, slave_id: u8, read_starting_address: u16, read_ammount: u16, write_starting_address: u16, values: Vec<u16>) -> Result<()>;
+        fn add_mask_write_register_query(&mut self, slave_id: SlaveId, address: Address, and_mask: u16, or_mask: u16) -> Result<()>;
+        fn add_read_holding_register_bit_query(&mut self, slave_id: u8, address: u16, bit: u8) -> Result<()>;
+        fn add_read_input_register_bit_query(&mut self, slave_id: u8, address: u16, bit: u8) -> Result<()>;
+        fn add_write_holding_register_bit_query(&mut self, slave_id: u8, address: u16, bit: u8, value: bool) -> Result<()>;
+    }
+
+    block_on! {
+        fn query_with_params(&mut self, params: ModbusMasterConnectionParams) -> Result<HashMap<ModbusAddress, ModbusResult>>;
+
+        fn read_coils(&mut self, slave_id: SlaveId, address: Address, ammount: u16) -> Result<Vec<bool>, ModbusError>;
+        fn read_discrete_inputs(&mut self, slave_id: SlaveId, address: Address, ammount: u16) -> Result<Vec<bool>, ModbusError>;
+        fn read_holding_registers(&mut self, slave_id: SlaveId, address: Address, ammount: u16) -> Result<Vec<u16>, ModbusError>;
+        fn read_input_registers(&mut self, slave_id: SlaveId, address: Address, ammount: u16) -> Result<Vec<u16>, ModbusError>;
+        fn write_single_coil(&mut self, slave_id: SlaveId, address: Address, value: bool) -> Result<(), ModbusError>;
+        fn write_single_register(&mut self, slave_id: SlaveId, address: Address, value: u16) -> Result<(), ModbusError>;
+        fn write_multiple_coils(&mut self, slave_id: SlaveId, address: Address, values: Vec<bool>) -> Result<(), ModbusError>;
+        fn write_multiple_registers(&mut self, slave_id: SlaveId, address: Address, values: Vec<u16>) -> Result<(), ModbusError>;
+        fn read_write_multiple_registers(&mut self, slave_id: SlaveId, read_starting_address: Address, read_ammount: u16, write_starting_address: Address, values: Vec<u16>) -> Result<Vec<u16>, ModbusError>;
+        fn mask_write_register(&mut self, slave_id: SlaveId, address: Address, and_mask: u16, or_mask: u16) -> Result<(), ModbusError>;
+        fn read_holding_register_bit(&mut self, slave_id: SlaveId, address: Address, bit: u8) -> Result<bool, ModbusError>;
+        fn read_input_register_bit(&mut self, slave_id: SlaveId, address: Address, bit: u8) -> Result<bool, ModbusError>;
+        fn write_holding_register_bit(&mut self, slave_id: SlaveId, address: Address, bit: u8, value: bool) -> Result<(), ModbusError>;
+    }
+}
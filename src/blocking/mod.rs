@@ -0,0 +1,14 @@
+//Synchronous wrappers around the async master and slave for programs that don't run tokio.
+//Each connection drives its own current-thread runtime, so they must not be used from inside one
+use anyhow::Result;
+use tokio::runtime::{Builder, Runtime};
+
+mod master;
+mod slave;
+
+pub use master::ModbusMasterConnection;
+pub use slave::{ModbusCallBack, ModbusSlaveConnection};
+
+fn new_runtime() -> Result<Runtime> {
+    Ok(Builder::new_current_thread().enable_all().build()?)
+}
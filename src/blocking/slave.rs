@@ -0,0 +1,147 @@
+use std::net::SocketAddr;
+
+use anyhow::Result;
+use tokio::runtime::Runtime;
+
+use crate::blocking::new_runtime;
+use crate::common::{ModbusAddress, ModbusDataType};
+use crate::communication::SerialSettings;
+use crate::messages::ExceptionCode;
+use crate::slave::{self, ModbusSlaveConnectionParameters};
+
+//Handlers run on the server thread, a slow one holds up every other connection while it runs
+pub trait ModbusCallBack: Send + Sync {
+    fn on_read(&self, addr: ModbusAddress) -> Result<ModbusDataType, ExceptionCode>;
+    fn on_write(&self, addr: ModbusAddress, value: ModbusDataType) -> Result<(), ExceptionCode>;
+}
+
+struct AsyncCallBack(Box<dyn ModbusCallBack>);
+
+#[async_trait::async_trait]
+impl slave::ModbusCallBack for AsyncCallBack {
+    async fn on_read(&self, addr: ModbusAddress) -> Result<ModbusDataType, ExceptionCode> {
+        self.0.on_read(addr)
+    }
+
+    async fn on_write(
+        &self,
+        addr: ModbusAddress,
+        value: ModbusDataType,
+    ) -> Result<(), ExceptionCode> {
+        self.0.on_write(addr, value)
+    }
+}
+
+//Same API as the async slave, serving blocks the calling thread for as long as the server runs
+pub struct ModbusSlaveConnection {
+    runtime: Runtime,
+    slave: slave::ModbusSlaveConnection,
+}
+
+impl ModbusSlaveConnection {
+    pub fn new_tcp(address: SocketAddr, callback: Box<dyn ModbusCallBack>) -> Result<Self> {
+        Ok(ModbusSlaveConnection {
+            runtime: new_runtime()?,
+            slave: slave::ModbusSlaveConnection::new_tcp(
+                address,
+                Box::new(AsyncCallBack(callback)),
+            ),
+        })
+    }
+
+    pub fn new_rtu(
+        device: &str,
+        settings: SerialSettings,
+        callback: Box<dyn ModbusCallBack>,
+    ) -> Result<Self> {
+        Ok(ModbusSlaveConnection {
+            runtime: new_runtime()?,
+            slave: slave::ModbusSlaveConnection::new_rtu(
+                device,
+                settings,
+                Box::new(AsyncCallBack(callback)),
+            ),
+        })
+    }
+
+    //Binding before serving lets masters connect as soon as this returns
+    pub fn bind(&mut self) -> Result<()> {
+        self.runtime.block_on(self.slave.bind())
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.slave.local_addr()
+    }
+
+    pub fn server_with_parameters(
+        &mut self,
+        params: ModbusSlaveConnectionParameters,
+    ) -> Result<()> {
+        self.runtime
+            .block_on(self.slave.server_with_parameters(params))
+    }
+
+    pub fn serve(&mut self) -> Result<()> {
+        self.runtime.block_on(self.slave.serve())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::blocking::ModbusMasterConnection;
+    use crate::common::ModbusTable;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    struct Registers(Mutex<HashMap<u16, u16>>);
+
+    impl ModbusCallBack for Registers {
+        fn on_read(&self, addr: ModbusAddress) -> Result<ModbusDataType, ExceptionCode> {
+            match (addr.table, self.0.lock().unwrap().get(&addr.address)) {
+                (ModbusTable::HoldingRegisters, Some(value)) => {
+                    Ok(ModbusDataType::Register(*value))
+                }
+                _ => Err(ExceptionCode::IllegalDataAddress),
+            }
+        }
+
+        fn on_write(
+            &self,
+            addr: ModbusAddress,
+            value: ModbusDataType,
+        ) -> Result<(), ExceptionCode> {
+            match value {
+                ModbusDataType::Register(value) => {
+                    self.0.lock().unwrap().insert(addr.address, value);
+                    Ok(())
+                }
+                ModbusDataType::Coil(_) => Err(ExceptionCode::IllegalDataAddress),
+            }
+        }
+    }
+
+    #[test]
+    fn test_blocking_master_and_slave() {
+        let address: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let registers = Registers(Mutex::new(
+            (0..4).map(|address| (address, address * 10)).collect(),
+        ));
+
+        let mut slave = ModbusSlaveConnection::new_tcp(address, Box::new(registers)).unwrap();
+        slave.bind().unwrap();
+        let address = slave.local_addr().unwrap();
+        std::thread::spawn(move || slave.serve());
+
+        let mut master = ModbusMasterConnection::new_tcp(address).unwrap();
+        assert_eq!(master.read_holding_registers(1, 1, 3), Ok(vec![10, 20, 30]));
+        assert_eq!(master.write_single_register(1, 2, 25), Ok(()));
+        assert_eq!(master.read_holding_registers(1, 2, 1), Ok(vec![25]));
+        assert!(master.read_coils(1, 0, 1).is_err());
+
+        master.add_read_holding_registers_query(1, 0, 2).unwrap();
+        let results = master.query().unwrap();
+        let result = &results[&ModbusAddress::new(1, ModbusTable::HoldingRegisters, 1)];
+        assert_eq!(result.value, Some(ModbusDataType::Register(10)));
+    }
+}
@@ -6,3 +6,88 @@ where
     fn rtu_serialize(&self) -> Result<Vec<u8>>;
     fn rtu_deserialize(data: Vec<u8>) -> Result<Vec<Self>>;
 }
+
+//CRC-16/MODBUS, polynomial 0xA001 reflected and 0xFFFF as initial value
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+
+    for byte in data {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            if crc & 1 != 0 {
+                crc = (crc >> 1) ^ 0xA001;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+
+    crc
+}
+
+//Slave id, pdu and the crc, which unlike everything else in modbus goes low byte first
+pub fn serialize_frame(slave_id: u8, pdu: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(pdu.len() + 3);
+
+    frame.push(slave_id);
+    frame.extend_from_slice(pdu);
+
+    let crc = crc16(&frame);
+    frame.extend_from_slice(&crc.to_le_bytes());
+
+    frame
+}
+
+//RTU frames have no length field, pdu_length works out how long a pdu is from its first bytes
+//and returns None when there aren't enough of them. Whatever follows a frame that is incomplete
+//or has a bad crc can't be resynchronised and is dropped, the master will time out on it
+pub fn deserialize_frames(
+    data: &[u8],
+    pdu_length: fn(&[u8]) -> Option<usize>,
+) -> Vec<(u8, Vec<u8>)> {
+    let mut frames = vec![];
+    let mut rest = data;
+
+    while rest.len() > 3 {
+        let Some(length) = pdu_length(&rest[1..]) else {
+            break;
+        };
+
+        let frame_length = length + 3;
+        if rest.len() < frame_length {
+            break;
+        }
+
+        let (frame, next) = rest.split_at(frame_length);
+        let (content, crc) = frame.split_at(frame_length - 2);
+        if crc16(content).to_le_bytes() != crc {
+            break;
+        }
+
+        frames.push((content[0], content[1..].to_vec()));
+        rest = next;
+    }
+
+    frames
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_crc() {
+        //Reading 3 holding registers from 0x006B of slave 0x11, as in the modbus specification
+        let frame = serialize_frame(0x11, &[0x03, 0x00, 0x6B, 0x00, 0x03]);
+        assert_eq!(frame[6..], [0x76, 0x87]);
+
+        let mut data = frame.clone();
+        data.extend_from_slice(&frame);
+        let frames = deserialize_frames(&data, |_| Some(5));
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0], (0x11, vec![0x03, 0x00, 0x6B, 0x00, 0x03]));
+
+        data[3] ^= 0xFF;
+        assert!(deserialize_frames(&data, |_| Some(5)).is_empty());
+    }
+}
@@ -22,11 +22,7 @@ pub fn serialize_values(mut values: Vec<ModbusDataType>) -> Result<Vec<u8>> {
     match first_value {
         ModbusDataType::Coil(_) => {
             values.reverse();
-            let length = if values.len() % 8 == 0 {
-                values.len() as u8 / 8
-            } else {
-                values.len() as u8 / 8 + 1
-            };
+            let length = values.len().div_ceil(8) as u8;
 
             result.push(length);
 
@@ -35,9 +31,9 @@ pub fn serialize_values(mut values: Vec<ModbusDataType>) -> Result<Vec<u8>> {
 
             for value in values {
                 if let ModbusDataType::Coil(value) = value {
-                    aux_byte = aux_byte << 1;
+                    aux_byte <<= 1;
                     if value {
-                        aux_byte = aux_byte | 0b1;
+                        aux_byte |= 0b1;
                     }
 
                     counter += 1;
@@ -90,11 +86,7 @@ pub fn deserialize_values(
 
     let expected_byte_count = match table {
         ModbusTable::Coils | ModbusTable::DiscreteInput => {
-            if ammount % 8 == 0 {
-                ammount / 8
-            } else {
-                (ammount / 8) + 1
-            }
+            ammount.div_ceil(8)
         }
         ModbusTable::InputRegisters | ModbusTable::HoldingRegisters => ammount * 2,
     };
@@ -121,7 +113,7 @@ pub fn deserialize_values(
                 }
                 let value = (aux_byte & 0b1) != 0;
                 values.push(ModbusDataType::Coil(value));
-                aux_byte = aux_byte >> 1;
+                aux_byte >>= 1;
                 counter += 1;
             }
         },
@@ -137,7 +129,7 @@ pub fn deserialize_values(
     Ok(values)
 }
 
-fn check_same_data_type_variant(values: &Vec<ModbusDataType>) -> bool {
+fn check_same_data_type_variant(values: &[ModbusDataType]) -> bool {
     if let Some((first, others)) = values.split_first() {
         let ref_discriminant = discriminant(first);
         others.iter().all(|e| discriminant(e) == ref_discriminant)
@@ -2,12 +2,14 @@ use crate::messages::{ExceptionCode, FunctionCode};
 
 use anyhow::{anyhow, Result};
 use std::cmp::{PartialOrd, Ordering};
+use std::fmt;
+use std::time::{Duration, SystemTime};
 
 //TODO: Ensure this types are use through the code base
 pub type Address = u16;
 pub type SlaveId = u8;
 
-#[derive(Clone, Copy, PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum ModbusDataType {
     Coil(bool),
     Register(u16),
@@ -27,6 +29,13 @@ impl ModbusDataType {
         }
     }
 
+    pub fn get_bit(&self, bit: u8) -> Option<bool> {
+        match self {
+            ModbusDataType::Register(value) if bit < 16 => Some((value >> bit) & 0b1 != 0),
+            _ => None,
+        }
+    }
+
     pub fn coil_from_representation(raw_value: u16) -> Result<Self> {
         match raw_value {
             0xFF00 => Ok(ModbusDataType::Coil(true)),
@@ -47,11 +56,102 @@ pub enum ModbusSubprotocol {
     ModbusRTUOverTCP,
 }
 
+//What a query got for a single address
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModbusResult {
+    //Value read, None for writes and for reads that didn't get one
+    pub value: Option<ModbusDataType>,
+    pub quality: Quality,
+    //Last time the request was put on the wire, None if it never was
+    pub requested_at: Option<SystemTime>,
+    //When the response arrived, None if none did
+    pub responded_at: Option<SystemTime>,
+    //Time between sending the request and getting its response
+    pub latency: Option<Duration>,
+    //Why a confirmed write couldn't be read back to verify it, the write itself went through
+    pub read_back_failure: Option<Quality>,
+}
+
+impl ModbusResult {
+    //Confirmed write or read value that came in time
+    pub fn is_good(&self) -> bool {
+        self.quality == Quality::Good
+    }
+}
+
+//Reason a single query couldn't be completed
 #[derive(Clone, Debug, PartialEq)]
-pub enum ModbusResult {
-    Error(ExceptionCode),
-    ReadResult(ModbusDataType),
-    WriteConfirmation,
+pub enum ModbusError {
+    Exception(ExceptionCode),
+    Timeout,
+    Connection(String),
+    Protocol(String),
+    InvalidQuery(String),
+    //The device confirmed the write but reads back something else
+    VerifyMismatch {
+        address: Address,
+        expected: ModbusDataType,
+        actual: ModbusDataType,
+    },
+}
+
+impl fmt::Display for ModbusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModbusError::Exception(exception_code) => {
+                write!(f, "Device answered with exception {:?}", exception_code)
+            }
+            ModbusError::Timeout => write!(f, "Query wasn't answered in time"),
+            ModbusError::Connection(reason) => write!(f, "Connection error: {}", reason),
+            ModbusError::Protocol(reason) => write!(f, "Protocol error: {}", reason),
+            ModbusError::InvalidQuery(reason) => write!(f, "Invalid query: {}", reason),
+            ModbusError::VerifyMismatch {
+                address,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Address {} reads back {:?} after writing {:?}",
+                address, actual, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ModbusError {}
+
+//How much a value can be trusted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quality {
+    Good,
+    Exception(ExceptionCode),
+    Timeout,
+    CommFailure,
+    //Answered after the response deadline, the value may already be outdated
+    Stale,
+    ProtocolError,
+    //The write was confirmed but reading it back gave another value, devices may clamp what they get
+    VerifyMismatch {
+        expected: ModbusDataType,
+        actual: ModbusDataType,
+    },
+}
+
+impl From<&ModbusError> for Quality {
+    fn from(error: &ModbusError) -> Self {
+        match error {
+            ModbusError::Exception(exception_code) => Quality::Exception(*exception_code),
+            ModbusError::Timeout => Quality::Timeout,
+            ModbusError::Connection(_) => Quality::CommFailure,
+            ModbusError::Protocol(_) | ModbusError::InvalidQuery(_) => Quality::ProtocolError,
+            ModbusError::VerifyMismatch {
+                expected, actual, ..
+            } => Quality::VerifyMismatch {
+                expected: *expected,
+                actual: *actual,
+            },
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
@@ -59,6 +159,28 @@ pub struct ModbusAddress {
     pub slave_id: SlaveId,
     pub table: ModbusTable,
     pub address: Address,
+    //Index of a single bit inside a register, None addresses the whole value
+    pub bit: Option<u8>,
+}
+
+impl ModbusAddress {
+    pub fn new(slave_id: SlaveId, table: ModbusTable, address: Address) -> Self {
+        ModbusAddress {
+            slave_id,
+            table,
+            address,
+            bit: None,
+        }
+    }
+
+    pub fn new_bit(slave_id: SlaveId, table: ModbusTable, address: Address, bit: u8) -> Self {
+        ModbusAddress {
+            slave_id,
+            table,
+            address,
+            bit: Some(bit),
+        }
+    }
 }
 
 impl Ord for ModbusAddress {
@@ -67,6 +189,7 @@ impl Ord for ModbusAddress {
             .cmp(&other.slave_id)
             .then_with(|| self.table.cmp(&other.table))
             .then_with(|| self.address.cmp(&other.address))
+            .then_with(|| self.bit.cmp(&other.bit))
     }
 }
 
@@ -85,6 +208,15 @@ pub enum ModbusTable {
 }
 
 impl ModbusTable {
+    pub fn get_read_function_code(&self) -> FunctionCode {
+        match self {
+            ModbusTable::Coils => FunctionCode::ReadCoils,
+            ModbusTable::DiscreteInput => FunctionCode::ReadDiscreteInputs,
+            ModbusTable::HoldingRegisters => FunctionCode::ReadMultipleHoldingRegister,
+            ModbusTable::InputRegisters => FunctionCode::ReadInputRegisters,
+        }
+    }
+
     pub fn get_table_from_function_code(function_code: FunctionCode) -> Option<ModbusTable> {
         match function_code {
             FunctionCode::WriteSingleCoil
@@ -92,6 +224,7 @@ impl ModbusTable {
             | FunctionCode::WriteMultipleCoils => Some(ModbusTable::Coils),
             FunctionCode::WriteSingleHoldingRegister
             | FunctionCode::ReadMultipleHoldingRegister
+            | FunctionCode::MaskWriteRegister
             | FunctionCode::WriteMultipleHoldingRegisters
             | FunctionCode::ReadWriteMultipleRegisters => Some(ModbusTable::HoldingRegisters),
             FunctionCode::ReadInputRegisters => Some(ModbusTable::InputRegisters),
@@ -0,0 +1,122 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+use crate::communication::ModbusSocket;
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+//One end of an in-process link, whatever is written comes out of the other end after the latency.
+//Every write fits in the buffer, so a read returns whole frames
+pub struct MemorySocket {
+    stream: DuplexStream,
+    latency: Duration,
+    //Read while waiting for the stream to become readable
+    pending: Vec<u8>,
+}
+
+impl MemorySocket {
+    pub fn pair(latency: Duration) -> (MemorySocket, MemorySocket) {
+        let (first, second) = duplex(BUFFER_SIZE);
+
+        (
+            MemorySocket {
+                stream: first,
+                latency,
+                pending: Vec::new(),
+            },
+            MemorySocket {
+                stream: second,
+                latency,
+                pending: Vec::new(),
+            },
+        )
+    }
+}
+
+#[async_trait]
+impl ModbusSocket for MemorySocket {
+    async fn read(&mut self) -> Result<Vec<u8>> {
+        self.readable().await?;
+        Ok(std::mem::take(&mut self.pending))
+    }
+
+    async fn readable(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            let mut buffer = vec![0u8; BUFFER_SIZE];
+            let n = self.stream.read(&mut buffer).await?;
+
+            if n == 0 {
+                return Err(anyhow!("Connection closed by peer"));
+            }
+            buffer.truncate(n);
+            self.pending = buffer;
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, data: Vec<u8>) -> Result<()> {
+        if !self.latency.is_zero() {
+            tokio::time::sleep(self.latency).await;
+        }
+        self.stream.write_all(&data).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::ModbusSubprotocol;
+    use crate::master::test_utils::MemoryCallBack;
+    use crate::master::ModbusMasterConnection;
+    use crate::slave::ModbusSlaveConnection;
+    use tokio::time::Instant;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_writes_arrive_after_the_latency() {
+        let (mut master, mut slave) = MemorySocket::pair(Duration::from_millis(30));
+
+        let started = Instant::now();
+        master.write(vec![1, 2, 3]).await.unwrap();
+        assert_eq!(slave.read().await.unwrap(), vec![1, 2, 3]);
+        assert_eq!(started.elapsed(), Duration::from_millis(30));
+
+        drop(master);
+        assert!(slave.read().await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_in_memory_link_for_every_subprotocol() {
+        let mut server =
+            ModbusSlaveConnection::new_in_memory(Box::new(MemoryCallBack::with_test_registers()));
+        let latency = Duration::from_millis(20);
+
+        for subprotocol in [
+            ModbusSubprotocol::ModbusTCP,
+            ModbusSubprotocol::ModbusRTU,
+            ModbusSubprotocol::ModbusRTUOverTCP,
+        ] {
+            let mut master = ModbusMasterConnection::new_in_memory(&server, subprotocol, latency);
+
+            let started = Instant::now();
+            assert_eq!(
+                master.read_holding_registers(1, 1, 2).await,
+                Ok(vec![10, 20])
+            );
+            assert_eq!(started.elapsed(), latency * 2);
+
+            master.write_single_register(1, 5, 55).await.unwrap();
+        }
+
+        //Every link is served by the same callback
+        let mut master =
+            ModbusMasterConnection::new_in_memory(&server, ModbusSubprotocol::ModbusTCP, latency);
+        assert_eq!(master.read_holding_registers(1, 5, 1).await, Ok(vec![55]));
+
+        //Nothing but its masters reach it
+        assert!(server.serve().await.is_err());
+    }
+}
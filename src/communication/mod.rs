@@ -3,55 +3,123 @@ use std::time::Duration;
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::Arc;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::AsyncRead;
 use tokio::net::TcpStream;
 
+mod memory;
+mod serial;
+mod stream;
+mod udp;
+#[cfg(unix)]
+mod unix;
+
+pub use memory::MemorySocket;
+pub use serial::{DataBits, FlowControl, Parity, RtuPort, RtuTimings, SerialSettings, StopBits};
+pub use stream::{stream_acceptor, stream_connector, AsyncStream, StreamAcceptor, StreamConnector};
+pub use udp::{connect_udp, recv_datagram};
+#[cfg(unix)]
+pub use unix::{bind_unix, PeerCredentials};
+
 pub enum AddressingInfo {
     TcpConnection {
         address: SocketAddr,
     },
-    #[allow(dead_code)]
     RtuConnection {
         device: String,
-        baud_rate: u32,
+        settings: SerialSettings,
+    },
+    //Modbus TCP framing, one datagram per message and no connection
+    UdpConnection {
+        address: SocketAddr,
+    },
+    #[cfg(unix)]
+    UnixConnection {
+        path: PathBuf,
+    },
+    //Every connection gets a fresh link to a server in the same process
+    InMemory {
+        connect: MemoryConnector,
+    },
+    //Every connection is a fresh stream from a user supplied factory
+    Stream {
+        connect: StreamConnector,
     },
 }
 
+pub type MemoryConnector = Arc<dyn Fn() -> MemorySocket + Send + Sync>;
+
 //This trait is meant to abstract both TCP and RTU system sockets in order to unify behaviour
 #[async_trait]
 pub trait ModbusSocket: Send + Sync {
     async fn read(&mut self) -> Result<Vec<u8>>;
 
     async fn write(&mut self, data: Vec<u8>) -> Result<()>;
+
+    //Resolves once there's something to read, so waiting for it can be given up without losing part
+    //of a response. Sockets that have to read to tell keep what they got for the next read
+    async fn readable(&mut self) -> Result<()> {
+        Ok(())
+    }
 }
 
-#[async_trait]
-impl ModbusSocket for TcpStream {
-    async fn read(&mut self) -> Result<Vec<u8>> {
-        let mut data = Vec::new();
+//Waits for the first bytes into pending, dropping it halfway loses nothing
+async fn read_first<S: AsyncRead + Unpin + Send>(stream: &mut S, pending: &mut Vec<u8>) -> Result<()> {
+    if pending.is_empty() {
         let mut buffer = [0u8; 1024];
+        let n = AsyncReadExt::read(stream, &mut buffer).await?;
+        if n == 0 {
+            return Err(anyhow!("Connection closed by peer"));
+        }
+        pending.extend_from_slice(&buffer[..n]);
+    }
+    Ok(())
+}
 
-        loop {
-            match tokio::time::timeout(
-                Duration::from_millis(50),
-                AsyncReadExt::read(self, &mut buffer),
-            )
-            .await
-            {
-                Ok(Ok(n)) => {
-                    data.extend_from_slice(&buffer[..n]);
-                    if n == 0 {
-                        break;
+//Reads whatever arrives after data until the line has been silent for a while
+async fn read_until_silent<S: AsyncRead + Unpin + Send>(
+    stream: &mut S,
+    mut data: Vec<u8>,
+) -> Result<Vec<u8>> {
+    let mut buffer = [0u8; 1024];
+
+    loop {
+        match tokio::time::timeout(
+            Duration::from_millis(50),
+            AsyncReadExt::read(stream, &mut buffer),
+        )
+        .await
+        {
+            Ok(Ok(n)) => {
+                if n == 0 {
+                    if data.is_empty() {
+                        return Err(anyhow!("Connection closed by peer"));
                     }
-                }
-                Ok(Err(err)) => return Err(anyhow!(err.to_string())),
-                Err(_) => {
                     break;
                 }
+                data.extend_from_slice(&buffer[..n]);
+            }
+            Ok(Err(err)) => return Err(anyhow!(err.to_string())),
+            Err(_) => {
+                break;
             }
         }
+    }
+
+    Ok(data)
+}
+
+#[async_trait]
+impl ModbusSocket for TcpStream {
+    async fn read(&mut self) -> Result<Vec<u8>> {
+        read_until_silent(self, Vec::new()).await
+    }
 
-        Ok(data)
+    async fn readable(&mut self) -> Result<()> {
+        Ok(TcpStream::readable(self).await?)
     }
 
     async fn write(&mut self, data: Vec<u8>) -> Result<()> {
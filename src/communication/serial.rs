@@ -0,0 +1,338 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::{sleep_until, timeout, Duration, Instant};
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+
+use crate::communication::ModbusSocket;
+
+pub use tokio_serial::{DataBits, FlowControl, Parity, StopBits};
+
+//Fixed values the specification asks for above 19200 baud, where the computed ones get too short to time
+const FAST_INTER_CHARACTER_TIMEOUT: Duration = Duration::from_micros(750);
+const FAST_FRAME_DELAY: Duration = Duration::from_micros(1750);
+const DEFAULT_TURNAROUND_DELAY: Duration = Duration::from_millis(100);
+
+//Silent intervals RTU framing depends on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RtuTimings {
+    //Longest silence allowed inside a frame (t1.5), a frame with a longer gap is discarded
+    pub inter_character_timeout: Duration,
+    //Silence that ends a frame and has to go by before the next one is sent (t3.5)
+    pub frame_delay: Duration,
+    //Time units on the bus are given to process a broadcast before the next request is sent
+    pub turnaround_delay: Duration,
+}
+
+impl RtuTimings {
+    pub fn from_settings(settings: &SerialSettings) -> Self {
+        let turnaround_delay = DEFAULT_TURNAROUND_DELAY;
+
+        if settings.baud_rate > 19200 {
+            return RtuTimings {
+                inter_character_timeout: FAST_INTER_CHARACTER_TIMEOUT,
+                frame_delay: FAST_FRAME_DELAY,
+                turnaround_delay,
+            };
+        }
+
+        let character_time = settings.character_time();
+        RtuTimings {
+            inter_character_timeout: character_time * 3 / 2,
+            frame_delay: character_time * 7 / 2,
+            turnaround_delay,
+        }
+    }
+}
+
+//Line settings of a serial port, the default is the 9600 8E1 the modbus specification asks for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SerialSettings {
+    pub baud_rate: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+    //Overrides the timings worked out from the line settings, for devices that don't keep to them
+    pub timings: Option<RtuTimings>,
+}
+
+impl Default for SerialSettings {
+    fn default() -> Self {
+        SerialSettings {
+            baud_rate: 9600,
+            data_bits: DataBits::Eight,
+            parity: Parity::Even,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+            timings: None,
+        }
+    }
+}
+
+impl SerialSettings {
+    pub fn new(baud_rate: u32) -> Self {
+        SerialSettings {
+            baud_rate,
+            ..Default::default()
+        }
+    }
+
+    pub fn timings(&self) -> RtuTimings {
+        self.timings
+            .unwrap_or_else(|| RtuTimings::from_settings(self))
+    }
+
+    //Time a single character takes on the line, start, data, parity and stop bits included
+    pub fn character_time(&self) -> Duration {
+        let data_bits = match self.data_bits {
+            DataBits::Five => 5,
+            DataBits::Six => 6,
+            DataBits::Seven => 7,
+            DataBits::Eight => 8,
+        };
+        let parity_bits = match self.parity {
+            Parity::None => 0,
+            Parity::Odd | Parity::Even => 1,
+        };
+        let stop_bits = match self.stop_bits {
+            StopBits::One => 1,
+            StopBits::Two => 2,
+        };
+        let bits: u64 = 1 + data_bits + parity_bits + stop_bits;
+
+        Duration::from_nanos(bits * 1_000_000_000 / self.baud_rate.max(1) as u64)
+    }
+
+    pub fn open(&self, device: &str) -> Result<RtuPort> {
+        let stream = tokio_serial::new(device, self.baud_rate)
+            .data_bits(self.data_bits)
+            .parity(self.parity)
+            .stop_bits(self.stop_bits)
+            .flow_control(self.flow_control)
+            .open_native_async()?;
+
+        Ok(RtuPort::new(stream, self))
+    }
+}
+
+//Serial port that splits what it reads into frames by the silences between them
+pub struct RtuPort {
+    stream: SerialStream,
+    timings: RtuTimings,
+    character_time: Duration,
+    //Nothing is sent before this, so the previous frame is over and broadcasts have been processed
+    line_free_at: Instant,
+    //Start of a frame read while waiting for the line to become readable
+    pending: Vec<u8>,
+}
+
+impl RtuPort {
+    pub fn new(stream: SerialStream, settings: &SerialSettings) -> Self {
+        RtuPort {
+            stream,
+            timings: settings.timings(),
+            character_time: settings.character_time(),
+            line_free_at: Instant::now(),
+            pending: Vec::new(),
+        }
+    }
+
+    async fn read_some(&mut self, data: &mut Vec<u8>) -> Result<()> {
+        let mut buffer = [0u8; 256];
+
+        match self.stream.read(&mut buffer).await {
+            Ok(0) => Err(anyhow!("Serial port closed")),
+            Ok(n) => {
+                data.extend_from_slice(&buffer[..n]);
+                Ok(())
+            }
+            Err(err) => Err(anyhow!(err.to_string())),
+        }
+    }
+}
+
+#[async_trait]
+impl ModbusSocket for RtuPort {
+    //Waits for a frame and returns it once the line has been silent for t3.5. A frame with a
+    //silence longer than t1.5 inside it is discarded and nothing is returned
+    async fn read(&mut self) -> Result<Vec<u8>> {
+        self.readable().await?;
+        let mut data = std::mem::take(&mut self.pending);
+        let mut broken = false;
+
+        loop {
+            if timeout(
+                self.timings.inter_character_timeout,
+                self.read_some(&mut data),
+            )
+            .await
+            .is_ok()
+            {
+                continue;
+            }
+
+            let rest_of_frame_delay = self
+                .timings
+                .frame_delay
+                .saturating_sub(self.timings.inter_character_timeout);
+            match timeout(rest_of_frame_delay, self.read_some(&mut data)).await {
+                Ok(result) => {
+                    result?;
+                    broken = true;
+                }
+                Err(_) => break,
+            }
+        }
+
+        if broken {
+            return Ok(vec![]);
+        }
+
+        Ok(data)
+    }
+
+    async fn readable(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            let mut data = vec![];
+            self.read_some(&mut data).await?;
+            self.pending = data;
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, data: Vec<u8>) -> Result<()> {
+        sleep_until(self.line_free_at).await;
+
+        //A frame has to go out in one piece, so unlike tcp partial writes aren't good enough
+        if let Err(err) = self.stream.write_all(data.as_slice()).await {
+            return Err(anyhow!(err.to_string()));
+        }
+
+        let is_broadcast = data.first() == Some(&0);
+        let silence = if is_broadcast {
+            self.timings.turnaround_delay
+        } else {
+            self.timings.frame_delay
+        };
+        self.line_free_at = Instant::now() + self.character_time * data.len() as u32 + silence;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::{ModbusAddress, ModbusDataType, ModbusError, ModbusTable};
+    use crate::master::ModbusMasterConnectionParams;
+    use crate::messages::ExceptionCode;
+
+    #[test]
+    fn test_timings_scale_with_baud_rate() {
+        //11 bits per character at 9600 baud
+        let timings = SerialSettings::new(9600).timings();
+        assert_eq!(
+            timings.inter_character_timeout,
+            Duration::from_nanos(1_718_749)
+        );
+        assert_eq!(timings.frame_delay, Duration::from_nanos(4_010_415));
+
+        let settings = SerialSettings {
+            parity: Parity::None,
+            ..SerialSettings::new(19200)
+        };
+        assert_eq!(settings.character_time(), Duration::from_nanos(520_833));
+
+        let timings = SerialSettings::new(115200).timings();
+        assert_eq!(timings.inter_character_timeout, Duration::from_micros(750));
+        assert_eq!(timings.frame_delay, Duration::from_micros(1750));
+    }
+
+    #[tokio::test]
+    async fn test_frames_with_gaps_are_discarded() {
+        let (pty, mut line) = SerialStream::pair().unwrap();
+        let timings = RtuTimings {
+            inter_character_timeout: Duration::from_millis(20),
+            frame_delay: Duration::from_millis(100),
+            turnaround_delay: Duration::from_millis(100),
+        };
+        let settings = SerialSettings {
+            timings: Some(timings),
+            ..Default::default()
+        };
+        let mut pty = RtuPort::new(pty, &settings);
+
+        //The frame is read while it's still being written, so the gap is seen
+        for (gap, expected) in [(50, vec![]), (5, vec![1, 3, 0, 0, 0, 1])] {
+            let write = async {
+                line.write_all(&[1, 3, 0]).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(gap)).await;
+                line.write_all(&[0, 0, 1]).await.unwrap();
+            };
+            let (read, _) = tokio::join!(pty.read(), write);
+            assert_eq!(read.unwrap(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rtu_over_pty() {
+        use crate::master::test_utils::connect_rtu;
+
+        let mut master = connect_rtu(SerialSettings::new(19200)).await;
+
+        assert_eq!(
+            master.read_holding_registers(1, 2, 3).await,
+            Ok(vec![20, 30, 40])
+        );
+
+        master
+            .write_multiple_registers(1, 2, vec![7, 8])
+            .await
+            .unwrap();
+        assert_eq!(
+            master.read_holding_registers(1, 8, 5).await,
+            Err(ModbusError::Exception(ExceptionCode::IllegalDataAddress))
+        );
+
+        //Responses carry no transaction id, so the window is kept at one transaction
+        master.set_params(ModbusMasterConnectionParams {
+            max_simultaneous_transactions: 4,
+            ..Default::default()
+        });
+        for address in 1..5 {
+            master
+                .add_read_holding_registers_query(1, address, 1)
+                .unwrap();
+        }
+        let results = master.query().await.unwrap();
+        assert_eq!(
+            results[&ModbusAddress::new(1, ModbusTable::HoldingRegisters, 3)].value,
+            Some(ModbusDataType::Register(8))
+        );
+        assert_eq!(results.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_rtu_broadcast_waits_for_turnaround() {
+        use crate::master::test_utils::connect_rtu;
+        use std::time::{Duration, Instant};
+
+        let mut settings = SerialSettings::new(19200);
+        let turnaround_delay = Duration::from_millis(300);
+        settings.timings = Some(RtuTimings {
+            turnaround_delay,
+            ..settings.timings()
+        });
+        let mut master = connect_rtu(settings).await;
+
+        //Nobody answers a broadcast, it's done as soon as it's sent
+        let started = Instant::now();
+        master.write_single_register(0, 2, 7).await.unwrap();
+        assert!(started.elapsed() < turnaround_delay);
+        assert!(master.read_holding_registers(0, 2, 1).await.is_err());
+
+        assert_eq!(master.read_holding_registers(1, 3, 1).await, Ok(vec![30]));
+        assert!(started.elapsed() >= turnaround_delay);
+    }
+}
@@ -0,0 +1,152 @@
+use std::future::Future;
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use futures::{FutureExt, Stream, StreamExt, TryFutureExt};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::Mutex;
+
+use crate::communication::{read_first, read_until_silent, ModbusSocket};
+
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for S {}
+
+//Any byte stream, like a tunnel or a proxy. Frames are told apart by the line going quiet as on TCP
+pub struct StreamSocket {
+    //Only ever used through &mut, the mutex just makes the socket Sync
+    stream: Mutex<Box<dyn AsyncStream>>,
+    //Read while waiting for the stream to become readable
+    pending: Vec<u8>,
+}
+
+impl StreamSocket {
+    pub fn new<S: AsyncStream + 'static>(stream: S) -> Self {
+        StreamSocket {
+            stream: Mutex::new(Box::new(stream)),
+            pending: Vec::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl ModbusSocket for StreamSocket {
+    async fn read(&mut self) -> Result<Vec<u8>> {
+        let pending = std::mem::take(&mut self.pending);
+        read_until_silent(self.stream.get_mut(), pending).await
+    }
+
+    async fn readable(&mut self) -> Result<()> {
+        read_first(self.stream.get_mut(), &mut self.pending).await
+    }
+
+    async fn write(&mut self, data: Vec<u8>) -> Result<()> {
+        let stream = self.stream.get_mut();
+        stream.write_all(&data).await?;
+        stream.flush().await?;
+        Ok(())
+    }
+}
+
+//Called for the first connection and again for every reconnect
+pub type StreamConnector = Arc<dyn Fn() -> BoxFuture<'static, Result<StreamSocket>> + Send + Sync>;
+
+//Yields the connections to serve, the server stops once it ends
+pub type StreamAcceptor = BoxStream<'static, Result<StreamSocket>>;
+
+pub fn stream_connector<F, Fut, S, E>(connect: F) -> StreamConnector
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<S, E>> + Send + 'static,
+    S: AsyncStream + 'static,
+    E: Into<anyhow::Error> + 'static,
+{
+    Arc::new(move || {
+        connect()
+            .map_ok(StreamSocket::new)
+            .map_err(Into::into)
+            .boxed()
+    })
+}
+
+pub fn stream_acceptor<A, S, E>(incoming: A) -> StreamAcceptor
+where
+    A: Stream<Item = Result<S, E>> + Send + 'static,
+    S: AsyncStream + 'static,
+    E: Into<anyhow::Error> + 'static,
+{
+    incoming
+        .map(|stream| stream.map(StreamSocket::new).map_err(Into::into))
+        .boxed()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::ModbusSubprotocol;
+    use crate::master::test_utils::MemoryCallBack;
+    use crate::master::ModbusMasterConnection;
+    use crate::slave::ModbusSlaveConnection;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn test_stream_socket_reads_what_the_other_end_wrote() {
+        let (first, second) = duplex(1024);
+        let mut first = StreamSocket::new(first);
+        let mut second = StreamSocket::new(second);
+
+        first.write(vec![1, 2, 3]).await.unwrap();
+        assert_eq!(second.read().await.unwrap(), vec![1, 2, 3]);
+
+        drop(first);
+        assert!(second.read().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connector_and_acceptor_streams() {
+        use futures::channel::mpsc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let (incoming, accepted) = mpsc::unbounded();
+        let mut server = ModbusSlaveConnection::new_with_acceptor(
+            accepted,
+            ModbusSubprotocol::ModbusRTUOverTCP,
+            Box::new(MemoryCallBack::with_test_registers()),
+        );
+        tokio::spawn(async move { server.serve().await });
+
+        //The first attempt fails, the master has to come back to the factory
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let connector_attempts = attempts.clone();
+        let mut master = ModbusMasterConnection::new_with_connector(
+            ModbusSubprotocol::ModbusRTUOverTCP,
+            move || {
+                let attempt = connector_attempts.fetch_add(1, Ordering::SeqCst);
+                let incoming = incoming.clone();
+
+                async move {
+                    if attempt == 0 {
+                        return Err(std::io::Error::other("Tunnel not up yet"));
+                    }
+                    let (client, server) = tokio::io::duplex(1024);
+                    incoming
+                        .unbounded_send(Ok::<_, std::io::Error>(server))
+                        .unwrap();
+                    Ok(client)
+                }
+            },
+        );
+
+        assert_eq!(
+            master.read_holding_registers(1, 1, 2).await,
+            Ok(vec![10, 20])
+        );
+        master.write_single_register(1, 3, 33).await.unwrap();
+        assert_eq!(master.read_holding_registers(1, 3, 1).await, Ok(vec![33]));
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,122 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::net::UdpSocket;
+
+use crate::communication::ModbusSocket;
+
+//Largest payload a datagram can carry, every ADU fits in one
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+//Binds an ephemeral local port and only takes datagrams from the server from then on
+pub async fn connect_udp(address: SocketAddr) -> Result<UdpSocket> {
+    let local: SocketAddr = match address {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(address).await?;
+    Ok(socket)
+}
+
+pub async fn recv_datagram(socket: &UdpSocket) -> Result<(Vec<u8>, SocketAddr)> {
+    let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+    let (n, source) = socket.recv_from(&mut buffer).await?;
+    buffer.truncate(n);
+    Ok((buffer, source))
+}
+
+//A connected socket, every read returns one datagram and every write sends one
+#[async_trait]
+impl ModbusSocket for UdpSocket {
+    async fn read(&mut self) -> Result<Vec<u8>> {
+        let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+        let n = self.recv(&mut buffer).await?;
+        buffer.truncate(n);
+        Ok(buffer)
+    }
+
+    async fn write(&mut self, data: Vec<u8>) -> Result<()> {
+        self.send(&data).await?;
+        Ok(())
+    }
+
+    async fn readable(&mut self) -> Result<()> {
+        Ok(UdpSocket::readable(self).await?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::master::test_utils::MemoryCallBack;
+    use crate::master::ModbusMasterConnection;
+    use crate::slave::ModbusSlaveConnection;
+    use tokio::time::Instant;
+
+    #[tokio::test]
+    async fn test_datagrams_are_kept_apart() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut client = connect_udp(server.local_addr().unwrap()).await.unwrap();
+
+        client.write(vec![1, 2]).await.unwrap();
+        client.write(vec![3]).await.unwrap();
+
+        let (first, source) = recv_datagram(&server).await.unwrap();
+        let (second, _) = recv_datagram(&server).await.unwrap();
+        assert_eq!((first, second), (vec![1, 2], vec![3]));
+
+        server.send_to(&[4, 5, 6], source).await.unwrap();
+        assert_eq!(client.read().await.unwrap(), vec![4, 5, 6]);
+    }
+
+    #[tokio::test]
+    async fn test_udp_master_and_slave() {
+        let mut server = ModbusSlaveConnection::new_udp(
+            "127.0.0.1:0".parse().unwrap(),
+            Box::new(MemoryCallBack::with_test_registers()),
+        );
+        server.bind().await.unwrap();
+        let address = server.local_addr().unwrap();
+        tokio::spawn(async move { server.serve().await });
+
+        let mut master = ModbusMasterConnection::new_udp(address);
+        assert_eq!(
+            master.read_holding_registers(1, 1, 2).await,
+            Ok(vec![10, 20])
+        );
+        master
+            .write_multiple_registers(1, 4, vec![44, 55])
+            .await
+            .unwrap();
+        assert_eq!(
+            master.read_holding_registers(1, 4, 2).await,
+            Ok(vec![44, 55])
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lost_udp_datagrams_are_sent_again() {
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut master = ModbusMasterConnection::new_udp(server.local_addr().unwrap());
+
+        //Drops the first request and answers the second one with the transaction id it carries
+        let answering = tokio::spawn(async move {
+            let mut buffer = [0u8; 64];
+            let (_, _) = server.recv_from(&mut buffer).await.unwrap();
+            let first_transaction = [buffer[0], buffer[1]];
+            let (_, source) = server.recv_from(&mut buffer).await.unwrap();
+            assert_ne!(first_transaction, [buffer[0], buffer[1]]);
+
+            let response = [buffer[0], buffer[1], 0, 0, 0, 5, 1, 0x03, 0x02, 0x00, 42];
+            server.send_to(&response, source).await.unwrap();
+        });
+
+        let started = Instant::now();
+        assert_eq!(master.read_holding_registers(1, 0, 1).await, Ok(vec![42]));
+        assert!(started.elapsed() >= master.get_params().max_response_time);
+        answering.await.unwrap();
+    }
+}
@@ -0,0 +1,95 @@
+use std::io::ErrorKind;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::communication::{read_until_silent, ModbusSocket};
+
+//Who is on the other end of a Unix socket, as reported by the kernel
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+    //Not every platform reports the process
+    pub pid: Option<i32>,
+}
+
+impl PeerCredentials {
+    pub fn of(stream: &UnixStream) -> Result<Self> {
+        let credentials = stream.peer_cred()?;
+
+        Ok(PeerCredentials {
+            uid: credentials.uid(),
+            gid: credentials.gid(),
+            pid: credentials.pid(),
+        })
+    }
+}
+
+//A socket left behind by a server that's gone is replaced. Anything else at the path, or a socket
+//something still accepts on, makes binding fail
+pub fn bind_unix(path: &Path) -> Result<UnixListener> {
+    let is_socket =
+        std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket());
+    if is_socket {
+        let refused = std::os::unix::net::UnixStream::connect(path)
+            .is_err_and(|err| err.kind() == ErrorKind::ConnectionRefused);
+        if refused {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    UnixListener::bind(path).map_err(|err| anyhow!("Can't bind {}: {}", path.display(), err))
+}
+
+#[async_trait]
+impl ModbusSocket for UnixStream {
+    async fn read(&mut self) -> Result<Vec<u8>> {
+        read_until_silent(self, Vec::new()).await
+    }
+
+    async fn write(&mut self, data: Vec<u8>) -> Result<()> {
+        self.write_all(&data).await?;
+        Ok(())
+    }
+
+    async fn readable(&mut self) -> Result<()> {
+        Ok(UnixStream::readable(self).await?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_peer_credentials_of_this_process() {
+        let (first, _second) = UnixStream::pair().unwrap();
+
+        let credentials = PeerCredentials::of(&first).unwrap();
+        assert_eq!(credentials.pid, Some(std::process::id() as i32));
+    }
+
+    #[tokio::test]
+    async fn test_only_stale_sockets_are_replaced() {
+        let path = std::env::temp_dir().join(format!(
+            "tweakable-modbus-{}-stale.sock",
+            std::process::id()
+        ));
+
+        let listener = bind_unix(&path).unwrap();
+        assert!(bind_unix(&path).is_err());
+        drop(listener);
+        drop(bind_unix(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        std::fs::write(&path, "").unwrap();
+        assert!(bind_unix(&path).is_err());
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -5,15 +5,61 @@ mod master;
 mod messages;
 mod slave;
 
+pub mod blocking;
+pub mod registers;
+
+//Lets the derive macros name this crate from within it too
+extern crate self as tweakable_modbus;
+
 pub use master::ModbusMasterConnection;
 pub use master::ModbusMasterConnectionParams;
+pub use master::ModbusMasterHandle;
+pub use master::ModbusDeviceHandle;
+pub use master::ModbusMasterPool;
+pub use master::QueryOptions;
+pub use master::WithQueryOptions;
+pub use master::Priority;
+pub use master::Pacing;
+pub use master::RetryPolicy;
+pub use master::Backoff;
+pub use master::RetryOn;
+pub use master::ConnectionState;
+pub use master::ReconnectPolicy;
+pub use master::Poller;
+pub use master::PollGroup;
+pub use master::PollSnapshot;
+pub use master::PolledValue;
+pub use master::CycleOverrun;
+pub use master::Deadband;
+pub use master::ChangeEvent;
+pub use master::Subscription;
+pub use master::SubscriptionEvent;
+pub use master::Scanner;
+pub use master::ScanOptions;
+pub use master::ScannedUnit;
+pub use master::DeviceInventory;
 
 pub use slave::ModbusSlaveConnection;
 pub use slave::ModbusSlaveConnectionParameters;
 pub use slave::ModbusCallBack;
 
+pub use registers::ModbusRegisters;
+
 pub use common::ModbusDataType;
+pub use common::ModbusError;
 pub use common::ModbusResult;
 pub use common::ModbusTable;
+pub use common::ModbusSubprotocol;
 pub use common::ModbusAddress;
+pub use common::Quality;
 pub use messages::ExceptionCode;
+
+pub use communication::SerialSettings;
+pub use communication::RtuTimings;
+pub use communication::DataBits;
+pub use communication::Parity;
+pub use communication::StopBits;
+pub use communication::FlowControl;
+pub use communication::AsyncStream;
+#[cfg(unix)]
+pub use communication::PeerCredentials;
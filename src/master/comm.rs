@@ -1,45 +1,177 @@
-use crate::communication::{AddressingInfo, ModbusSocket};
+use crate::communication::{
+    connect_udp, AddressingInfo, MemoryConnector, ModbusSocket, SerialSettings, StreamConnector,
+};
+use crate::master::retry::Backoff;
 use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::PathBuf;
 use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::time::{sleep_until, Duration, Instant};
 
 use anyhow::{anyhow, Result};
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    //The link is down but reconnects are still being tried
+    Degraded,
+    //Never connected, or down for longer than the allowed downtime
+    Disconnected,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReconnectPolicy {
+    pub connect_timeout: Duration,
+    pub backoff: Backoff,
+    //Fraction of every delay that is randomised, between 0 and 1
+    pub jitter: f64,
+    //Once the device has been unreachable for this long it's reported offline and queries fail
+    pub max_downtime: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            connect_timeout: Duration::from_secs(5),
+            backoff: Backoff::Exponential {
+                initial: Duration::from_millis(100),
+                max: Duration::from_secs(5),
+            },
+            jitter: 0.2,
+            max_downtime: Duration::from_secs(10),
+        }
+    }
+}
+
 pub struct ModbusMasterCommunicationInfo {
     pub comm: Option<Box<dyn ModbusSocket>>,
     addressing_info: AddressingInfo,
-    pub has_failed: bool
+    pub has_failed: bool,
+    state: watch::Sender<ConnectionState>,
+    down_since: Option<Instant>,
 }
 
 impl ModbusMasterCommunicationInfo {
-    pub fn new_tcp(address: SocketAddr) -> Self {
+    fn new(addressing_info: AddressingInfo) -> Self {
         ModbusMasterCommunicationInfo {
             comm: None,
-            addressing_info: AddressingInfo::TcpConnection { address },
-            has_failed: false
+            addressing_info,
+            has_failed: false,
+            state: watch::Sender::new(ConnectionState::Disconnected),
+            down_since: None,
         }
     }
 
-    #[allow(dead_code)]
-    pub fn new_rtu(device: String, baud_rate: u32) -> Self {
-        ModbusMasterCommunicationInfo {
-            comm: None,
-            addressing_info: AddressingInfo::RtuConnection { device, baud_rate },
-            has_failed: false
-        }
+    pub fn new_tcp(address: SocketAddr) -> Self {
+        Self::new(AddressingInfo::TcpConnection { address })
+    }
+
+    pub fn new_rtu(device: String, settings: SerialSettings) -> Self {
+        Self::new(AddressingInfo::RtuConnection { device, settings })
+    }
+
+    pub fn new_udp(address: SocketAddr) -> Self {
+        Self::new(AddressingInfo::UdpConnection { address })
+    }
+
+    #[cfg(unix)]
+    pub fn new_unix(path: PathBuf) -> Self {
+        Self::new(AddressingInfo::UnixConnection { path })
+    }
+
+    pub fn new_in_memory(connect: MemoryConnector) -> Self {
+        Self::new(AddressingInfo::InMemory { connect })
+    }
+
+    pub fn new_stream(connect: StreamConnector) -> Self {
+        Self::new(AddressingInfo::Stream { connect })
     }
 
     pub async fn connect(&mut self) -> Result<()> {
         self.comm = None;
 
-        if let AddressingInfo::TcpConnection { address } = &self.addressing_info {
-            let stream = TcpStream::connect(address).await?;
-            stream.set_linger(Some(std::time::Duration::from_secs(0)))?;
-            self.comm = Some(Box::new(stream));
-            self.has_failed = false;
-            Ok(())
-        } else {
-            Err(anyhow!("Addressing info didn't match tcp protocol"))
+        match &self.addressing_info {
+            AddressingInfo::TcpConnection { address } => {
+                let stream = TcpStream::connect(address).await?;
+                stream.set_linger(Some(std::time::Duration::from_secs(0)))?;
+                self.comm = Some(Box::new(stream));
+            }
+            AddressingInfo::RtuConnection { device, settings } => {
+                self.comm = Some(Box::new(settings.open(device)?));
+            }
+            AddressingInfo::UdpConnection { address } => {
+                self.comm = Some(Box::new(connect_udp(*address).await?));
+            }
+            #[cfg(unix)]
+            AddressingInfo::UnixConnection { path } => {
+                self.comm = Some(Box::new(tokio::net::UnixStream::connect(path).await?));
+            }
+            AddressingInfo::InMemory { connect } => {
+                self.comm = Some(Box::new(connect()));
+            }
+            AddressingInfo::Stream { connect } => {
+                self.comm = Some(Box::new(connect().await?));
+            }
         }
+
+        self.has_failed = false;
+        Ok(())
+    }
+
+    //Tries to connect with backoff until it works or the device has been down for longer than max_downtime
+    pub async fn reconnect(&mut self, policy: &ReconnectPolicy) -> Result<()> {
+        let mut attempts = 0;
+
+        loop {
+            self.set_state(ConnectionState::Connecting);
+
+            let err = match tokio::time::timeout(policy.connect_timeout, self.connect()).await {
+                Ok(Ok(())) => {
+                    self.down_since = None;
+                    self.set_state(ConnectionState::Connected);
+                    return Ok(());
+                }
+                Ok(Err(err)) => err,
+                Err(_) => anyhow!("Connection attempt timed out"),
+            };
+
+            attempts += 1;
+            let now = Instant::now();
+            let offline_at = *self.down_since.get_or_insert(now) + policy.max_downtime;
+
+            if now >= offline_at {
+                self.set_state(ConnectionState::Disconnected);
+                return Err(err);
+            }
+
+            self.set_state(ConnectionState::Degraded);
+            //The last attempt is made right when the allowed downtime runs out
+            sleep_until((now + policy.backoff.delay(attempts, policy.jitter)).min(offline_at))
+                .await;
+        }
+    }
+
+    //The link can't be trusted anymore, it'll be reconnected before the next request
+    pub fn mark_failed(&mut self) {
+        self.has_failed = true;
+        self.down_since.get_or_insert_with(Instant::now);
+        self.set_state(ConnectionState::Degraded);
+    }
+
+    fn set_state(&self, state: ConnectionState) {
+        self.state.send_if_modified(|current| {
+            if *current == state {
+                return false;
+            }
+            *current = state;
+            true
+        });
+    }
+
+    pub fn subscribe_state(&self) -> watch::Receiver<ConnectionState> {
+        self.state.subscribe()
     }
 
     pub async fn is_connected(&mut self) -> bool {
@@ -47,6 +179,57 @@ impl ModbusMasterCommunicationInfo {
             return false;
         }
 
-        ! self.has_failed
+        !self.has_failed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn policy(max_downtime: Duration) -> ReconnectPolicy {
+        ReconnectPolicy {
+            connect_timeout: Duration::from_millis(100),
+            backoff: Backoff::Fixed(Duration::from_millis(50)),
+            jitter: 0.0,
+            max_downtime,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_reports_state_changes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut comm = ModbusMasterCommunicationInfo::new_tcp(listener.local_addr().unwrap());
+        let state = comm.subscribe_state();
+        assert_eq!(*state.borrow(), ConnectionState::Disconnected);
+
+        comm.reconnect(&policy(Duration::from_secs(1))).await.unwrap();
+        assert_eq!(*state.borrow(), ConnectionState::Connected);
+
+        comm.mark_failed();
+        assert!(!comm.is_connected().await);
+        assert_eq!(*state.borrow(), ConnectionState::Degraded);
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_gives_up_after_max_downtime() {
+        let address = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut comm = ModbusMasterCommunicationInfo::new_tcp(address);
+        let state = comm.subscribe_state();
+
+        let started = Instant::now();
+        assert!(comm.reconnect(&policy(Duration::from_millis(200))).await.is_err());
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert_eq!(*state.borrow(), ConnectionState::Disconnected);
+
+        //The device is already offline, the next attempt fails right away
+        let started = Instant::now();
+        assert!(comm.reconnect(&policy(Duration::from_millis(200))).await.is_err());
+        assert!(started.elapsed() < Duration::from_millis(200));
     }
 }
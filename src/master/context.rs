@@ -1,215 +1,1782 @@
-use anyhow::Result;
 use std::cell::Cell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
+use tokio::time::{Duration, Instant};
 
 use crate::{
     codec::ModbusSerialize,
-    common::{ModbusResult,ModbusAddress, ModbusSubprotocol},
-    messages::{ModbusQuery, ModbusResponse},
+    common::{
+        Address, ModbusAddress, ModbusDataType, ModbusError, ModbusResult, ModbusSubprotocol,
+        Quality, SlaveId,
+    },
+    messages::{
+        query::{ReadQueryParameters, SingleWriteQueryParameters},
+        response::{
+            MaskWriteResponseParameters, MultipleWriteResponse, ReadResponseParameters,
+            SingleWriteResponseParameters,
+        },
+        ExceptionCode, FunctionCode, ModbusMessageData, ModbusQuery, ModbusResponse,
+    },
 };
 
 use crate::common::ModbusTable;
+use crate::master::optimizer::{optimize_queries, values_for_original};
+use crate::master::pacing::{Pacer, Pacing};
+use crate::master::queue::{Priority, QueryQueue};
+use crate::master::retry::RetryPolicy;
+use crate::master::validation::validate_response;
+
+//Bit level operations are not part of modbus, they are built on top of register queries.
+//Each variant describes what has to be done with the response of the query it's attached to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BitOperation {
+    Read { bit: u8 },
+    MaskWrite { bit: u8, value: bool },
+    ReadModifyWrite { bit: u8, value: bool },
+    WriteBack { bit: u8 },
+}
+
+impl BitOperation {
+    fn bit(&self) -> u8 {
+        match *self {
+            BitOperation::Read { bit }
+            | BitOperation::MaskWrite { bit, .. }
+            | BitOperation::ReadModifyWrite { bit, .. }
+            | BitOperation::WriteBack { bit } => bit,
+        }
+    }
+}
+
+//Identifies a query added by the user, every request derived from it shares the same id
+pub type QueryId = u64;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueuedQuery {
+    pub id: QueryId,
+    pub query: ModbusQuery,
+    pub bit_operation: Option<BitOperation>,
+    //Whether the optimizer is allowed to merge this query with others
+    pub coalesce: bool,
+    //Queries that were merged into this one, their results are fanned out from its response
+    pub coalesced: Vec<QueuedQuery>,
+    //Times this query has been put on the wire
+    pub attempts: u32,
+    //Overrides the retry policy of the device and the connection
+    pub retry_policy: Option<RetryPolicy>,
+    //Retried queries wait for their backoff before being sent again
+    pub not_before: Option<Instant>,
+    //Last time this query was put on the wire
+    pub requested_at: Option<SystemTime>,
+    pub priority: Priority,
+    //Confirmed single and multiple writes are read back and compared
+    pub verify: bool,
+    //Values the read back of a verified write has to find
+    pub expected_values: Option<Vec<ModbusDataType>>,
+}
+
+impl QueuedQuery {
+    pub fn new(query: ModbusQuery) -> Self {
+        QueuedQuery {
+            id: 0,
+            query,
+            bit_operation: None,
+            coalesce: true,
+            coalesced: vec![],
+            attempts: 0,
+            retry_policy: None,
+            not_before: None,
+            requested_at: None,
+            priority: Priority::default(),
+            verify: false,
+            expected_values: None,
+        }
+    }
+
+    pub fn new_bit_operation(query: ModbusQuery, bit_operation: BitOperation) -> Self {
+        QueuedQuery {
+            bit_operation: Some(bit_operation),
+            ..QueuedQuery::new(query)
+        }
+    }
+
+    pub fn derived(&self, query: QueuedQuery) -> QueuedQuery {
+        QueuedQuery {
+            id: self.id,
+            retry_policy: self.retry_policy,
+            priority: self.priority,
+            verify: self.verify,
+            ..query
+        }
+    }
+
+    fn ids(&self) -> Vec<QueryId> {
+        if self.coalesced.is_empty() {
+            vec![self.id]
+        } else {
+            self.coalesced
+                .iter()
+                .flat_map(|query| query.ids())
+                .collect()
+        }
+    }
+
+    //Addresses the results of this query are recorded under, with the query they belong to
+    fn result_addresses(&self) -> Vec<(QueryId, ModbusAddress)> {
+        if !self.coalesced.is_empty() {
+            return self
+                .coalesced
+                .iter()
+                .flat_map(|query| query.result_addresses())
+                .collect();
+        }
+
+        let slave_id = self.query.get_message_data().slave_id;
+        let ranges = match &self.query {
+            ModbusQuery::ReadQuery { params, .. } => {
+                vec![(params.table, params.starting_address, params.ammount)]
+            }
+            ModbusQuery::SingleWriteQuery { params, .. } => {
+                vec![(params.table, params.starting_address, 1)]
+            }
+            ModbusQuery::MultipleWriteQuery { params, .. } => vec![(
+                params.table,
+                params.starting_address,
+                params.values.len() as u16,
+            )],
+            ModbusQuery::MultipleReadWriteQuery { params, .. } => vec![
+                (
+                    params.table,
+                    params.read_starting_address,
+                    params.read_ammount,
+                ),
+                (
+                    params.table,
+                    params.write_starting_address,
+                    params.values.len() as u16,
+                ),
+            ],
+            ModbusQuery::MaskWriteQuery { params, .. } => vec![(params.table, params.address, 1)],
+        };
+        let bit = self.bit_operation.map(|bit_operation| bit_operation.bit());
+
+        ranges
+            .into_iter()
+            .flat_map(|(table, address, ammount)| {
+                (address..address.saturating_add(ammount)).map(move |address| ModbusAddress {
+                    slave_id,
+                    table,
+                    address,
+                    bit,
+                })
+            })
+            .map(|address| (self.id, address))
+            .collect()
+    }
+
+    //Register guarded by a read-modify-write, other read-modify-writes of it wait until the write
+    //back is answered. Plain writes to it aren't held back
+    fn guarded_register(&self) -> Option<(SlaveId, Address)> {
+        match (&self.bit_operation, &self.query) {
+            (
+                Some(BitOperation::ReadModifyWrite { .. }),
+                ModbusQuery::ReadQuery {
+                    message_data,
+                    params,
+                },
+            ) => Some((message_data.slave_id, params.starting_address)),
+            (
+                Some(BitOperation::WriteBack { .. }),
+                ModbusQuery::SingleWriteQuery {
+                    message_data,
+                    params,
+                },
+            ) => Some((message_data.slave_id, params.starting_address)),
+            _ => None,
+        }
+    }
+}
+
+//A query that was sent and is waiting for its response
+#[derive(Clone, Debug)]
+pub struct InFlightTransaction {
+    pub query: QueuedQuery,
+    pub sent_at: Instant,
+    pub deadline: Instant,
+}
+
+//When the transaction a result comes from went out and came back
+#[derive(Clone, Copy, Debug)]
+struct RoundTrip {
+    requested_at: Option<SystemTime>,
+    responded_at: Option<SystemTime>,
+    latency: Option<Duration>,
+    //The response came after the deadline, values in it may be outdated
+    late: bool,
+}
+
+impl RoundTrip {
+    fn unanswered(requested_at: Option<SystemTime>) -> Self {
+        RoundTrip {
+            requested_at,
+            responded_at: None,
+            latency: None,
+            late: false,
+        }
+    }
+}
 
 //This struct is meant to hold the state of the on going modbus communication
 pub struct ModbusMasterContext {
-    pub queued_queries: Vec<ModbusQuery>,
-    pub on_going_queries: HashMap<u16, ModbusQuery>,
+    pub queued_queries: QueryQueue,
+    pub on_going_queries: HashMap<u16, InFlightTransaction>,
     current_transaction_id: Cell<u16>,
+    next_query_id: QueryId,
+    mask_write_unsupported: HashSet<SlaveId>,
+    //Results of every query, kept until they are collected
+    answered_queries: HashMap<QueryId, HashMap<ModbusAddress, ModbusResult>>,
+    failed_queries: HashMap<QueryId, ModbusError>,
+    retry_policy: RetryPolicy,
+    device_retry_policies: HashMap<SlaveId, RetryPolicy>,
+    pacer: Pacer,
 }
 
 impl ModbusMasterContext {
     pub fn new() -> Self {
         ModbusMasterContext {
             current_transaction_id: Cell::new(1),
-            queued_queries: Vec::new(),
+            queued_queries: QueryQueue::new(),
             on_going_queries: HashMap::new(),
+            next_query_id: 1,
+            mask_write_unsupported: HashSet::new(),
+            answered_queries: HashMap::new(),
+            failed_queries: HashMap::new(),
+            retry_policy: RetryPolicy::default(),
+            device_retry_policies: HashMap::new(),
+            pacer: Pacer::default(),
         }
     }
 
-    fn get_next_free_transaction_id(&self) -> u16 {
-        let result = self.current_transaction_id.get();
-        if result == u16::MAX {
-            self.current_transaction_id.set(0);
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    pub fn set_device_retry_policy(
+        &mut self,
+        slave_id: SlaveId,
+        retry_policy: Option<RetryPolicy>,
+    ) {
+        match retry_policy {
+            Some(retry_policy) => self.device_retry_policies.insert(slave_id, retry_policy),
+            None => self.device_retry_policies.remove(&slave_id),
+        };
+    }
+
+    fn retry_policy_for(&self, query: &QueuedQuery) -> RetryPolicy {
+        let slave_id = query.query.get_message_data().slave_id;
+        query
+            .retry_policy
+            .or_else(|| self.device_retry_policies.get(&slave_id).copied())
+            .unwrap_or(self.retry_policy)
+    }
+
+    //Queues the query again after its backoff when the policy allows it, otherwise it's failed
+    fn retry_or_fail(&mut self, mut query: QueuedQuery, error: ModbusError, now: Instant) {
+        let retry_policy = self.retry_policy_for(&query);
+
+        if !retry_policy.should_retry(query.attempts, &error) {
+            self.fail_query(&query, &error);
+            return;
+        }
+
+        query.query.get_message_data().transaction_id.set(None);
+        query.not_before = Some(now + retry_policy.delay(query.attempts));
+        self.queued_queries.push(query);
+    }
+
+    pub fn enqueue(&mut self, mut query: QueuedQuery) -> QueryId {
+        query.id = self.next_query_id;
+        self.next_query_id += 1;
+        let id = query.id;
+        self.queued_queries.push(query);
+        id
+    }
+
+    pub fn set_pacing(&mut self, pacing: Pacing) {
+        self.pacer.set_pacing(pacing);
+    }
+
+    pub fn set_fair_scheduling(&mut self, fair: bool) {
+        self.queued_queries.set_fair(fair);
+    }
+
+    pub fn has_queued_queries(&self) -> bool {
+        !self.queued_queries.is_empty()
+    }
+
+    //A query is pending while it, or anything derived from it, is waiting to be sent or answered
+    pub fn is_pending(&self, id: QueryId) -> bool {
+        self.queued_queries
+            .iter()
+            .chain(
+                self.on_going_queries
+                    .values()
+                    .map(|transaction| &transaction.query),
+            )
+            .any(|query| query.ids().contains(&id))
+    }
+
+    fn record(
+        &mut self,
+        id: QueryId,
+        address: ModbusAddress,
+        value: Option<ModbusDataType>,
+        quality: Quality,
+        round_trip: RoundTrip,
+    ) {
+        let quality = if round_trip.late && value.is_some() && quality == Quality::Good {
+            Quality::Stale
         } else {
-            self.current_transaction_id.set(result + 1);
+            quality
+        };
+
+        self.answered_queries.entry(id).or_default().insert(
+            address,
+            ModbusResult {
+                value,
+                quality,
+                requested_at: round_trip.requested_at,
+                responded_at: round_trip.responded_at,
+                latency: round_trip.latency,
+                read_back_failure: None,
+            },
+        );
+    }
+
+    //The write was confirmed, only reading it back failed
+    fn record_read_back_failure(
+        &mut self,
+        id: QueryId,
+        address: ModbusAddress,
+        failure: Quality,
+        round_trip: RoundTrip,
+    ) {
+        self.record(id, address.clone(), None, Quality::Good, round_trip);
+        if let Some(result) = self
+            .answered_queries
+            .get_mut(&id)
+            .and_then(|results| results.get_mut(&address))
+        {
+            result.read_back_failure = Some(failure);
+        }
+    }
+
+    fn fail(&mut self, id: QueryId, error: ModbusError) {
+        self.failed_queries.insert(id, error);
+    }
+
+    //Every address of the query still gets a result, with the quality the error maps to
+    fn fail_query(&mut self, query: &QueuedQuery, error: &ModbusError) {
+        let round_trip = RoundTrip::unanswered(query.requested_at);
+        if query.expected_values.is_some() {
+            for (id, address) in query.result_addresses() {
+                self.record_read_back_failure(id, address, Quality::from(error), round_trip);
+            }
+            return;
+        }
+
+        for (id, address) in query.result_addresses() {
+            self.record(id, address, None, Quality::from(error), round_trip);
         }
 
-        result
+        for id in query.ids() {
+            self.fail(id, error.clone());
+        }
     }
 
-    pub fn load_queued_queries(&mut self, ammount: u32) {
-        self.on_going_queries.clear();
+    //Every query still waiting to be sent or answered is given up with the given error
+    pub fn fail_all_queries(&mut self, error: ModbusError) {
+        let queries: Vec<QueuedQuery> = self
+            .queued_queries
+            .take_all()
+            .into_iter()
+            .chain(
+                self.on_going_queries
+                    .drain()
+                    .map(|(_, transaction)| transaction.query),
+            )
+            .collect();
 
-        for _index in 0..ammount {
-            if self.queued_queries.is_empty()
-            {
-                break;
+        for query in queries {
+            self.fail_query(&query, &error);
+        }
+    }
+
+    //The connection broke, queries on the wire are sent again if their retry policy allows it
+    pub fn requeue_on_going_queries(&mut self, error: ModbusError, now: Instant) {
+        let mut transactions: Vec<(u16, InFlightTransaction)> =
+            self.on_going_queries.drain().collect();
+        //Latest transactions are pushed first so the oldest one is sent again first
+        transactions.sort_by_key(|(_, transaction)| std::cmp::Reverse(transaction.sent_at));
+
+        for (_, transaction) in transactions {
+            self.retry_or_fail(transaction.query, error.clone(), now);
+        }
+    }
+
+    //Transactions past their deadline are retried or failed on their own, a late response for them is discarded
+    pub fn expire_transactions(&mut self, now: Instant) {
+        let expired: Vec<u16> = self
+            .on_going_queries
+            .iter()
+            .filter(|(_, transaction)| transaction.deadline <= now)
+            .map(|(transaction_id, _)| *transaction_id)
+            .collect();
+
+        for transaction_id in expired {
+            if let Some(transaction) = self.on_going_queries.remove(&transaction_id) {
+                self.retry_or_fail(transaction.query, ModbusError::Timeout, now);
             }
-            let query = self.queued_queries.pop().unwrap();
-            let message_data = query.get_message_data();
-            message_data
-                .transaction_id
-                .set(Some(self.get_next_free_transaction_id()));
-            self.on_going_queries
-                .insert(message_data.transaction_id.get().unwrap(), query.clone());
         }
     }
 
-    pub fn serialize_queries(&self, subprotocol: ModbusSubprotocol) -> Result<Vec<u8>> {
-        let mut result = vec![];
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.on_going_queries
+            .values()
+            .map(|transaction| transaction.deadline)
+            .min()
+    }
+
+    //Earliest time a query waiting for its backoff can be sent
+    pub fn next_retry(&self, now: Instant) -> Option<Instant> {
+        self.queued_queries
+            .iter()
+            .filter_map(|query| query.not_before)
+            .filter(|not_before| *not_before > now)
+            .min()
+    }
 
-        for (_transaction_id, query) in &self.on_going_queries {
-            result.extend_from_slice(&query.serialize(subprotocol)?);
+    //Earliest time pacing lets one of the queued queries go out, None when nothing is held back by it
+    pub fn next_paced_send(&self, now: Instant) -> Option<Instant> {
+        self.queued_queries
+            .iter()
+            .map(|query| {
+                self.pacer
+                    .ready_at(query.query.get_message_data().slave_id, now)
+            })
+            .filter(|ready_at| *ready_at > now)
+            .min()
+    }
+
+    pub fn in_flight_transactions(&self) -> usize {
+        self.on_going_queries.len()
+    }
+
+    pub fn take_outcome(
+        &mut self,
+        id: QueryId,
+    ) -> Option<Result<HashMap<ModbusAddress, ModbusResult>, ModbusError>> {
+        let answered = self.answered_queries.remove(&id);
+        if let Some(error) = self.failed_queries.remove(&id) {
+            return Some(Err(error));
         }
+        answered.map(Ok)
+    }
 
-        Ok(result)
+    //Collects the results of every answered query into a single map, failed queries are left out
+    pub fn take_results(&mut self) -> HashMap<ModbusAddress, ModbusResult> {
+        self.failed_queries.clear();
+        self.answered_queries
+            .drain()
+            .flat_map(|(_, results)| results)
+            .collect()
     }
 
-    pub fn process_modbus_responses(
+    fn get_next_free_transaction_id(&self) -> u16 {
+        loop {
+            let result = self.current_transaction_id.get();
+            if result == u16::MAX {
+                self.current_transaction_id.set(0);
+            } else {
+                self.current_transaction_id.set(result + 1);
+            }
+
+            //After wrapping around, ids still in flight can't be handed out again
+            if !self.on_going_queries.contains_key(&result) {
+                return result;
+            }
+        }
+    }
+
+    pub fn supports_mask_write(&self, slave_id: SlaveId) -> bool {
+        !self.mask_write_unsupported.contains(&slave_id)
+    }
+
+    pub fn optimize_queued_queries(&mut self, gap_tolerance: Option<u16>) {
+        let queries = self.queued_queries.take_all();
+        for query in optimize_queries(queries, gap_tolerance) {
+            self.queued_queries.push(query);
+        }
+    }
+
+    //Registers that can't be touched by a new read-modify-write right now
+    fn guarded_registers(&self) -> HashSet<(SlaveId, Address)> {
+        let pending_write_backs = self
+            .queued_queries
+            .iter()
+            .filter(|query| matches!(query.bit_operation, Some(BitOperation::WriteBack { .. })));
+
+        self.on_going_queries
+            .values()
+            .map(|transaction| &transaction.query)
+            .chain(pending_write_backs)
+            .filter_map(|query| query.guarded_register())
+            .collect()
+    }
+
+    fn pop_next_query(&mut self, now: Instant) -> Option<QueuedQuery> {
+        let guarded_registers = self.guarded_registers();
+        let pacer = &self.pacer;
+
+        //Guarded read-modify-writes, retries waiting for their backoff and units held back by
+        //pacing stay in place
+        self.queued_queries.pop(|query| {
+            if query.not_before.is_some_and(|not_before| not_before > now)
+                || pacer.ready_at(query.query.get_message_data().slave_id, now) > now
+            {
+                return false;
+            }
+
+            !matches!(
+                query.bit_operation,
+                Some(BitOperation::ReadModifyWrite { .. })
+            ) || query
+                .guarded_register()
+                .is_none_or(|register| !guarded_registers.contains(&register))
+        })
+    }
+
+    //Takes the next query that can be sent, gives it a transaction id and returns its bytes.
+    //Queries that can't be serialized are failed on their own
+    pub fn start_next_transaction(
         &mut self,
-        responses: Vec<ModbusResponse>,
-        address_map: &mut HashMap<ModbusAddress, ModbusResult>,
-    ) {
+        subprotocol: ModbusSubprotocol,
+        now: Instant,
+        max_response_time: Duration,
+    ) -> Option<Vec<u8>> {
+        loop {
+            let mut query = self.pop_next_query(now)?;
+
+            let transaction_id = self.get_next_free_transaction_id();
+            query
+                .query
+                .get_message_data()
+                .transaction_id
+                .set(Some(transaction_id));
+
+            let bytes = match query.query.serialize(subprotocol) {
+                Ok(bytes) => bytes,
+                Err(err) => {
+                    self.fail_query(&query, &ModbusError::InvalidQuery(err.to_string()));
+                    continue;
+                }
+            };
+
+            query.attempts += 1;
+            query.requested_at = Some(SystemTime::now());
+            self.pacer
+                .record_request(query.query.get_message_data().slave_id, now);
+            self.on_going_queries.insert(
+                transaction_id,
+                InFlightTransaction {
+                    query,
+                    sent_at: now,
+                    deadline: now + max_response_time,
+                },
+            );
+
+            return Some(bytes);
+        }
+    }
+
+    pub fn process_modbus_responses(&mut self, responses: Vec<ModbusResponse>, now: Instant) {
         for response in responses {
-            let transaction_id = response.get_message_data().transaction_id.get();
-            if let None = transaction_id {
+            //Responses without transaction id come from subprotocols that only allow one transaction at a time
+            let transaction_id = match response.get_message_data().transaction_id.get() {
+                Some(transaction_id) => transaction_id,
+                None if self.on_going_queries.len() == 1 => {
+                    *self.on_going_queries.keys().next().unwrap()
+                }
+                None => continue,
+            };
+
+            //Unknown ids belong to transactions that already expired
+            let Some(transaction) = self.on_going_queries.remove(&transaction_id) else {
+                continue;
+            };
+            self.pacer.record_response(&transaction.query.query, now);
+
+            //A response that doesn't answer the query is never written into the results
+            if let Err(error) = validate_response(&transaction.query.query, &response) {
+                self.retry_or_fail(transaction.query, error, now);
                 continue;
             }
 
-            let transaction_id = transaction_id.unwrap();
+            if let ModbusResponse::Error { exception_code, .. } = &response {
+                let error = ModbusError::Exception(*exception_code);
+                if self
+                    .retry_policy_for(&transaction.query)
+                    .should_retry(transaction.query.attempts, &error)
+                {
+                    self.retry_or_fail(transaction.query, error, now);
+                    continue;
+                }
+            }
 
-            if !self.on_going_queries.contains_key(&transaction_id) {
-                continue;
+            let round_trip = RoundTrip {
+                requested_at: transaction.query.requested_at,
+                responded_at: Some(SystemTime::now()),
+                latency: Some(now.saturating_duration_since(transaction.sent_at)),
+                late: now > transaction.deadline,
+            };
+            self.process_modbus_response(transaction.query, response, round_trip);
+        }
+    }
+
+    fn process_modbus_response(
+        &mut self,
+        queued_query: QueuedQuery,
+        response: ModbusResponse,
+        round_trip: RoundTrip,
+    ) {
+        if !queued_query.coalesced.is_empty() {
+            self.process_coalesced_response(queued_query, response, round_trip);
+            return;
+        }
+
+        if queued_query.bit_operation.is_some() {
+            self.process_bit_response(queued_query, response, round_trip);
+            return;
+        }
+
+        if queued_query.expected_values.is_some() {
+            self.process_read_back_response(queued_query, response, round_trip);
+            return;
+        }
+
+        if queued_query.verify
+            && matches!(
+                response,
+                ModbusResponse::SingleWriteResponse { .. }
+                    | ModbusResponse::MultipleWriteResponse { .. }
+            )
+        {
+            if let Some(read_back) = Self::read_back_query(&queued_query.query) {
+                self.queued_queries.push(queued_query.derived(read_back));
+                return;
             }
+        }
 
-            let slave_id = response.get_message_data().slave_id;
+        let slave_id = response.get_message_data().slave_id;
+        let id = queued_query.id;
+        let query = queued_query.query;
 
-            match response {
-                ModbusResponse::Error {
-                    message_data: _message_data,
-                    exception_code,
+        match response {
+            ModbusResponse::Error {
+                message_data: _message_data,
+                exception_code,
+            } => match query {
+                ModbusQuery::ReadQuery {
+                    message_data,
+                    params,
                 } => {
-                    let query = self.on_going_queries.get(&transaction_id).unwrap();
-
-                    match query {
-                        ModbusQuery::ReadQuery {
-                            message_data,
-                            params,
-                        } => {
-                            for address in
-                                params.starting_address..params.starting_address + params.ammount
-                            {
-                                let table = ModbusTable::get_table_from_function_code(
-                                    message_data.function_code,
-                                )
+                    for address in params.starting_address..params.starting_address + params.ammount
+                    {
+                        let table =
+                            ModbusTable::get_table_from_function_code(message_data.function_code)
                                 .unwrap();
-                                address_map.insert(
-                                    ModbusAddress { table, address, slave_id },
-                                    ModbusResult::Error(exception_code),
-                                );
-                            }
-                        }
-                        ModbusQuery::SingleWriteQuery {
-                            message_data,
-                            params,
-                        } => {
-                            let table = ModbusTable::get_table_from_function_code(
-                                message_data.function_code,
-                            )
-                            .unwrap();
-                            address_map.insert(
-                                ModbusAddress {
-                                    slave_id,
-                                    table,
-                                    address: params.starting_address,
-                                },
-                                ModbusResult::Error(exception_code),
-                            );
-                        }
-                        ModbusQuery::MultipleWriteQuery {
-                            message_data,
-                            params,
-                        } => {
-                            let table = ModbusTable::get_table_from_function_code(
-                                message_data.function_code,
-                            )
-                            .unwrap();
-                            for address in params.starting_address
-                                ..params.starting_address + params.values.len() as u16
-                            {
-                                address_map.insert(
-                                    ModbusAddress {
-                                        slave_id,
-                                        table,
-                                        address,
-                                    },
-                                    ModbusResult::Error(exception_code),
-                                );
-                            }
-                        }
-                        ModbusQuery::MultipleReadWriteQuery {
-                            message_data,
-                            params,
-                        } => {
-                            let table = ModbusTable::get_table_from_function_code(message_data.function_code).unwrap();
-                            for address in params.read_starting_address
-                                ..params.read_starting_address + params.read_ammount
-                            {
-                                address_map.insert(ModbusAddress { slave_id,table, address}, ModbusResult::Error(exception_code));
-                            }
-
-                            for address in params.write_starting_address
-                                ..params.write_starting_address + params.values.len() as u16
-                            {
-                                address_map.insert(ModbusAddress { slave_id,table, address}, ModbusResult::Error(exception_code));
-                            }
-                        }
+                        self.record(
+                            id,
+                            ModbusAddress::new(slave_id, table, address),
+                            None,
+                            Quality::Exception(exception_code),
+                            round_trip,
+                        );
                     }
                 }
-                ModbusResponse::SingleWriteResponse {
+                ModbusQuery::SingleWriteQuery {
                     message_data,
                     params,
                 } => {
-                    let table = ModbusTable::get_table_from_function_code(message_data.function_code).unwrap();
-                    address_map.insert(ModbusAddress { slave_id,table, address: params.address}, ModbusResult::WriteConfirmation);
+                    let table =
+                        ModbusTable::get_table_from_function_code(message_data.function_code)
+                            .unwrap();
+                    self.record(
+                        id,
+                        ModbusAddress::new(slave_id, table, params.starting_address),
+                        None,
+                        Quality::Exception(exception_code),
+                        round_trip,
+                    );
                 }
-                ModbusResponse::MultipleWriteResponse {
+                ModbusQuery::MultipleWriteQuery {
                     message_data,
                     params,
                 } => {
-                    let table = ModbusTable::get_table_from_function_code(message_data.function_code).unwrap();
-                    for address in params.address..params.address + params.ammount {
-                        address_map.insert(ModbusAddress { slave_id,table, address }, ModbusResult::WriteConfirmation);
+                    let table =
+                        ModbusTable::get_table_from_function_code(message_data.function_code)
+                            .unwrap();
+                    for address in params.starting_address
+                        ..params.starting_address + params.values.len() as u16
+                    {
+                        self.record(
+                            id,
+                            ModbusAddress::new(slave_id, table, address),
+                            None,
+                            Quality::Exception(exception_code),
+                            round_trip,
+                        );
                     }
                 }
-                ModbusResponse::ReadResponse {
+                ModbusQuery::MultipleReadWriteQuery {
                     message_data,
-                    mut params,
+                    params,
                 } => {
-                    let query = self.on_going_queries.get(&transaction_id).unwrap();
+                    let table =
+                        ModbusTable::get_table_from_function_code(message_data.function_code)
+                            .unwrap();
+                    for address in params.read_starting_address
+                        ..params.read_starting_address + params.read_ammount
+                    {
+                        self.record(
+                            id,
+                            ModbusAddress::new(slave_id, table, address),
+                            None,
+                            Quality::Exception(exception_code),
+                            round_trip,
+                        );
+                    }
 
-                    let table = ModbusTable::get_table_from_function_code(message_data.function_code).unwrap();
-                    if let ModbusQuery::ReadQuery {
+                    for address in params.write_starting_address
+                        ..params.write_starting_address + params.values.len() as u16
+                    {
+                        self.record(
+                            id,
+                            ModbusAddress::new(slave_id, table, address),
+                            None,
+                            Quality::Exception(exception_code),
+                            round_trip,
+                        );
+                    }
+                }
+                ModbusQuery::MaskWriteQuery {
+                    message_data: _message_data,
+                    params,
+                } => {
+                    self.record(
+                        id,
+                        ModbusAddress::new(slave_id, params.table, params.address),
+                        None,
+                        Quality::Exception(exception_code),
+                        round_trip,
+                    );
+                }
+            },
+            ModbusResponse::SingleWriteResponse {
+                message_data,
+                params,
+            } => {
+                let table =
+                    ModbusTable::get_table_from_function_code(message_data.function_code).unwrap();
+                self.record(
+                    id,
+                    ModbusAddress::new(slave_id, table, params.address),
+                    None,
+                    Quality::Good,
+                    round_trip,
+                );
+            }
+            ModbusResponse::MultipleWriteResponse {
+                message_data,
+                params,
+            } => {
+                let table =
+                    ModbusTable::get_table_from_function_code(message_data.function_code).unwrap();
+                for address in params.address..params.address + params.ammount {
+                    self.record(
+                        id,
+                        ModbusAddress::new(slave_id, table, address),
+                        None,
+                        Quality::Good,
+                        round_trip,
+                    );
+                }
+            }
+            ModbusResponse::MaskWriteResponse {
+                message_data: _message_data,
+                params,
+            } => {
+                self.record(
+                    id,
+                    ModbusAddress::new(slave_id, params.table, params.address),
+                    None,
+                    Quality::Good,
+                    round_trip,
+                );
+            }
+            ModbusResponse::ReadResponse {
+                message_data,
+                mut params,
+            } => {
+                let table =
+                    ModbusTable::get_table_from_function_code(message_data.function_code).unwrap();
+                let (starting_address, ammount) = match query {
+                    ModbusQuery::ReadQuery {
                         message_data: _message_data,
                         params: query_params,
-                    } = query
-                    {
-                        params.values.truncate(query_params.ammount as usize);
-                        let mut address = query_params.starting_address;
-                        
-                        for value in params.values {
-                            address_map.insert(ModbusAddress { slave_id,table, address }, ModbusResult::ReadResult(value));
-                            address += 1;
+                    } => (query_params.starting_address, query_params.ammount),
+                    ModbusQuery::MultipleReadWriteQuery {
+                        message_data: _message_data,
+                        params: query_params,
+                    } => {
+                        //Writes are done before reads, so read values take precedence
+                        let write_ammount = query_params.values.len() as u16;
+                        for address in query_params.write_starting_address
+                            ..query_params.write_starting_address + write_ammount
+                        {
+                            self.record(
+                                id,
+                                ModbusAddress::new(slave_id, table, address),
+                                None,
+                                Quality::Good,
+                                round_trip,
+                            );
                         }
+                        (
+                            query_params.read_starting_address,
+                            query_params.read_ammount,
+                        )
                     }
+                    _ => return,
+                };
+
+                params.values.truncate(ammount as usize);
+
+                for (address, value) in (starting_address..).zip(params.values) {
+                    self.record(
+                        id,
+                        ModbusAddress::new(slave_id, table, address),
+                        Some(value),
+                        Quality::Good,
+                        round_trip,
+                    );
                 }
-            };
+            }
+        };
+    }
+
+    fn process_coalesced_response(
+        &mut self,
+        queued_query: QueuedQuery,
+        response: ModbusResponse,
+        round_trip: RoundTrip,
+    ) {
+        let starting_address = match &queued_query.query {
+            ModbusQuery::ReadQuery { params, .. } => params.starting_address,
+            _ => return,
+        };
+
+        match response {
+            ModbusResponse::ReadResponse {
+                message_data,
+                params,
+            } => {
+                for original in queued_query.coalesced {
+                    let Some(values) =
+                        values_for_original(starting_address, &params.values, &original)
+                    else {
+                        continue;
+                    };
 
-            self.on_going_queries.remove(&transaction_id);
+                    let response = ModbusResponse::ReadResponse {
+                        message_data: message_data.clone(),
+                        params: ReadResponseParameters {
+                            table: params.table,
+                            values,
+                        },
+                    };
+
+                    self.process_modbus_response(original, response, round_trip);
+                }
+            }
+            _ => {
+                //Part of the merged range may be invalid for the device, the original queries are
+                //sent on their own so each one gets the answer it would have gotten
+                for mut original in queued_query.coalesced {
+                    original.coalesce = false;
+                    self.queued_queries.push(original);
+                }
+            }
         }
     }
 
+    fn process_bit_response(
+        &mut self,
+        queued_query: QueuedQuery,
+        response: ModbusResponse,
+        round_trip: RoundTrip,
+    ) {
+        let Some(bit_operation) = queued_query.bit_operation else {
+            return;
+        };
+
+        let (slave_id, table, address) = match &queued_query.query {
+            ModbusQuery::ReadQuery {
+                message_data,
+                params,
+            } => (message_data.slave_id, params.table, params.starting_address),
+            ModbusQuery::SingleWriteQuery {
+                message_data,
+                params,
+            } => (message_data.slave_id, params.table, params.starting_address),
+            ModbusQuery::MaskWriteQuery {
+                message_data,
+                params,
+            } => (message_data.slave_id, params.table, params.address),
+            _ => return,
+        };
+
+        let (value, quality) = match (bit_operation, response) {
+            (BitOperation::Read { bit }, ModbusResponse::ReadResponse { params, .. }) => {
+                match params.values.first().and_then(|value| value.get_bit(bit)) {
+                    Some(value) => (Some(ModbusDataType::Coil(value)), Quality::Good),
+                    None => return,
+                }
+            }
+            (BitOperation::MaskWrite { .. }, ModbusResponse::MaskWriteResponse { .. })
+            | (BitOperation::WriteBack { .. }, ModbusResponse::SingleWriteResponse { .. }) => {
+                (None, Quality::Good)
+            }
+            (
+                BitOperation::MaskWrite { bit, value },
+                ModbusResponse::Error {
+                    exception_code: ExceptionCode::IllegalFunction,
+                    ..
+                },
+            ) => {
+                //The device doesn't know about mask writes, fall back to a read-modify-write
+                self.mask_write_unsupported.insert(slave_id);
+                let follow_up = Self::read_modify_write_query(slave_id, address, bit, value);
+                self.queued_queries.push(queued_query.derived(follow_up));
+                return;
+            }
+            (
+                BitOperation::ReadModifyWrite { bit, value },
+                ModbusResponse::ReadResponse { params, .. },
+            ) => {
+                let Some(ModbusDataType::Register(current)) = params.values.first() else {
+                    return;
+                };
+
+                let new_value = if value {
+                    current | (1 << bit)
+                } else {
+                    current & !(1 << bit)
+                };
+
+                let follow_up = Self::write_back_query(slave_id, address, bit, new_value);
+                self.queued_queries.push(queued_query.derived(follow_up));
+                return;
+            }
+            (_, ModbusResponse::Error { exception_code, .. }) => {
+                (None, Quality::Exception(exception_code))
+            }
+            _ => return,
+        };
+
+        self.record(
+            queued_query.id,
+            ModbusAddress::new_bit(slave_id, table, address, bit_operation.bit()),
+            value,
+            quality,
+            round_trip,
+        );
+    }
+
+    //Reads the range of a write back, expecting the values that were written
+    fn read_back_query(query: &ModbusQuery) -> Option<QueuedQuery> {
+        let (message_data, table, starting_address, values) = match query {
+            ModbusQuery::SingleWriteQuery {
+                message_data,
+                params,
+            } => (
+                message_data,
+                params.table,
+                params.starting_address,
+                vec![params.value],
+            ),
+            ModbusQuery::MultipleWriteQuery {
+                message_data,
+                params,
+            } => (
+                message_data,
+                params.table,
+                params.starting_address,
+                params.values.clone(),
+            ),
+            _ => return None,
+        };
+
+        let query = ModbusQuery::ReadQuery {
+            message_data: ModbusMessageData {
+                slave_id: message_data.slave_id,
+                function_code: table.get_read_function_code(),
+                transaction_id: Cell::new(None),
+            },
+            params: ReadQueryParameters {
+                table,
+                starting_address,
+                ammount: values.len() as u16,
+            },
+        };
+
+        Some(QueuedQuery {
+            coalesce: false,
+            expected_values: Some(values),
+            ..QueuedQuery::new(query)
+        })
+    }
+
+    fn process_read_back_response(
+        &mut self,
+        queued_query: QueuedQuery,
+        response: ModbusResponse,
+        round_trip: RoundTrip,
+    ) {
+        let (
+            Some(expected_values),
+            ModbusQuery::ReadQuery {
+                message_data,
+                params,
+            },
+        ) = (queued_query.expected_values, &queued_query.query)
+        else {
+            return;
+        };
+
+        let addresses = (params.starting_address..)
+            .map(|address| ModbusAddress::new(message_data.slave_id, params.table, address))
+            .zip(expected_values);
+
+        match response {
+            ModbusResponse::ReadResponse { params, .. } => {
+                for ((address, expected), actual) in addresses.zip(params.values) {
+                    let quality = if expected == actual {
+                        Quality::Good
+                    } else {
+                        Quality::VerifyMismatch { expected, actual }
+                    };
+                    self.record(queued_query.id, address, None, quality, round_trip);
+                }
+            }
+            ModbusResponse::Error { exception_code, .. } => {
+                for (address, _) in addresses {
+                    self.record_read_back_failure(
+                        queued_query.id,
+                        address,
+                        Quality::Exception(exception_code),
+                        round_trip,
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn read_modify_write_query(
+        slave_id: SlaveId,
+        address: Address,
+        bit: u8,
+        value: bool,
+    ) -> QueuedQuery {
+        let query = ModbusQuery::ReadQuery {
+            message_data: ModbusMessageData {
+                slave_id,
+                function_code: FunctionCode::ReadMultipleHoldingRegister,
+                transaction_id: Cell::new(None),
+            },
+            params: ReadQueryParameters {
+                table: ModbusTable::HoldingRegisters,
+                starting_address: address,
+                ammount: 1,
+            },
+        };
+
+        QueuedQuery::new_bit_operation(query, BitOperation::ReadModifyWrite { bit, value })
+    }
+
+    fn write_back_query(slave_id: SlaveId, address: Address, bit: u8, value: u16) -> QueuedQuery {
+        let query = ModbusQuery::SingleWriteQuery {
+            message_data: ModbusMessageData {
+                slave_id,
+                function_code: FunctionCode::WriteSingleHoldingRegister,
+                transaction_id: Cell::new(None),
+            },
+            params: SingleWriteQueryParameters {
+                table: ModbusTable::HoldingRegisters,
+                starting_address: address,
+                value: ModbusDataType::Register(value),
+            },
+        };
+
+        QueuedQuery::new_bit_operation(query, BitOperation::WriteBack { bit })
+    }
+
     pub fn has_on_going_queries(&self) -> bool {
-        return !self.on_going_queries.is_empty();
+        !self.on_going_queries.is_empty()
+    }
+
+    //Broadcasts on a serial line are never answered, so they are done once sent. Writes complete as
+    //if every unit had echoed them back, reads can't be broadcast
+    pub fn complete_broadcasts(&mut self) {
+        let broadcasts: Vec<u16> = self
+            .on_going_queries
+            .iter()
+            .filter(|(_, transaction)| transaction.query.query.get_message_data().slave_id == 0)
+            .map(|(transaction_id, _)| *transaction_id)
+            .collect();
+
+        for transaction_id in broadcasts {
+            let Some(transaction) = self.on_going_queries.remove(&transaction_id) else {
+                continue;
+            };
+
+            match Self::broadcast_echo(&transaction.query.query) {
+                Some(response) => {
+                    let round_trip = RoundTrip::unanswered(transaction.query.requested_at);
+                    self.process_modbus_response(transaction.query, response, round_trip)
+                }
+                None => self.fail_query(
+                    &transaction.query,
+                    &ModbusError::InvalidQuery("Reads can't be broadcast".to_string()),
+                ),
+            }
+        }
+    }
+
+    fn broadcast_echo(query: &ModbusQuery) -> Option<ModbusResponse> {
+        match query {
+            ModbusQuery::SingleWriteQuery {
+                message_data,
+                params,
+            } => Some(ModbusResponse::SingleWriteResponse {
+                message_data: message_data.clone(),
+                params: SingleWriteResponseParameters {
+                    table: params.table,
+                    address: params.starting_address,
+                    value: params.value,
+                },
+            }),
+            ModbusQuery::MultipleWriteQuery {
+                message_data,
+                params,
+            } => Some(ModbusResponse::MultipleWriteResponse {
+                message_data: message_data.clone(),
+                params: MultipleWriteResponse {
+                    table: params.table,
+                    address: params.starting_address,
+                    ammount: params.values.len() as u16,
+                },
+            }),
+            ModbusQuery::MaskWriteQuery {
+                message_data,
+                params,
+            } => Some(ModbusResponse::MaskWriteResponse {
+                message_data: message_data.clone(),
+                params: MaskWriteResponseParameters {
+                    table: params.table,
+                    address: params.address,
+                    and_mask: params.and_mask,
+                    or_mask: params.or_mask,
+                },
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::master::retry::Backoff;
+    use crate::master::test_utils::connect_recording;
+    use crate::master::QueryOptions;
+    use crate::messages::query::MaskWriteQueryParameters;
+    use crate::messages::response::SingleWriteResponseParameters;
+
+    fn message_data(function_code: FunctionCode, transaction_id: u16) -> ModbusMessageData {
+        ModbusMessageData {
+            slave_id: 1,
+            function_code,
+            transaction_id: Cell::new(Some(transaction_id)),
+        }
+    }
+
+    fn read_response(transaction_id: u16, value: u16) -> ModbusResponse {
+        ModbusResponse::ReadResponse {
+            message_data: message_data(FunctionCode::ReadMultipleHoldingRegister, transaction_id),
+            params: ReadResponseParameters {
+                table: ModbusTable::HoldingRegisters,
+                values: vec![ModbusDataType::Register(value)],
+            },
+        }
+    }
+
+    fn start(context: &mut ModbusMasterContext, now: Instant) -> Option<u16> {
+        context.start_next_transaction(
+            ModbusSubprotocol::ModbusTCP,
+            now,
+            Duration::from_secs(1),
+        )?;
+        context
+            .on_going_queries
+            .iter()
+            .find(|(_, transaction)| transaction.sent_at == now)
+            .map(|(transaction_id, _)| *transaction_id)
+    }
+
+    fn load_single(context: &mut ModbusMasterContext) -> u16 {
+        let transaction_id = start(context, Instant::now()).unwrap();
+        assert_eq!(context.in_flight_transactions(), 1);
+        transaction_id
+    }
+
+    fn enqueue_read(context: &mut ModbusMasterContext, starting_address: u16) -> QueryId {
+        context.enqueue(QueuedQuery::new(ModbusQuery::ReadQuery {
+            message_data: message_data(FunctionCode::ReadMultipleHoldingRegister, 0),
+            params: ReadQueryParameters {
+                table: ModbusTable::HoldingRegisters,
+                starting_address,
+                ammount: 1,
+            },
+        }))
+    }
+
+    #[test]
+    fn test_bit_read_extracts_bit() {
+        let mut context = ModbusMasterContext::new();
+        let query = ModbusQuery::ReadQuery {
+            message_data: message_data(FunctionCode::ReadMultipleHoldingRegister, 0),
+            params: ReadQueryParameters {
+                table: ModbusTable::HoldingRegisters,
+                starting_address: 10,
+                ammount: 1,
+            },
+        };
+        context.enqueue(QueuedQuery::new_bit_operation(
+            query,
+            BitOperation::Read { bit: 3 },
+        ));
+
+        let transaction_id = load_single(&mut context);
+        context
+            .process_modbus_responses(vec![read_response(transaction_id, 0b1000)], Instant::now());
+        let results = context.take_results();
+
+        assert_eq!(
+            results
+                .get(&ModbusAddress::new_bit(
+                    1,
+                    ModbusTable::HoldingRegisters,
+                    10,
+                    3
+                ))
+                .map(|result| (result.value, result.quality)),
+            Some((Some(ModbusDataType::Coil(true)), Quality::Good))
+        );
+        assert_eq!(results.len(), 1);
+    }
+
+    #[test]
+    fn test_mask_write_falls_back_to_read_modify_write() {
+        let mut context = ModbusMasterContext::new();
+        let query = ModbusQuery::MaskWriteQuery {
+            message_data: message_data(FunctionCode::MaskWriteRegister, 0),
+            params: MaskWriteQueryParameters {
+                table: ModbusTable::HoldingRegisters,
+                address: 7,
+                and_mask: !(1 << 2),
+                or_mask: 0,
+            },
+        };
+        context.enqueue(QueuedQuery::new_bit_operation(
+            query,
+            BitOperation::MaskWrite {
+                bit: 2,
+                value: false,
+            },
+        ));
+
+        let transaction_id = load_single(&mut context);
+        let refused = ModbusResponse::Error {
+            message_data: message_data(FunctionCode::MaskWriteRegister, transaction_id),
+            exception_code: ExceptionCode::IllegalFunction,
+        };
+        context.process_modbus_responses(vec![refused], Instant::now());
+        assert!(!context.supports_mask_write(1));
+        assert!(context.take_results().is_empty());
+
+        let transaction_id = load_single(&mut context);
+        context
+            .process_modbus_responses(vec![read_response(transaction_id, 0xFFFF)], Instant::now());
+        assert!(context.take_results().is_empty());
+
+        let transaction_id = load_single(&mut context);
+        match &context.on_going_queries[&transaction_id].query.query {
+            ModbusQuery::SingleWriteQuery { params, .. } => {
+                assert_eq!(params.value, ModbusDataType::Register(0xFFFB))
+            }
+            query => panic!("Expected a write back, got {:?}", query),
+        }
+
+        let confirmation = ModbusResponse::SingleWriteResponse {
+            message_data: message_data(FunctionCode::WriteSingleHoldingRegister, transaction_id),
+            params: SingleWriteResponseParameters {
+                table: ModbusTable::HoldingRegisters,
+                address: 7,
+                value: ModbusDataType::Register(0xFFFB),
+            },
+        };
+        context.process_modbus_responses(vec![confirmation], Instant::now());
+        let results = context.take_results();
+
+        assert_eq!(
+            results
+                .get(&ModbusAddress::new_bit(
+                    1,
+                    ModbusTable::HoldingRegisters,
+                    7,
+                    2
+                ))
+                .map(|result| result.quality),
+            Some(Quality::Good)
+        );
+    }
+
+    #[test]
+    fn test_verified_write_reports_clamped_value() {
+        let mut context = ModbusMasterContext::new();
+        let mut write = QueuedQuery::new(ModbusQuery::SingleWriteQuery {
+            message_data: message_data(FunctionCode::WriteSingleHoldingRegister, 0),
+            params: SingleWriteQueryParameters {
+                table: ModbusTable::HoldingRegisters,
+                starting_address: 1,
+                value: ModbusDataType::Register(500),
+            },
+        });
+        write.verify = true;
+        let id = context.enqueue(write);
+
+        let transaction_id = load_single(&mut context);
+        let confirmation = ModbusResponse::SingleWriteResponse {
+            message_data: message_data(FunctionCode::WriteSingleHoldingRegister, transaction_id),
+            params: SingleWriteResponseParameters {
+                table: ModbusTable::HoldingRegisters,
+                address: 1,
+                value: ModbusDataType::Register(500),
+            },
+        };
+        context.process_modbus_responses(vec![confirmation], Instant::now());
+        assert!(context.is_pending(id));
+
+        //The device acked the write but kept the value within its own limits
+        let transaction_id = load_single(&mut context);
+        context.process_modbus_responses(vec![read_response(transaction_id, 100)], Instant::now());
+
+        let results = context.take_outcome(id).unwrap().unwrap();
+        assert_eq!(
+            results[&ModbusAddress::new(1, ModbusTable::HoldingRegisters, 1)].quality,
+            Quality::VerifyMismatch {
+                expected: ModbusDataType::Register(500),
+                actual: ModbusDataType::Register(100),
+            }
+        );
+    }
+
+    #[test]
+    fn test_failed_read_back_keeps_the_write() {
+        let mut context = ModbusMasterContext::new();
+        let mut write = QueuedQuery::new(ModbusQuery::SingleWriteQuery {
+            message_data: message_data(FunctionCode::WriteSingleHoldingRegister, 0),
+            params: SingleWriteQueryParameters {
+                table: ModbusTable::HoldingRegisters,
+                starting_address: 1,
+                value: ModbusDataType::Register(500),
+            },
+        });
+        write.verify = true;
+        let id = context.enqueue(write);
+
+        let transaction_id = load_single(&mut context);
+        let confirmation = ModbusResponse::SingleWriteResponse {
+            message_data: message_data(FunctionCode::WriteSingleHoldingRegister, transaction_id),
+            params: SingleWriteResponseParameters {
+                table: ModbusTable::HoldingRegisters,
+                address: 1,
+                value: ModbusDataType::Register(500),
+            },
+        };
+        context.process_modbus_responses(vec![confirmation], Instant::now());
+
+        //The register can be written but not read
+        let transaction_id = load_single(&mut context);
+        let refused = ModbusResponse::Error {
+            message_data: message_data(FunctionCode::ReadMultipleHoldingRegister, transaction_id),
+            exception_code: ExceptionCode::IllegalFunction,
+        };
+        context.process_modbus_responses(vec![refused], Instant::now());
+
+        let results = context.take_outcome(id).unwrap().unwrap();
+        let result = &results[&ModbusAddress::new(1, ModbusTable::HoldingRegisters, 1)];
+        assert_eq!(result.quality, Quality::Good);
+        assert_eq!(
+            result.read_back_failure,
+            Some(Quality::Exception(ExceptionCode::IllegalFunction))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_verified_writes_are_read_back() {
+        let (mut master, socket) = connect_recording();
+        let verified = QueryOptions {
+            verify_writes: true,
+            ..Default::default()
+        };
+
+        master
+            .with_options(verified)
+            .write_multiple_registers(1, 2, vec![7, 8])
+            .await
+            .unwrap();
+        master
+            .with_options(verified)
+            .write_single_coil(1, 0, true)
+            .await
+            .unwrap();
+        assert_eq!(socket.requests().len(), 4);
+
+        //Only the calls that asked for it are verified
+        master.write_single_register(1, 4, 9).await.unwrap();
+        assert_eq!(socket.requests().len(), 5);
+        assert_eq!(master.read_holding_registers(1, 2, 2).await, Ok(vec![7, 8]));
+    }
+
+    #[tokio::test]
+    async fn test_verified_query_is_read_back_alone() {
+        let (mut master, socket) = connect_recording();
+
+        master
+            .add_write_holding_register_query_verified(1, 2, 7)
+            .unwrap();
+        master.add_write_holding_register_query(1, 3, 8).unwrap();
+        master.query().await.unwrap();
+        assert_eq!(socket.requests().len(), 3);
+
+        master.add_write_holding_register_query(1, 4, 9).unwrap();
+        master.query().await.unwrap();
+        assert_eq!(socket.requests().len(), 4);
+    }
+
+    #[test]
+    fn test_read_modify_write_guards_register() {
+        let mut context = ModbusMasterContext::new();
+        context.enqueue(ModbusMasterContext::read_modify_write_query(1, 4, 0, true));
+        context.enqueue(ModbusMasterContext::read_modify_write_query(1, 4, 1, true));
+
+        let now = Instant::now();
+        assert!(start(&mut context, now).is_some());
+        assert!(start(&mut context, now).is_none());
+
+        assert_eq!(context.in_flight_transactions(), 1);
+        assert_eq!(context.queued_queries.len(), 1);
+    }
+
+    #[test]
+    fn test_responses_are_matched_out_of_order() {
+        let mut context = ModbusMasterContext::new();
+        let first = enqueue_read(&mut context, 1);
+        let second = enqueue_read(&mut context, 2);
+
+        let now = Instant::now();
+        let first_transaction = start(&mut context, now).unwrap();
+        let second_transaction = start(&mut context, now + Duration::from_millis(1)).unwrap();
+
+        context.process_modbus_responses(
+            vec![
+                read_response(second_transaction, 20),
+                read_response(first_transaction, 10),
+            ],
+            Instant::now(),
+        );
+
+        let first_results = context.take_outcome(first).unwrap().unwrap();
+        let second_results = context.take_outcome(second).unwrap().unwrap();
+        assert_eq!(
+            first_results[&ModbusAddress::new(1, ModbusTable::HoldingRegisters, 1)].value,
+            Some(ModbusDataType::Register(10))
+        );
+        assert_eq!(
+            second_results[&ModbusAddress::new(1, ModbusTable::HoldingRegisters, 2)].value,
+            Some(ModbusDataType::Register(20))
+        );
+    }
+
+    #[test]
+    fn test_transactions_expire_on_their_own() {
+        let mut context = ModbusMasterContext::new();
+        let late = enqueue_read(&mut context, 1);
+        let on_time = enqueue_read(&mut context, 2);
+
+        let now = Instant::now();
+        let late_transaction = start(&mut context, now).unwrap();
+        let on_time_transaction = start(&mut context, now + Duration::from_millis(500)).unwrap();
+
+        context.expire_transactions(now + Duration::from_secs(1));
+        assert_eq!(context.in_flight_transactions(), 1);
+        assert_eq!(context.take_outcome(late), Some(Err(ModbusError::Timeout)));
+
+        //The late response is discarded, the other transaction is still answered
+        context.process_modbus_responses(
+            vec![
+                read_response(late_transaction, 10),
+                read_response(on_time_transaction, 20),
+            ],
+            Instant::now(),
+        );
+        assert_eq!(context.take_outcome(late), None);
+        assert!(context.take_outcome(on_time).unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_results_carry_timing_and_quality() {
+        let mut context = ModbusMasterContext::new();
+        let late = enqueue_read(&mut context, 2);
+        let on_time = enqueue_read(&mut context, 1);
+
+        let now = Instant::now();
+        let late_transaction = start(&mut context, now).unwrap();
+        let on_time_transaction = start(&mut context, now + Duration::from_millis(800)).unwrap();
+
+        //Past the deadline of the first transaction, but before it was expired
+        context.process_modbus_responses(
+            vec![
+                read_response(on_time_transaction, 10),
+                read_response(late_transaction, 20),
+            ],
+            now + Duration::from_millis(1200),
+        );
+
+        let results = context.take_outcome(on_time).unwrap().unwrap();
+        let result = &results[&ModbusAddress::new(1, ModbusTable::HoldingRegisters, 1)];
+        assert_eq!(result.quality, Quality::Good);
+        assert_eq!(result.latency, Some(Duration::from_millis(400)));
+        assert!(result.requested_at.is_some() && result.responded_at.is_some());
+
+        let results = context.take_outcome(late).unwrap().unwrap();
+        let result = &results[&ModbusAddress::new(1, ModbusTable::HoldingRegisters, 2)];
+        assert_eq!(result.quality, Quality::Stale);
+        assert_eq!(result.value, Some(ModbusDataType::Register(20)));
+    }
+
+    #[test]
+    fn test_failed_queries_keep_their_addresses() {
+        let mut context = ModbusMasterContext::new();
+        enqueue_read(&mut context, 1);
+
+        let now = Instant::now();
+        start(&mut context, now).unwrap();
+        context.expire_transactions(now + Duration::from_secs(1));
+
+        let never_sent = enqueue_read(&mut context, 3);
+        context.fail_all_queries(ModbusError::Connection("reset".to_string()));
+        assert!(!context.is_pending(never_sent));
+
+        let results = context.take_results();
+        let result = &results[&ModbusAddress::new(1, ModbusTable::HoldingRegisters, 1)];
+        assert_eq!((result.value, result.quality), (None, Quality::Timeout));
+        assert!(result.requested_at.is_some() && result.responded_at.is_none());
+
+        let result = &results[&ModbusAddress::new(1, ModbusTable::HoldingRegisters, 3)];
+        assert_eq!(result.quality, Quality::CommFailure);
+        assert_eq!(result.requested_at, None);
+    }
+
+    #[test]
+    fn test_mismatched_response_is_a_protocol_error() {
+        let mut context = ModbusMasterContext::new();
+        let id = enqueue_read(&mut context, 1);
+        let transaction_id = load_single(&mut context);
+
+        let mut response = read_response(transaction_id, 10);
+        if let ModbusResponse::ReadResponse { params, .. } = &mut response {
+            params.values.push(ModbusDataType::Register(20));
+        }
+        context.process_modbus_responses(vec![response], Instant::now());
+
+        let results = &context.answered_queries[&id];
+        let result = &results[&ModbusAddress::new(1, ModbusTable::HoldingRegisters, 1)];
+        assert_eq!(
+            (result.value, result.quality),
+            (None, Quality::ProtocolError)
+        );
+        assert_eq!(results.len(), 1);
+        assert!(matches!(
+            context.take_outcome(id),
+            Some(Err(ModbusError::Protocol(_)))
+        ));
+    }
+
+    #[test]
+    fn test_connection_loss_requeues_once() {
+        let mut context = ModbusMasterContext::new();
+        let id = enqueue_read(&mut context, 1);
+        let error = ModbusError::Connection("reset".to_string());
+        context.set_retry_policy(RetryPolicy::new(2, Backoff::Fixed(Duration::ZERO)));
+
+        load_single(&mut context);
+        context.requeue_on_going_queries(error.clone(), Instant::now());
+        assert!(context.is_pending(id));
+        assert_eq!(context.queued_queries.len(), 1);
+
+        load_single(&mut context);
+        context.requeue_on_going_queries(error.clone(), Instant::now());
+        assert!(!context.is_pending(id));
+        assert_eq!(context.take_outcome(id), Some(Err(error)));
+    }
+
+    #[test]
+    fn test_busy_device_is_retried_after_backoff() {
+        let mut context = ModbusMasterContext::new();
+        context.set_retry_policy(RetryPolicy::new(
+            3,
+            Backoff::Fixed(Duration::from_millis(100)),
+        ));
+        let id = enqueue_read(&mut context, 1);
+
+        let now = Instant::now();
+        let transaction_id = start(&mut context, now).unwrap();
+        let busy = ModbusResponse::Error {
+            message_data: message_data(FunctionCode::ReadMultipleHoldingRegister, transaction_id),
+            exception_code: ExceptionCode::ServerDeviceBusy,
+        };
+        context.process_modbus_responses(vec![busy], now);
+        assert!(context.is_pending(id));
+
+        let retry_at = now + Duration::from_millis(100);
+        assert_eq!(context.next_retry(now), Some(retry_at));
+        assert!(start(&mut context, now + Duration::from_millis(50)).is_none());
+
+        let transaction_id = start(&mut context, retry_at).unwrap();
+        context.process_modbus_responses(vec![read_response(transaction_id, 7)], retry_at);
+        assert!(context.take_outcome(id).unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_query_retry_policy_overrides_device() {
+        let mut context = ModbusMasterContext::new();
+        context
+            .set_device_retry_policy(1, Some(RetryPolicy::new(3, Backoff::Fixed(Duration::ZERO))));
+        let retried = enqueue_read(&mut context, 1);
+        let mut write = QueuedQuery::new(ModbusQuery::SingleWriteQuery {
+            message_data: message_data(FunctionCode::WriteSingleHoldingRegister, 0),
+            params: SingleWriteQueryParameters {
+                table: ModbusTable::HoldingRegisters,
+                starting_address: 2,
+                value: ModbusDataType::Register(1),
+            },
+        });
+        write.retry_policy = Some(RetryPolicy::none());
+        let not_retried = context.enqueue(write);
+
+        let now = Instant::now();
+        start(&mut context, now).unwrap();
+        start(&mut context, now + Duration::from_millis(1)).unwrap();
+        context.expire_transactions(now + Duration::from_secs(2));
+
+        assert!(context.is_pending(retried));
+        assert_eq!(
+            context.take_outcome(not_retried),
+            Some(Err(ModbusError::Timeout))
+        );
+    }
+
+    #[test]
+    fn test_coalesced_read_fans_out_results() {
+        let mut context = ModbusMasterContext::new();
+        for (starting_address, ammount) in [(0, 2), (3, 1)] {
+            context.enqueue(QueuedQuery::new(ModbusQuery::ReadQuery {
+                message_data: message_data(FunctionCode::ReadMultipleHoldingRegister, 0),
+                params: ReadQueryParameters {
+                    table: ModbusTable::HoldingRegisters,
+                    starting_address,
+                    ammount,
+                },
+            }));
+        }
+        context.optimize_queued_queries(Some(1));
+        assert_eq!(context.queued_queries.len(), 1);
+
+        let transaction_id = load_single(&mut context);
+        let response = ModbusResponse::ReadResponse {
+            message_data: message_data(FunctionCode::ReadMultipleHoldingRegister, transaction_id),
+            params: ReadResponseParameters {
+                table: ModbusTable::HoldingRegisters,
+                values: (10..14).map(ModbusDataType::Register).collect(),
+            },
+        };
+        context.process_modbus_responses(vec![response], Instant::now());
+        let results: HashMap<ModbusAddress, Option<ModbusDataType>> = context
+            .take_results()
+            .into_iter()
+            .map(|(address, result)| (address, result.value))
+            .collect();
+
+        let expected: HashMap<ModbusAddress, Option<ModbusDataType>> = [(0, 10), (1, 11), (3, 13)]
+            .into_iter()
+            .map(|(address, value)| {
+                (
+                    ModbusAddress::new(1, ModbusTable::HoldingRegisters, address),
+                    Some(ModbusDataType::Register(value)),
+                )
+            })
+            .collect();
+        assert_eq!(results, expected);
+    }
+
+    #[test]
+    fn test_coalesced_read_error_resends_originals() {
+        let mut context = ModbusMasterContext::new();
+        for starting_address in [0, 1] {
+            context.enqueue(QueuedQuery::new(ModbusQuery::ReadQuery {
+                message_data: message_data(FunctionCode::ReadMultipleHoldingRegister, 0),
+                params: ReadQueryParameters {
+                    table: ModbusTable::HoldingRegisters,
+                    starting_address,
+                    ammount: 1,
+                },
+            }));
+        }
+        context.optimize_queued_queries(Some(0));
+
+        let transaction_id = load_single(&mut context);
+        let refused = ModbusResponse::Error {
+            message_data: message_data(FunctionCode::ReadMultipleHoldingRegister, transaction_id),
+            exception_code: ExceptionCode::IllegalDataAddress,
+        };
+        context.process_modbus_responses(vec![refused], Instant::now());
+
+        assert!(context.take_results().is_empty());
+        assert_eq!(context.queued_queries.len(), 2);
+        context.optimize_queued_queries(Some(0));
+        assert_eq!(context.queued_queries.len(), 2);
     }
 }
@@ -0,0 +1,238 @@
+use std::future::Future;
+
+use tokio::sync::{mpsc, oneshot};
+
+use crate::common::{Address, ModbusError, SlaveId};
+use crate::master::context::QueryId;
+use crate::master::requests::{self, invalid_query, request_methods, Enqueue, ModbusResultMap};
+use crate::master::{ModbusMasterConnection, QueryOptions};
+
+type Outcome = Result<ModbusResultMap, ModbusError>;
+
+struct Request {
+    enqueue: Enqueue,
+    reply: oneshot::Sender<Outcome>,
+}
+
+//Cheap to clone handle to a master running on its own task. Requests from every clone are
+//pipelined over the same connection, the task stops once the last handle is dropped
+#[derive(Clone)]
+pub struct ModbusMasterHandle {
+    requests: mpsc::UnboundedSender<Request>,
+    //Used instead of the query options of the master for the requests of this handle
+    query_options: Option<QueryOptions>,
+}
+
+fn stopped() -> ModbusError {
+    ModbusError::Connection("Master task has stopped".to_string())
+}
+
+impl ModbusMasterHandle {
+    pub fn spawn(master: ModbusMasterConnection) -> Self {
+        let (handle, task) = Self::new(master);
+        tokio::spawn(task);
+        handle
+    }
+
+    fn new(master: ModbusMasterConnection) -> (Self, impl Future<Output = ()> + Send) {
+        let (requests, receiver) = mpsc::unbounded_channel();
+        let handle = ModbusMasterHandle {
+            requests,
+            query_options: None,
+        };
+        (handle, run(master, receiver))
+    }
+
+    //Handle to the same master whose requests use the given options, the other handles keep theirs
+    pub fn with_options(&self, query_options: QueryOptions) -> Self {
+        ModbusMasterHandle {
+            requests: self.requests.clone(),
+            query_options: Some(query_options),
+        }
+    }
+
+    async fn execute(&self, enqueue: Enqueue) -> Outcome {
+        let enqueue: Enqueue = match self.query_options {
+            Some(query_options) => Box::new(move |master: &mut ModbusMasterConnection| {
+                enqueue(&mut master.with_options(query_options))
+            }),
+            None => enqueue,
+        };
+        let (reply, outcome) = oneshot::channel();
+        self.requests
+            .send(Request { enqueue, reply })
+            .map_err(|_| stopped())?;
+        outcome.await.map_err(|_| stopped())?
+    }
+
+    async fn request<T: 'static>(&self, request: requests::Request<T>) -> Result<T, ModbusError> {
+        let results = self.execute(request.enqueue).await?;
+        (request.answer)(&results)
+    }
+}
+
+//Requests are queued on the task of the master and pipelined with those of the other handles
+macro_rules! handle_requests {
+    ($(fn $name:ident($($arg:ident: $ty:ty),*) -> $ret:ty;)*) => {
+        impl ModbusMasterHandle {
+            $(
+                pub async fn $name(&self, $($arg: $ty),*) -> Result<$ret, ModbusError> {
+                    self.request(requests::$name($($arg),*)).await
+                }
+            )*
+        }
+    };
+}
+
+request_methods!(handle_requests);
+
+//Owns the connection, queues requests as they come and answers each caller once its query is done
+async fn run(mut master: ModbusMasterConnection, mut requests: mpsc::UnboundedReceiver<Request>) {
+    let mut waiting: Vec<(QueryId, oneshot::Sender<Outcome>)> = vec![];
+
+    loop {
+        if waiting.is_empty() {
+            //Nothing to do until a request comes, every handle being dropped ends the task
+            let Some(request) = requests.recv().await else {
+                return;
+            };
+            accept(&mut master, request, &mut waiting);
+        }
+
+        while let Ok(request) = requests.try_recv() {
+            accept(&mut master, request, &mut waiting);
+        }
+
+        let params = master.params;
+        master.prepare_queued_queries(params);
+        //Connection failures are recorded for every query, so callers get them as outcomes.
+        //A request coming while a response is awaited is queued right away and sent next step
+        let mut incoming = None;
+        let _ = master
+            .process_step_until(params, async {
+                incoming = Some(requests.recv().await);
+            })
+            .await;
+        match incoming {
+            Some(Some(request)) => accept(&mut master, request, &mut waiting),
+            //Callers hold a handle while they wait, so none are left
+            Some(None) => return,
+            None => {}
+        }
+
+        let (done, pending) = std::mem::take(&mut waiting)
+            .into_iter()
+            .partition(|(id, _)| !master.context.is_pending(*id));
+        waiting = pending;
+
+        for (id, reply) in done {
+            let outcome = master.take_outcome(id).unwrap_or(Err(ModbusError::Timeout));
+            //The caller may have given up already
+            let _ = reply.send(outcome);
+        }
+    }
+}
+
+fn accept(
+    master: &mut ModbusMasterConnection,
+    request: Request,
+    waiting: &mut Vec<(QueryId, oneshot::Sender<Outcome>)>,
+) {
+    match (request.enqueue)(master) {
+        Ok(id) => waiting.push((id, request.reply)),
+        Err(err) => {
+            let _ = request.reply.send(Err(invalid_query(err)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::master::test_utils::spawn_slave;
+    use crate::master::ModbusMasterConnectionParams;
+    use tokio::time::Duration;
+
+    fn is_send_and_sync<T: Send + Sync + Clone>() {}
+
+    #[tokio::test]
+    async fn test_concurrent_callers_share_the_connection() {
+        is_send_and_sync::<ModbusMasterHandle>();
+
+        let mut master = ModbusMasterConnection::new_tcp(spawn_slave().await);
+        master.set_params(ModbusMasterConnectionParams {
+            max_simultaneous_transactions: 4,
+            ..Default::default()
+        });
+        let handle = ModbusMasterHandle::spawn(master);
+
+        let callers: Vec<_> = (0..10)
+            .map(|address| {
+                let handle = handle.clone();
+                tokio::spawn(async move { handle.read_holding_registers(1, address, 1).await })
+            })
+            .collect();
+
+        for (address, caller) in (0..10).zip(callers) {
+            assert_eq!(caller.await.unwrap(), Ok(vec![address * 10]));
+        }
+
+        handle.write_single_register(1, 3, 7).await.unwrap();
+        assert_eq!(handle.read_holding_registers(1, 3, 1).await, Ok(vec![7]));
+    }
+
+    #[tokio::test]
+    async fn test_task_stops_when_last_handle_is_dropped() {
+        let master = ModbusMasterConnection::new_tcp(spawn_slave().await);
+        let (handle, task) = ModbusMasterHandle::new(master);
+        let task = tokio::spawn(task);
+
+        let other = handle.clone();
+        assert_eq!(other.read_holding_registers(1, 1, 1).await, Ok(vec![10]));
+        drop(handle);
+        drop(other);
+
+        tokio::time::timeout(Duration::from_secs(1), task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_requests_go_out_while_a_response_is_awaited() {
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut master = ModbusMasterConnection::new_udp(server.local_addr().unwrap());
+        master.set_params(ModbusMasterConnectionParams {
+            max_simultaneous_transactions: 2,
+            ..master.get_params()
+        });
+        let max_response_time = master.get_params().max_response_time;
+        let handle = ModbusMasterHandle::spawn(master);
+
+        let first = handle.clone();
+        let first = tokio::spawn(async move { first.read_holding_registers(1, 0, 1).await });
+
+        let mut buffer = [0u8; 64];
+        let (_, source) = server.recv_from(&mut buffer).await.unwrap();
+        let first_request = buffer;
+        let started = tokio::time::Instant::now();
+
+        let second = handle.clone();
+        let second = tokio::spawn(async move { second.read_holding_registers(1, 1, 1).await });
+
+        //The second request is sent before the first one is answered or given up on
+        server.recv_from(&mut buffer).await.unwrap();
+        assert!(started.elapsed() < max_response_time);
+        assert_eq!(buffer[8..10], [0, 1]);
+
+        for (request, value) in [(buffer, 11), (first_request, 10)] {
+            let response = [
+                request[0], request[1], 0, 0, 0, 5, 1, 0x03, 0x02, 0x00, value,
+            ];
+            server.send_to(&response, source).await.unwrap();
+        }
+
+        assert_eq!(second.await.unwrap(), Ok(vec![11]));
+        assert_eq!(first.await.unwrap(), Ok(vec![10]));
+    }
+}
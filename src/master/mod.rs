@@ -1,107 +1,486 @@
 use crate::codec::ModbusSerialize;
-use crate::common::{ModbusAddress, ModbusDataType, ModbusResult, ModbusSubprotocol, ModbusTable};
+use crate::common::{
+    ModbusAddress, ModbusDataType, ModbusError, ModbusResult, ModbusSubprotocol, ModbusTable,
+    Quality,
+};
+use crate::communication::{stream_connector, AsyncStream, SerialSettings};
+use crate::slave::ModbusSlaveConnection;
 use crate::master::comm::ModbusMasterCommunicationInfo;
 use crate::messages::{FunctionCode, ModbusMessageData, ModbusQuery, ModbusResponse};
-use context::ModbusMasterContext;
+use context::{BitOperation, ModbusMasterContext, QueryId, QueuedQuery};
+use subscription::Subscriber;
 
 use anyhow::{anyhow, Result};
-use std::{cell::Cell, collections::HashMap, net::SocketAddr};
-use tokio::time::{sleep, Duration};
+use std::{cell::Cell, collections::HashMap, future::Future, net::SocketAddr};
+use std::ops::{Deref, DerefMut};
+use std::time::SystemTime;
+use tokio::time::{sleep_until, Duration, Instant};
 
 mod comm;
 mod context;
+mod handle;
+mod optimizer;
+mod pacing;
+mod poller;
+mod pool;
+mod queue;
+mod requests;
+mod retry;
+mod scanner;
+mod subscription;
+mod validation;
+#[cfg(test)]
+pub(crate) mod test_utils;
+
+pub use comm::{ConnectionState, ReconnectPolicy};
+pub(crate) use optimizer::{
+    MAX_READ_COILS, MAX_READ_REGISTERS, MAX_WRITE_COILS, MAX_WRITE_REGISTERS,
+};
+pub use handle::ModbusMasterHandle;
+pub use pacing::Pacing;
+pub use pool::{ModbusDeviceHandle, ModbusMasterPool};
+pub use queue::Priority;
+pub use poller::{CycleOverrun, PollGroup, PollSnapshot, PolledValue, Poller};
+pub use retry::{Backoff, RetryOn, RetryPolicy};
+pub use scanner::{DeviceInventory, ScanOptions, ScannedUnit, Scanner};
+pub use subscription::{ChangeEvent, Deadband, Subscription, SubscriptionEvent};
 
 const MAX_MODBUS_RESPONSE_TIME: Duration = tokio::time::Duration::from_millis(5000);
+//A datagram is either answered quickly or lost, waiting long before sending it again gains nothing
+const UDP_RESPONSE_TIME: Duration = Duration::from_millis(1000);
+const UDP_ATTEMPTS: u32 = 3;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct ModbusMasterConnectionParams {
     pub max_response_time: Duration,
     pub max_simultaneous_transactions: u32,
+    //Reads on the same unit and table are merged when at most this many addresses lie between them,
+    //None disables merging. Oversized requests are always split at the protocol limits
+    pub read_coalescing_gap: Option<u16>,
+    //Used for every query unless its device or the query itself has its own
+    pub retry_policy: RetryPolicy,
+    pub reconnect_policy: ReconnectPolicy,
+    //Takes turns between units so a device with a long queue can't starve the others
+    pub fair_scheduling: bool,
+    pub pacing: Pacing,
+}
+
+impl Default for ModbusMasterConnectionParams {
+    fn default() -> Self {
+        ModbusMasterConnectionParams {
+            max_response_time: MAX_MODBUS_RESPONSE_TIME,
+            max_simultaneous_transactions: 1,
+            read_coalescing_gap: None,
+            retry_policy: RetryPolicy::default(),
+            reconnect_policy: ReconnectPolicy::default(),
+            fair_scheduling: false,
+            pacing: Pacing::default(),
+        }
+    }
+}
+
+//Settings applied to every query added after they are set, or to the queries of a single call
+//through with_options
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QueryOptions {
+    //Takes precedence over the device and connection retry policies,
+    //RetryPolicy::none() keeps non-idempotent writes from being sent twice
+    pub retry_policy: Option<RetryPolicy>,
+    //None sends writes before reads
+    pub priority: Option<Priority>,
+    //Confirmed single and multiple writes are read back, values the device changed are reported
+    pub verify_writes: bool,
+}
+
+//The master with options that only apply to the queries added through it, like
+//`master.with_options(options).write_single_register(1, 0, 5).await`. The options of the
+//connection are back once it's dropped
+pub struct WithQueryOptions<'a> {
+    master: &'a mut ModbusMasterConnection,
+    previous: QueryOptions,
+}
+
+impl Deref for WithQueryOptions<'_> {
+    type Target = ModbusMasterConnection;
+
+    fn deref(&self) -> &Self::Target {
+        self.master
+    }
+}
+
+impl DerefMut for WithQueryOptions<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.master
+    }
+}
+
+impl Drop for WithQueryOptions<'_> {
+    fn drop(&mut self) {
+        self.master.query_options = self.previous;
+    }
 }
 
 pub struct ModbusMasterConnection {
     comm: ModbusMasterCommunicationInfo,
     context: ModbusMasterContext,
     subprotocol: ModbusSubprotocol,
+    params: ModbusMasterConnectionParams,
+    query_options: QueryOptions,
+    //Latest value read for every address, subscriptions start from it
+    snapshot: PollSnapshot,
+    subscribers: Vec<Subscriber>,
 }
 
 impl ModbusMasterConnection {
+    //Every constructor ends up here, they only differ in the link and what suits it
+    fn with_comm(
+        comm: ModbusMasterCommunicationInfo,
+        subprotocol: ModbusSubprotocol,
+        params: ModbusMasterConnectionParams,
+    ) -> Self {
+        ModbusMasterConnection {
+            comm,
+            context: ModbusMasterContext::new(),
+            subprotocol,
+            params,
+            query_options: QueryOptions::default(),
+            snapshot: PollSnapshot::default(),
+            subscribers: vec![],
+        }
+    }
+
     pub fn new_tcp(address: SocketAddr) -> Self {
         let comm = ModbusMasterCommunicationInfo::new_tcp(address);
 
-        let context = ModbusMasterContext::new();
+        Self::with_comm(comm, ModbusSubprotocol::ModbusTCP, ModbusMasterConnectionParams::default())
+    }
 
-        ModbusMasterConnection {
-            comm,
-            context,
-            subprotocol: ModbusSubprotocol::ModbusTCP,
+    //RTU frames over a TCP connection, as spoken by serial gateways that don't translate to Modbus TCP
+    pub fn new_rtu_over_tcp(address: SocketAddr) -> Self {
+        let comm = ModbusMasterCommunicationInfo::new_tcp(address);
+
+        Self::with_comm(comm, ModbusSubprotocol::ModbusRTUOverTCP, ModbusMasterConnectionParams::default())
+    }
+
+    //Serial lines carry one transaction at a time, max_simultaneous_transactions is ignored on them
+    pub fn new_rtu(device: &str, settings: SerialSettings) -> Self {
+        let comm = ModbusMasterCommunicationInfo::new_rtu(device.to_string(), settings);
+
+        Self::with_comm(comm, ModbusSubprotocol::ModbusRTU, ModbusMasterConnectionParams::default())
+    }
+
+    //Modbus TCP framing over datagrams. Lost datagrams show up as timeouts, so by default queries
+    //are sent again on a timeout with a fresh transaction id and late answers are discarded
+    pub fn new_udp(address: SocketAddr) -> Self {
+        let comm = ModbusMasterCommunicationInfo::new_udp(address);
+
+        let params = ModbusMasterConnectionParams {
+            max_response_time: UDP_RESPONSE_TIME,
+            retry_policy: RetryPolicy {
+                retry_on: RetryOn {
+                    timeout: true,
+                    connection_error: false,
+                    server_device_busy: false,
+                    acknowledge: false,
+                    gateway_error: false,
+                },
+                ..RetryPolicy::new(UDP_ATTEMPTS, Backoff::Fixed(Duration::ZERO))
+            },
+            ..Default::default()
+        };
+
+        Self::with_comm(comm, ModbusSubprotocol::ModbusTCP, params)
+    }
+
+    //Modbus TCP framing over a local socket, access is up to the permissions on the path
+    #[cfg(unix)]
+    pub fn new_unix(path: impl AsRef<std::path::Path>) -> Self {
+        let comm = ModbusMasterCommunicationInfo::new_unix(path.as_ref().to_path_buf());
+
+        Self::with_comm(comm, ModbusSubprotocol::ModbusTCP, ModbusMasterConnectionParams::default())
+    }
+
+    //Talks to the server inside this process, every message takes the latency to reach the other end
+    pub fn new_in_memory(
+        server: &ModbusSlaveConnection,
+        subprotocol: ModbusSubprotocol,
+        latency: Duration,
+    ) -> Self {
+        let comm = ModbusMasterCommunicationInfo::new_in_memory(
+            server.in_memory_connector(subprotocol, latency),
+        );
+
+        Self::with_comm(comm, subprotocol, ModbusMasterConnectionParams::default())
+    }
+
+    //Runs over whatever stream the connector makes, like an SSH tunnel or a proxy. The connector is
+    //called again on every reconnect
+    pub fn new_with_connector<F, Fut, S, E>(subprotocol: ModbusSubprotocol, connect: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<S, E>> + Send + 'static,
+        S: AsyncStream + 'static,
+        E: Into<anyhow::Error> + 'static,
+    {
+        let comm = ModbusMasterCommunicationInfo::new_stream(stream_connector(connect));
+
+        Self::with_comm(comm, subprotocol, ModbusMasterConnectionParams::default())
+    }
+
+    pub fn set_params(&mut self, params: ModbusMasterConnectionParams) {
+        self.params = params;
+    }
+
+    pub fn get_params(&self) -> ModbusMasterConnectionParams {
+        self.params
+    }
+
+    //Publishes every change of the connection state, starting with the current one
+    pub fn connection_state(&self) -> tokio::sync::watch::Receiver<ConnectionState> {
+        self.comm.subscribe_state()
+    }
+
+    //Streams changes of the given addresses as any call, batch or poll reads them, starting with
+    //what is already known
+    pub fn subscribe(&mut self, addresses: Vec<ModbusAddress>, deadband: Deadband) -> Subscription {
+        let (subscriber, subscription) = Subscriber::new(addresses, deadband, &self.snapshot);
+        self.subscribers.push(subscriber);
+        subscription
+    }
+
+    //Keeps the values in the snapshot and tells subscribers about them
+    fn publish(
+        &mut self,
+        values: Vec<(ModbusAddress, Option<ModbusDataType>, Quality)>,
+        timestamp: SystemTime,
+    ) {
+        for (address, value, quality) in values {
+            if let Some(value) = value {
+                for subscriber in self.subscribers.iter_mut() {
+                    subscriber.notify(&address, value, timestamp);
+                }
+            }
+            self.snapshot.update(address, value, quality, timestamp);
         }
+
+        //Dropped subscriptions are forgotten, whether or not they were told about anything
+        self.subscribers.retain(|subscriber| !subscriber.is_closed());
     }
 
-    pub async fn query_with_params(
+    //Only values that were read are published, writes have nothing to tell
+    fn publish_results(&mut self, results: &HashMap<ModbusAddress, ModbusResult>) {
+        let values = results
+            .iter()
+            .filter(|(_, result)| result.value.is_some())
+            .map(|(address, result)| (address.clone(), result.value, result.quality))
+            .collect();
+        self.publish(values, SystemTime::now());
+    }
+
+    //Outcome of a finished query, what it read is published on the way
+    fn take_outcome(
         &mut self,
-        params: ModbusMasterConnectionParams,
-    ) -> Result<HashMap<ModbusAddress, ModbusResult>> {
-        let mut results = HashMap::new();
-        while !self.context.queued_queries.is_empty() {
-            self.context
-                .load_queued_queries(params.max_simultaneous_transactions);
+        id: QueryId,
+    ) -> Option<std::result::Result<HashMap<ModbusAddress, ModbusResult>, ModbusError>> {
+        let outcome = self.context.take_outcome(id);
+        if let Some(Ok(results)) = &outcome {
+            self.publish_results(results);
+        }
+        outcome
+    }
 
-            let all_queries = self.context.serialize_queries(self.subprotocol)?;
+    pub fn set_query_options(&mut self, query_options: QueryOptions) {
+        self.query_options = query_options;
+    }
 
-            if !self.comm.is_connected().await {
-                self.comm.connect().await?;
-            }
+    pub fn get_query_options(&self) -> QueryOptions {
+        self.query_options
+    }
 
-            let comm = self
-                .comm
-                .comm
-                .as_mut()
-                .ok_or_else(|| anyhow!("Socket wasn't intialised!"))?;
+    //Takes the place of the query options for the calls made through the returned master only
+    pub fn with_options(&mut self, query_options: QueryOptions) -> WithQueryOptions<'_> {
+        let previous = std::mem::replace(&mut self.query_options, query_options);
+        WithQueryOptions {
+            master: self,
+            previous,
+        }
+    }
+
+    //Overrides the connection retry policy for a single unit, None goes back to the connection one
+    pub fn set_device_retry_policy(&mut self, slave_id: u8, retry_policy: Option<RetryPolicy>) {
+        self.context.set_device_retry_policy(slave_id, retry_policy);
+    }
 
-            let result = comm.write(all_queries).await;
+    fn enqueue(&mut self, mut query: QueuedQuery) -> QueryId {
+        query.retry_policy = self.query_options.retry_policy;
+        query.verify = self.query_options.verify_writes;
+        query.priority = self
+            .query_options
+            .priority
+            .unwrap_or_else(|| Priority::for_query(&query));
+        self.context.enqueue(query)
+    }
 
-            if result.is_err() {
-                self.comm.has_failed = true;
+    //Keeps up to max_simultaneous_transactions queries on the wire, matching responses by transaction id
+    //until everything queued is done or, when given, the target query is done
+    async fn process_queued_queries(
+        &mut self,
+        params: ModbusMasterConnectionParams,
+        target: Option<QueryId>,
+    ) -> Result<()> {
+        self.prepare_queued_queries(params);
+
+        loop {
+            let target_done = target.is_some_and(|id| !self.context.is_pending(id));
+            if target_done
+                || (!self.context.has_queued_queries() && !self.context.has_on_going_queries())
+            {
+                return Ok(());
             }
 
-            result?;
+            self.process_step(params).await?;
+        }
+    }
 
-            let time_out = sleep(params.max_response_time);
-            tokio::pin!(time_out);
+    fn prepare_queued_queries(&mut self, params: ModbusMasterConnectionParams) {
+        self.context
+            .optimize_queued_queries(params.read_coalescing_gap);
+        self.context.set_retry_policy(params.retry_policy);
+        self.context.set_fair_scheduling(params.fair_scheduling);
+        self.context.set_pacing(params.pacing);
+    }
 
-            let mut stop_listening = false;
-            loop {
-                tokio::select! {
-                    bytes = comm.read() => {
-                        if let Err(_) = bytes {
-                            self.comm.has_failed = true;
-                            break;
-                        }
+    //Connects if needed, fills the transaction window and waits for a response, a deadline or a retry.
+    //Every step is bounded by the socket read, so new queries can be queued in between
+    async fn process_step(&mut self, params: ModbusMasterConnectionParams) -> Result<()> {
+        self.process_step_until(params, std::future::pending()).await
+    }
 
-                        let bytes = bytes.unwrap();
+    //Same as process_step, but gives up waiting when new_work resolves before any of a response
+    //has arrived, so queries queued meanwhile go out without waiting for the ones on the wire
+    async fn process_step_until(
+        &mut self,
+        params: ModbusMasterConnectionParams,
+        new_work: impl Future<Output = ()>,
+    ) -> Result<()> {
+        if !self.comm.is_connected().await {
+            if let Err(err) = self.comm.reconnect(&params.reconnect_policy).await {
+                self.context
+                    .fail_all_queries(ModbusError::Connection(err.to_string()));
+                return Err(err);
+            }
+        }
 
-                        let responses = ModbusResponse::deserialize(bytes, self.subprotocol)?;
+        let comm = self
+            .comm
+            .comm
+            .as_mut()
+            .ok_or_else(|| anyhow!("Socket wasn't intialised!"))?;
 
-                        self.context.process_modbus_responses(responses, & mut results);
+        //Only tcp has transaction ids to tell responses apart
+        let window = match self.subprotocol {
+            ModbusSubprotocol::ModbusTCP => params.max_simultaneous_transactions.max(1) as usize,
+            _ => 1,
+        };
 
-                        if ! self.context.has_on_going_queries()
-                        {
-                            stop_listening = true;
-                        }
+        let mut write_error = None;
+        while self.context.in_flight_transactions() < window {
+            let Some(bytes) = self.context.start_next_transaction(
+                self.subprotocol,
+                Instant::now(),
+                params.max_response_time,
+            ) else {
+                break;
+            };
+
+            if let Err(err) = comm.write(bytes).await {
+                write_error = Some(err);
+                break;
+            }
 
-                    }
-                    _ = & mut time_out => {
-                        self.comm.has_failed = true;
-                        stop_listening = true;
-                    }
-                };
+            if self.subprotocol != ModbusSubprotocol::ModbusTCP {
+                self.context.complete_broadcasts();
+            }
+        }
 
-                if stop_listening {
-                    break;
-                }
+        if let Some(err) = write_error {
+            self.comm.mark_failed();
+            self.context.requeue_on_going_queries(
+                ModbusError::Connection(err.to_string()),
+                Instant::now(),
+            );
+            return Ok(());
+        }
+
+        //Wakes up for the first response deadline or the first retry or paced query that can be sent
+        let Some(wake_up) = [
+            self.context.next_deadline(),
+            self.context.next_retry(Instant::now()),
+            self.context.next_paced_send(Instant::now()),
+        ]
+        .into_iter()
+        .flatten()
+        .min() else {
+            return Ok(());
+        };
+
+        //Only waiting for the first byte can be cut short
+        tokio::select! {
+            _ = comm.readable() => {}
+            _ = sleep_until(wake_up) => {
+                self.context.expire_transactions(Instant::now());
+                return Ok(());
+            }
+            _ = new_work => return Ok(()),
+        };
+
+        //Reads aren't cancel safe, so a response that started before the deadline is read in full
+        //and the deadline is only checked once it's in
+        let responses = comm
+            .read()
+            .await
+            .and_then(|bytes| ModbusResponse::deserialize(bytes, self.subprotocol));
+
+        match responses {
+            Ok(responses) => self.context.process_modbus_responses(responses, Instant::now()),
+            Err(err) => {
+                //The stream can't be trusted anymore, queries on the wire go out again on a new connection
+                self.comm.mark_failed();
+                self.context.requeue_on_going_queries(ModbusError::Connection(err.to_string()), Instant::now());
+                return Ok(());
             }
         }
+
+        if Instant::now() >= wake_up {
+            self.context.expire_transactions(Instant::now());
+        }
+
+        Ok(())
+    }
+
+    //Runs a single query to completion, batch queries sent meanwhile keep their results for query()
+    async fn execute(
+        &mut self,
+        id: QueryId,
+    ) -> std::result::Result<HashMap<ModbusAddress, ModbusResult>, ModbusError> {
+        let result = self.process_queued_queries(self.params, Some(id)).await;
+
+        match (self.take_outcome(id), result) {
+            (Some(outcome), _) => outcome,
+            (None, Err(err)) => Err(ModbusError::Connection(err.to_string())),
+            (None, Ok(())) => Err(ModbusError::Timeout),
+        }
+    }
+
+    pub async fn query_with_params(
+        &mut self,
+        params: ModbusMasterConnectionParams,
+    ) -> Result<HashMap<ModbusAddress, ModbusResult>> {
+        self.process_queued_queries(params, None).await?;
+
+        let results = self.context.take_results();
+        self.publish_results(&results);
+
         if results.is_empty() {
             Err(anyhow!("No queries got answered!"))
         } else {
@@ -112,11 +491,20 @@ impl ModbusMasterConnection {
     pub fn query(
         &mut self,
     ) -> impl std::future::Future<Output = Result<HashMap<ModbusAddress, ModbusResult>>> + '_ {
-        let params = ModbusMasterConnectionParams {
-            max_response_time: MAX_MODBUS_RESPONSE_TIME,
-            max_simultaneous_transactions: 1,
-        };
-        self.query_with_params(params)
+        self.query_with_params(self.params)
+    }
+
+    //Queries that would run past the last address are refused before they are split or sent
+    fn check_range(address: u16, ammount: usize) -> Result<()> {
+        if address as usize + ammount > u16::MAX as usize + 1 {
+            return Err(anyhow!(
+                "{} values from address {} go past the last address {}",
+                ammount,
+                address,
+                u16::MAX
+            ));
+        }
+        Ok(())
     }
 
     fn add_read_query(
@@ -125,7 +513,9 @@ impl ModbusMasterConnection {
         address: u16,
         ammount: u16,
         function_code: FunctionCode,
-    ) -> Result<()> {
+    ) -> Result<QueryId> {
+        Self::check_range(address, ammount as usize)?;
+
         let message_data = ModbusMessageData {
             slave_id,
             function_code,
@@ -146,9 +536,7 @@ impl ModbusMasterConnection {
             params,
         };
 
-        self.context.queued_queries.push(query);
-
-        Ok(())
+        Ok(self.enqueue(QueuedQuery::new(query)))
     }
 
     fn add_single_write_query(
@@ -157,7 +545,7 @@ impl ModbusMasterConnection {
         address: u16,
         value: ModbusDataType,
         function_code: FunctionCode,
-    ) -> Result<()> {
+    ) -> Result<QueryId> {
         let message_data = ModbusMessageData {
             slave_id,
             function_code,
@@ -178,9 +566,7 @@ impl ModbusMasterConnection {
             params,
         };
 
-        self.context.queued_queries.push(query);
-
-        Ok(())
+        Ok(self.enqueue(QueuedQuery::new(query)))
     }
 
     fn add_multiple_write_query(
@@ -189,7 +575,9 @@ impl ModbusMasterConnection {
         address: u16,
         values: Vec<ModbusDataType>,
         function_code: FunctionCode,
-    ) -> Result<()> {
+    ) -> Result<QueryId> {
+        Self::check_range(address, values.len())?;
+
         let message_data = ModbusMessageData {
             slave_id,
             function_code,
@@ -210,9 +598,7 @@ impl ModbusMasterConnection {
             params,
         };
 
-        self.context.queued_queries.push(query);
-
-        Ok(())
+        Ok(self.enqueue(QueuedQuery::new(query)))
     }
 
     fn add_multiple_read_write_query(
@@ -223,7 +609,10 @@ impl ModbusMasterConnection {
         write_starting_address: u16,
         values: Vec<ModbusDataType>,
         function_code: FunctionCode,
-    ) -> Result<()> {
+    ) -> Result<QueryId> {
+        Self::check_range(read_starting_address, read_ammount as usize)?;
+        Self::check_range(write_starting_address, values.len())?;
+
         let message_data = ModbusMessageData {
             function_code,
             slave_id,
@@ -246,9 +635,7 @@ impl ModbusMasterConnection {
             params,
         };
 
-        self.context.queued_queries.push(query);
-
-        Ok(())
+        Ok(self.enqueue(QueuedQuery::new(query)))
     }
 
     pub fn add_multiple_read_write_holding_registers_query(
@@ -272,7 +659,8 @@ impl ModbusMasterConnection {
             write_starting_address,
             modbus_values,
             FunctionCode::ReadWriteMultipleRegisters,
-        )
+        )?;
+        Ok(())
     }
 
     pub fn add_write_multiple_coils_query(
@@ -292,7 +680,8 @@ impl ModbusMasterConnection {
             address,
             modbus_values,
             FunctionCode::WriteMultipleCoils,
-        )
+        )?;
+        Ok(())
     }
 
     pub fn add_write_multiple_holding_registers_query(
@@ -310,7 +699,8 @@ impl ModbusMasterConnection {
             address,
             modbus_values,
             FunctionCode::WriteMultipleHoldingRegisters,
-        )
+        )?;
+        Ok(())
     }
 
     pub fn add_write_coil_query(&mut self, slave_id: u8, address: u16, value: bool) -> Result<()> {
@@ -319,7 +709,8 @@ impl ModbusMasterConnection {
             address,
             ModbusDataType::Coil(value),
             FunctionCode::WriteSingleCoil,
-        )
+        )?;
+        Ok(())
     }
 
     pub fn add_write_holding_register_query(
@@ -333,11 +724,171 @@ impl ModbusMasterConnection {
             address,
             ModbusDataType::Register(value),
             FunctionCode::WriteSingleHoldingRegister,
-        )
+        )?;
+        Ok(())
+    }
+
+    //Only the query added through it is read back once confirmed
+    fn verified(&mut self) -> WithQueryOptions<'_> {
+        let query_options = QueryOptions {
+            verify_writes: true,
+            ..self.query_options
+        };
+        self.with_options(query_options)
+    }
+
+    pub fn add_write_multiple_coils_query_verified(
+        &mut self,
+        slave_id: u8,
+        address: u16,
+        values: Vec<bool>,
+    ) -> Result<()> {
+        self.verified()
+            .add_write_multiple_coils_query(slave_id, address, values)
+    }
+
+    pub fn add_write_multiple_holding_registers_query_verified(
+        &mut self,
+        slave_id: u8,
+        address: u16,
+        values: Vec<u16>,
+    ) -> Result<()> {
+        self.verified()
+            .add_write_multiple_holding_registers_query(slave_id, address, values)
+    }
+
+    pub fn add_write_coil_query_verified(
+        &mut self,
+        slave_id: u8,
+        address: u16,
+        value: bool,
+    ) -> Result<()> {
+        self.verified().add_write_coil_query(slave_id, address, value)
+    }
+
+    pub fn add_write_holding_register_query_verified(
+        &mut self,
+        slave_id: u8,
+        address: u16,
+        value: u16,
+    ) -> Result<()> {
+        self.verified()
+            .add_write_holding_register_query(slave_id, address, value)
+    }
+
+    fn check_bit(bit: u8) -> Result<()> {
+        if bit >= 16 {
+            return Err(anyhow!("Bit index {} is out of range, registers have 16 bits", bit));
+        }
+        Ok(())
+    }
+
+    fn add_read_bit_query(
+        &mut self,
+        slave_id: u8,
+        address: u16,
+        bit: u8,
+        function_code: FunctionCode,
+    ) -> Result<QueryId> {
+        Self::check_bit(bit)?;
+
+        let table = ModbusTable::get_table_from_function_code(function_code)
+            .ok_or_else(|| anyhow!("Function code doesn't address any table"))?;
+
+        let query = ModbusQuery::ReadQuery {
+            message_data: ModbusMessageData {
+                slave_id,
+                function_code,
+                transaction_id: Cell::new(None),
+            },
+            params: crate::messages::query::ReadQueryParameters {
+                starting_address: address,
+                ammount: 1,
+                table,
+            },
+        };
+
+        Ok(self.enqueue(QueuedQuery::new_bit_operation(query, BitOperation::Read { bit })))
+    }
+
+    pub fn add_read_holding_register_bit_query(
+        &mut self,
+        slave_id: u8,
+        address: u16,
+        bit: u8,
+    ) -> Result<()> {
+        self.add_read_bit_query(
+            slave_id,
+            address,
+            bit,
+            FunctionCode::ReadMultipleHoldingRegister,
+        )?;
+        Ok(())
+    }
+
+    pub fn add_read_input_register_bit_query(
+        &mut self,
+        slave_id: u8,
+        address: u16,
+        bit: u8,
+    ) -> Result<()> {
+        self.add_read_bit_query(slave_id, address, bit, FunctionCode::ReadInputRegisters)?;
+        Ok(())
+    }
+
+    //Uses a mask write (FC 22) unless the device already refused one, then it falls back to a read-modify-write
+    fn add_write_bit_query(
+        &mut self,
+        slave_id: u8,
+        address: u16,
+        bit: u8,
+        value: bool,
+    ) -> Result<QueryId> {
+        Self::check_bit(bit)?;
+
+        if !self.context.supports_mask_write(slave_id) {
+            return Ok(self.enqueue(ModbusMasterContext::read_modify_write_query(
+                    slave_id, address, bit, value,
+                )));
+        }
+
+        let and_mask = !(1u16 << bit);
+        let or_mask = if value { 1u16 << bit } else { 0 };
+
+        let query = ModbusQuery::MaskWriteQuery {
+            message_data: ModbusMessageData {
+                slave_id,
+                function_code: FunctionCode::MaskWriteRegister,
+                transaction_id: Cell::new(None),
+            },
+            params: crate::messages::query::MaskWriteQueryParameters {
+                table: ModbusTable::HoldingRegisters,
+                address,
+                and_mask,
+                or_mask,
+            },
+        };
+
+        Ok(self.enqueue(QueuedQuery::new_bit_operation(
+            query,
+            BitOperation::MaskWrite { bit, value },
+        )))
+    }
+
+    pub fn add_write_holding_register_bit_query(
+        &mut self,
+        slave_id: u8,
+        address: u16,
+        bit: u8,
+        value: bool,
+    ) -> Result<()> {
+        self.add_write_bit_query(slave_id, address, bit, value)?;
+        Ok(())
     }
 
     pub fn add_read_coils_query(&mut self, slave_id: u8, address: u16, ammount: u16) -> Result<()> {
-        self.add_read_query(slave_id, address, ammount, FunctionCode::ReadCoils)
+        self.add_read_query(slave_id, address, ammount, FunctionCode::ReadCoils)?;
+        Ok(())
     }
 
     pub fn add_read_holding_registers_query(
@@ -351,7 +902,8 @@ impl ModbusMasterConnection {
             address,
             ammount,
             FunctionCode::ReadMultipleHoldingRegister,
-        )
+        )?;
+        Ok(())
     }
 
     pub fn add_read_discrete_inputs_query(
@@ -360,7 +912,8 @@ impl ModbusMasterConnection {
         address: u16,
         ammount: u16,
     ) -> Result<()> {
-        self.add_read_query(slave_id, address, ammount, FunctionCode::ReadDiscreteInputs)
+        self.add_read_query(slave_id, address, ammount, FunctionCode::ReadDiscreteInputs)?;
+        Ok(())
     }
 
     pub fn add_read_input_registers_query(
@@ -369,6 +922,7 @@ impl ModbusMasterConnection {
         address: u16,
         ammount: u16,
     ) -> Result<()> {
-        self.add_read_query(slave_id, address, ammount, FunctionCode::ReadInputRegisters)
+        self.add_read_query(slave_id, address, ammount, FunctionCode::ReadInputRegisters)?;
+        Ok(())
     }
 }
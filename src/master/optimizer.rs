@@ -0,0 +1,373 @@
+use std::cell::Cell;
+
+use crate::common::{ModbusDataType, ModbusTable};
+use crate::master::context::{BitOperation, QueuedQuery};
+use crate::messages::query::{MultipleWriteQueryParameters, ReadQueryParameters};
+use crate::messages::{ModbusMessageData, ModbusQuery};
+
+//Protocol limits for the ammount of values a single request can carry
+pub const MAX_READ_REGISTERS: u16 = 125;
+pub const MAX_READ_COILS: u16 = 2000;
+pub const MAX_WRITE_REGISTERS: u16 = 123;
+pub const MAX_WRITE_COILS: u16 = 1968;
+
+fn max_read_ammount(table: ModbusTable) -> u16 {
+    match table {
+        ModbusTable::Coils | ModbusTable::DiscreteInput => MAX_READ_COILS,
+        ModbusTable::HoldingRegisters | ModbusTable::InputRegisters => MAX_READ_REGISTERS,
+    }
+}
+
+fn max_write_ammount(table: ModbusTable) -> u16 {
+    match table {
+        ModbusTable::Coils | ModbusTable::DiscreteInput => MAX_WRITE_COILS,
+        ModbusTable::HoldingRegisters | ModbusTable::InputRegisters => MAX_WRITE_REGISTERS,
+    }
+}
+
+//Splits requests over the protocol limits and, when a gap tolerance is given,
+//merges reads on the same unit and table that are close enough to each other.
+//Parts come out lowest address first, in the place of the query they came from
+pub fn optimize_queries(queries: Vec<QueuedQuery>, gap_tolerance: Option<u16>) -> Vec<QueuedQuery> {
+    let queries = queries.into_iter().flat_map(split_query).collect();
+
+    match gap_tolerance {
+        Some(gap_tolerance) => coalesce_reads(queries, gap_tolerance),
+        None => queries,
+    }
+}
+
+fn split_query(queued_query: QueuedQuery) -> Vec<QueuedQuery> {
+    if queued_query.bit_operation.is_some() {
+        return vec![queued_query];
+    }
+
+    match &queued_query.query {
+        ModbusQuery::ReadQuery {
+            message_data,
+            params,
+        } if params.ammount > max_read_ammount(params.table) => {
+            let max = max_read_ammount(params.table);
+            let end = params.starting_address as u32 + params.ammount as u32;
+
+            (params.starting_address as u32..end)
+                .step_by(max as usize)
+                .map(|starting_address| {
+                    let ammount = (end - starting_address).min(max as u32) as u16;
+                    queued_query.derived(QueuedQuery::new(read_query(
+                        message_data,
+                        params.table,
+                        starting_address as u16,
+                        ammount,
+                    )))
+                })
+                .collect::<Vec<QueuedQuery>>()
+        }
+        ModbusQuery::MultipleWriteQuery {
+            message_data,
+            params,
+        } if params.values.len() > max_write_ammount(params.table) as usize => params
+            .values
+            .chunks(max_write_ammount(params.table) as usize)
+            .enumerate()
+            .map(|(index, values)| {
+                let starting_address = params.starting_address
+                    + (index * max_write_ammount(params.table) as usize) as u16;
+                queued_query.derived(QueuedQuery::new(ModbusQuery::MultipleWriteQuery {
+                    message_data: fresh_message_data(message_data),
+                    params: MultipleWriteQueryParameters {
+                        table: params.table,
+                        starting_address,
+                        values: values.to_vec(),
+                    },
+                }))
+            })
+            .collect(),
+        _ => vec![queued_query],
+    }
+}
+
+fn is_coalescable(queued_query: &QueuedQuery) -> bool {
+    queued_query.coalesce
+        && matches!(queued_query.query, ModbusQuery::ReadQuery { .. })
+        && matches!(
+            queued_query.bit_operation,
+            None | Some(BitOperation::Read { .. })
+        )
+}
+
+fn read_range(queued_query: &QueuedQuery) -> (u32, u32) {
+    match &queued_query.query {
+        ModbusQuery::ReadQuery { params, .. } => (
+            params.starting_address as u32,
+            params.starting_address as u32 + params.ammount as u32,
+        ),
+        _ => (0, 0),
+    }
+}
+
+fn coalesce_reads(queries: Vec<QueuedQuery>, gap_tolerance: u16) -> Vec<QueuedQuery> {
+    //Groups are keyed by unit, function code, retry policy and priority, and remember where they
+    //first showed up
+    let mut groups: Vec<(usize, (u8, u8), Vec<QueuedQuery>)> = vec![];
+    let mut result: Vec<Option<QueuedQuery>> = vec![];
+
+    for queued_query in queries {
+        if !is_coalescable(&queued_query) {
+            result.push(Some(queued_query));
+            continue;
+        }
+
+        let message_data = queued_query.query.get_message_data();
+        let key = (message_data.slave_id, message_data.function_code as u8);
+
+        match groups.iter_mut().find(|(_, group_key, group)| {
+            *group_key == key
+                && group[0].retry_policy == queued_query.retry_policy
+                && group[0].priority == queued_query.priority
+        }) {
+            Some((_, _, group)) => group.push(queued_query),
+            None => {
+                groups.push((result.len(), key, vec![queued_query]));
+                result.push(None);
+            }
+        }
+    }
+
+    //Groups are spliced from the back so the positions of the remaining ones stay valid
+    for (position, _, mut group) in groups.into_iter().rev() {
+        group.sort_by_key(read_range);
+
+        let mut merged = vec![];
+        let mut run: Vec<QueuedQuery> = vec![];
+        let mut run_range = (0u32, 0u32);
+
+        for queued_query in group {
+            let (start, end) = read_range(&queued_query);
+
+            if let Some(first) = run.first() {
+                let table = match &first.query {
+                    ModbusQuery::ReadQuery { params, .. } => params.table,
+                    _ => unreachable!(),
+                };
+                let merged_end = run_range.1.max(end);
+
+                if start <= run_range.1 + gap_tolerance as u32
+                    && merged_end - run_range.0 <= max_read_ammount(table) as u32
+                {
+                    run_range.1 = merged_end;
+                    run.push(queued_query);
+                    continue;
+                }
+
+                merged.push(merge_run(std::mem::take(&mut run), run_range));
+            }
+
+            run_range = (start, end);
+            run.push(queued_query);
+        }
+
+        if !run.is_empty() {
+            merged.push(merge_run(run, run_range));
+        }
+
+        result.splice(position..position + 1, merged.into_iter().map(Some));
+    }
+
+    result.into_iter().flatten().collect()
+}
+
+fn merge_run(mut run: Vec<QueuedQuery>, (start, end): (u32, u32)) -> QueuedQuery {
+    if run.len() == 1 {
+        return run.pop().unwrap();
+    }
+
+    let (message_data, table) = match &run[0].query {
+        ModbusQuery::ReadQuery {
+            message_data,
+            params,
+        } => (message_data.clone(), params.table),
+        _ => unreachable!(),
+    };
+
+    let mut merged = QueuedQuery::new(read_query(
+        &message_data,
+        table,
+        start as u16,
+        (end - start) as u16,
+    ));
+    merged.coalesce = false;
+    merged.retry_policy = run[0].retry_policy;
+    merged.priority = run[0].priority;
+    merged.coalesced = run;
+    merged
+}
+
+fn fresh_message_data(message_data: &ModbusMessageData) -> ModbusMessageData {
+    ModbusMessageData {
+        slave_id: message_data.slave_id,
+        function_code: message_data.function_code,
+        transaction_id: Cell::new(None),
+    }
+}
+
+fn read_query(
+    message_data: &ModbusMessageData,
+    table: ModbusTable,
+    starting_address: u16,
+    ammount: u16,
+) -> ModbusQuery {
+    ModbusQuery::ReadQuery {
+        message_data: fresh_message_data(message_data),
+        params: ReadQueryParameters {
+            table,
+            starting_address,
+            ammount,
+        },
+    }
+}
+
+//Slices the values of a coalesced read back into the range of one of the original queries
+pub fn values_for_original(
+    merged_starting_address: u16,
+    values: &[ModbusDataType],
+    original: &QueuedQuery,
+) -> Option<Vec<ModbusDataType>> {
+    let (start, end) = read_range(original);
+    let offset = (start - merged_starting_address as u32) as usize;
+    let len = (end - start) as usize;
+
+    values
+        .get(offset..offset + len)
+        .map(|values| values.to_vec())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::messages::FunctionCode;
+
+    fn read(
+        slave_id: u8,
+        function_code: FunctionCode,
+        starting_address: u16,
+        ammount: u16,
+    ) -> QueuedQuery {
+        QueuedQuery::new(ModbusQuery::ReadQuery {
+            message_data: ModbusMessageData {
+                slave_id,
+                function_code,
+                transaction_id: Cell::new(None),
+            },
+            params: ReadQueryParameters {
+                table: ModbusTable::get_table_from_function_code(function_code).unwrap(),
+                starting_address,
+                ammount,
+            },
+        })
+    }
+
+    fn ranges(queries: &[QueuedQuery]) -> Vec<(u32, u32)> {
+        queries.iter().map(read_range).collect()
+    }
+
+    #[test]
+    fn test_split_oversized_read() {
+        let queries = vec![read(1, FunctionCode::ReadMultipleHoldingRegister, 0, 300)];
+
+        let result = optimize_queries(queries, None);
+
+        assert_eq!(ranges(&result), vec![(0, 125), (125, 250), (250, 300)]);
+    }
+
+    #[test]
+    fn test_split_oversized_write() {
+        let queries = vec![QueuedQuery::new(ModbusQuery::MultipleWriteQuery {
+            message_data: ModbusMessageData {
+                slave_id: 1,
+                function_code: FunctionCode::WriteMultipleHoldingRegisters,
+                transaction_id: Cell::new(None),
+            },
+            params: MultipleWriteQueryParameters {
+                table: ModbusTable::HoldingRegisters,
+                starting_address: 10,
+                values: vec![ModbusDataType::Register(0); 200],
+            },
+        })];
+
+        let result = optimize_queries(queries, None);
+
+        let parts: Vec<(u16, usize)> = result
+            .iter()
+            .map(|query| match &query.query {
+                ModbusQuery::MultipleWriteQuery { params, .. } => {
+                    (params.starting_address, params.values.len())
+                }
+                _ => panic!("Expected a multiple write"),
+            })
+            .collect();
+        assert_eq!(parts, vec![(10, 123), (133, 77)]);
+    }
+
+    #[test]
+    fn test_split_up_to_the_last_address() {
+        let queries = vec![
+            read(1, FunctionCode::ReadMultipleHoldingRegister, 65336, 200),
+            QueuedQuery::new(ModbusQuery::MultipleWriteQuery {
+                message_data: ModbusMessageData {
+                    slave_id: 1,
+                    function_code: FunctionCode::WriteMultipleHoldingRegisters,
+                    transaction_id: Cell::new(None),
+                },
+                params: MultipleWriteQueryParameters {
+                    table: ModbusTable::HoldingRegisters,
+                    starting_address: 65336,
+                    values: vec![ModbusDataType::Register(0); 200],
+                },
+            }),
+        ];
+
+        let result = optimize_queries(queries, None);
+
+        assert_eq!(ranges(&result[..2]), vec![(65336, 65461), (65461, 65536)]);
+        let parts: Vec<(u16, usize)> = result[2..]
+            .iter()
+            .map(|query| match &query.query {
+                ModbusQuery::MultipleWriteQuery { params, .. } => {
+                    (params.starting_address, params.values.len())
+                }
+                _ => panic!("Expected a multiple write"),
+            })
+            .collect();
+        assert_eq!(parts, vec![(65336, 123), (65459, 77)]);
+    }
+
+    #[test]
+    fn test_coalesce_nearby_reads() {
+        let queries = vec![
+            read(1, FunctionCode::ReadMultipleHoldingRegister, 0, 2),
+            read(2, FunctionCode::ReadMultipleHoldingRegister, 3, 2),
+            read(1, FunctionCode::ReadMultipleHoldingRegister, 4, 2),
+            read(1, FunctionCode::ReadInputRegisters, 2, 2),
+            read(1, FunctionCode::ReadMultipleHoldingRegister, 20, 2),
+        ];
+
+        let result = optimize_queries(queries, Some(2));
+
+        assert_eq!(ranges(&result), vec![(0, 6), (20, 22), (3, 5), (2, 4)]);
+        assert_eq!(result[0].coalesced.len(), 2);
+        assert!(result[1].coalesced.is_empty());
+    }
+
+    #[test]
+    fn test_coalesce_respects_limits() {
+        let queries = vec![
+            read(1, FunctionCode::ReadMultipleHoldingRegister, 0, 100),
+            read(1, FunctionCode::ReadMultipleHoldingRegister, 100, 100),
+        ];
+
+        let result = optimize_queries(queries, Some(0));
+
+        assert_eq!(ranges(&result), vec![(0, 100), (100, 200)]);
+    }
+}
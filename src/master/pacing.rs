@@ -0,0 +1,220 @@
+use std::collections::VecDeque;
+
+use tokio::time::{Duration, Instant};
+
+use crate::common::SlaveId;
+use crate::messages::ModbusQuery;
+
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+//Limits on how fast requests are put on the wire, for gateways that drop what comes in too fast.
+//The defaults don't hold anything back
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Pacing {
+    //Quiet time before every request, counted from the last request or response on the link
+    pub min_request_gap: Duration,
+    //Requests within any one second, to a single unit and to the whole endpoint
+    pub max_requests_per_second_per_unit: Option<u32>,
+    pub max_requests_per_second: Option<u32>,
+    //Time a device is given after answering a write before anything else is sent
+    pub write_settle_delay: Duration,
+}
+
+fn is_write(query: &ModbusQuery) -> bool {
+    !matches!(query, ModbusQuery::ReadQuery { .. })
+}
+
+//Keeps track of the traffic on the link to tell when the next request may go out
+#[derive(Debug, Default)]
+pub(super) struct Pacer {
+    pacing: Pacing,
+    line_free_at: Option<Instant>,
+    //Requests sent within the last rate window, oldest first
+    sent: VecDeque<(Instant, SlaveId)>,
+}
+
+//When the oldest requests have left the window so that fewer than max remain in it
+fn rate_limited_until<'a>(
+    sent: impl Iterator<Item = &'a Instant>,
+    max: Option<u32>,
+) -> Option<Instant> {
+    let max = max?.max(1) as usize;
+    let sent: Vec<&Instant> = sent.collect();
+
+    sent.len()
+        .checked_sub(max)
+        .map(|excess| *sent[excess] + RATE_WINDOW)
+}
+
+impl Pacer {
+    pub fn set_pacing(&mut self, pacing: Pacing) {
+        self.pacing = pacing;
+    }
+
+    //Earliest time a request for the unit can be sent, never before now
+    pub fn ready_at(&self, slave_id: SlaveId, now: Instant) -> Instant {
+        let recent = || {
+            self.sent
+                .iter()
+                .filter(move |(sent_at, _)| *sent_at + RATE_WINDOW > now)
+        };
+
+        [
+            Some(now),
+            self.line_free_at,
+            rate_limited_until(
+                recent().map(|(sent_at, _)| sent_at),
+                self.pacing.max_requests_per_second,
+            ),
+            rate_limited_until(
+                recent()
+                    .filter(|(_, unit)| *unit == slave_id)
+                    .map(|(sent_at, _)| sent_at),
+                self.pacing.max_requests_per_second_per_unit,
+            ),
+        ]
+        .into_iter()
+        .flatten()
+        .max()
+        .unwrap_or(now)
+    }
+
+    pub fn record_request(&mut self, slave_id: SlaveId, now: Instant) {
+        while self
+            .sent
+            .front()
+            .is_some_and(|(sent_at, _)| *sent_at + RATE_WINDOW <= now)
+        {
+            self.sent.pop_front();
+        }
+        self.sent.push_back((now, slave_id));
+        self.keep_line_free_until(now + self.pacing.min_request_gap);
+    }
+
+    pub fn record_response(&mut self, query: &ModbusQuery, now: Instant) {
+        self.keep_line_free_until(now + self.pacing.min_request_gap);
+        if is_write(query) {
+            self.keep_line_free_until(now + self.pacing.write_settle_delay);
+        }
+    }
+
+    fn keep_line_free_until(&mut self, until: Instant) {
+        self.line_free_at = self.line_free_at.max(Some(until));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::master::test_utils::{connect_recording, RecordingSocket};
+    use crate::master::{ModbusMasterConnection, ModbusMasterConnectionParams};
+
+    fn paced_master(pacing: Pacing) -> (ModbusMasterConnection, RecordingSocket) {
+        let (mut master, socket) = connect_recording();
+        master.set_params(ModbusMasterConnectionParams {
+            pacing,
+            ..Default::default()
+        });
+
+        (master, socket)
+    }
+
+    #[test]
+    fn test_rate_limit_waits_for_the_window() {
+        let now = Instant::now();
+        let mut pacer = Pacer::default();
+        pacer.set_pacing(Pacing {
+            max_requests_per_second_per_unit: Some(2),
+            ..Default::default()
+        });
+
+        let second = now + Duration::from_millis(300);
+        pacer.record_request(1, now);
+        pacer.record_request(1, second);
+        assert_eq!(pacer.ready_at(2, second), second);
+        assert_eq!(
+            pacer.ready_at(1, now + Duration::from_millis(500)),
+            now + RATE_WINDOW
+        );
+        assert_eq!(pacer.ready_at(1, now + RATE_WINDOW), now + RATE_WINDOW);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_requests_keep_the_minimum_gap() {
+        let (mut master, socket) = paced_master(Pacing {
+            min_request_gap: Duration::from_millis(100),
+            ..Default::default()
+        });
+
+        let started = Instant::now();
+        for address in 0..3 {
+            master
+                .add_read_holding_registers_query(1, address, 1)
+                .unwrap();
+        }
+        master.query().await.unwrap();
+
+        let sent: Vec<Duration> = socket
+            .requests()
+            .iter()
+            .map(|(sent_at, _)| *sent_at - started)
+            .collect();
+        assert_eq!(
+            sent,
+            vec![
+                Duration::ZERO,
+                Duration::from_millis(100),
+                Duration::from_millis(200)
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limit_per_unit() {
+        let (mut master, socket) = paced_master(Pacing {
+            max_requests_per_second_per_unit: Some(2),
+            ..Default::default()
+        });
+
+        let started = Instant::now();
+        for address in 0..3 {
+            master
+                .add_read_holding_registers_query(1, address, 1)
+                .unwrap();
+        }
+        master.add_read_holding_registers_query(2, 0, 1).unwrap();
+        master.query().await.unwrap();
+
+        let sent: Vec<(Duration, SlaveId)> = socket
+            .requests()
+            .iter()
+            .map(|(sent_at, slave_id)| (*sent_at - started, *slave_id))
+            .collect();
+        //Unit 2 isn't held up by the third request of unit 1
+        assert_eq!(
+            sent,
+            vec![
+                (Duration::ZERO, 1),
+                (Duration::ZERO, 1),
+                (Duration::ZERO, 2),
+                (RATE_WINDOW, 1)
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_writes_are_given_time_to_settle() {
+        let (mut master, socket) = paced_master(Pacing {
+            write_settle_delay: Duration::from_millis(250),
+            ..Default::default()
+        });
+
+        let started = Instant::now();
+        master.write_single_register(1, 0, 5).await.unwrap();
+        master.read_holding_registers(1, 0, 1).await.unwrap();
+
+        let requests = socket.requests();
+        assert_eq!(requests[0].0 - started, Duration::ZERO);
+        assert_eq!(requests[1].0 - started, Duration::from_millis(250));
+    }
+}
@@ -0,0 +1,337 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use tokio::sync::broadcast;
+use tokio::time::{sleep_until, Duration, Instant};
+
+use crate::common::{
+    Address, ModbusAddress, ModbusDataType, ModbusError, ModbusTable, Quality, SlaveId,
+};
+use crate::master::subscription::{Deadband, Subscription};
+use crate::master::{ModbusMasterConnection, Priority, QueryOptions};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct PollRead {
+    slave_id: SlaveId,
+    table: ModbusTable,
+    address: Address,
+    ammount: u16,
+}
+
+impl PollRead {
+    fn addresses(&self) -> impl Iterator<Item = ModbusAddress> + '_ {
+        (self.address..self.address.saturating_add(self.ammount))
+            .map(|address| ModbusAddress::new(self.slave_id, self.table, address))
+    }
+}
+
+//A set of reads that are done together every interval
+#[derive(Clone, Debug, PartialEq)]
+pub struct PollGroup {
+    interval: Duration,
+    reads: Vec<PollRead>,
+}
+
+impl PollGroup {
+    pub fn new(interval: Duration) -> Self {
+        PollGroup {
+            interval,
+            reads: vec![],
+        }
+    }
+
+    pub fn add_read(
+        &mut self,
+        slave_id: SlaveId,
+        table: ModbusTable,
+        address: Address,
+        ammount: u16,
+    ) {
+        self.reads.push(PollRead {
+            slave_id,
+            table,
+            address,
+            ammount,
+        });
+    }
+
+    pub fn get_interval(&self) -> Duration {
+        self.interval
+    }
+}
+
+//Latest known state of an address, a failed read keeps the last good value with a bad quality
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PolledValue {
+    pub value: Option<ModbusDataType>,
+    pub quality: Quality,
+    pub timestamp: SystemTime,
+}
+
+//Shared view of the latest values read by a master, can be cloned and read from anywhere while it runs
+#[derive(Clone, Debug, Default)]
+pub struct PollSnapshot {
+    values: Arc<RwLock<HashMap<ModbusAddress, PolledValue>>>,
+}
+
+impl PollSnapshot {
+    pub fn get(&self, address: &ModbusAddress) -> Option<PolledValue> {
+        self.values.read().unwrap().get(address).copied()
+    }
+
+    pub fn values(&self) -> HashMap<ModbusAddress, PolledValue> {
+        self.values.read().unwrap().clone()
+    }
+
+    pub(super) fn update(
+        &self,
+        address: ModbusAddress,
+        value: Option<ModbusDataType>,
+        quality: Quality,
+        timestamp: SystemTime,
+    ) {
+        let mut values = self.values.write().unwrap();
+        let value = value.or_else(|| values.get(&address).and_then(|polled| polled.value));
+
+        values.insert(
+            address,
+            PolledValue {
+                value,
+                quality,
+                timestamp,
+            },
+        );
+    }
+}
+
+//A group took longer than its interval, the cycles it missed are skipped
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CycleOverrun {
+    pub group: usize,
+    pub due: Instant,
+    pub finished: Instant,
+    pub missed_cycles: u32,
+}
+
+struct ScheduledGroup {
+    group: PollGroup,
+    next_due: Instant,
+}
+
+pub struct Poller {
+    master: ModbusMasterConnection,
+    groups: Vec<ScheduledGroup>,
+    overruns: broadcast::Sender<CycleOverrun>,
+}
+
+//Deadlines are kept on the original grid so the schedule doesn't drift, cycles that were
+//already missed when the group finished are skipped instead of being run back to back
+fn next_due(due: Instant, interval: Duration, finished: Instant) -> (Instant, u32) {
+    let interval = interval.max(Duration::from_millis(1));
+    let missed_cycles =
+        (finished.saturating_duration_since(due).as_nanos() / interval.as_nanos()) as u32;
+
+    (due + interval * (missed_cycles + 1), missed_cycles)
+}
+
+impl Poller {
+    pub fn new(master: ModbusMasterConnection) -> Self {
+        let (overruns, _) = broadcast::channel(64);
+
+        Poller {
+            master,
+            groups: vec![],
+            overruns,
+        }
+    }
+
+    //Groups are polled for the first time right away, the returned index identifies them in overruns
+    pub fn add_group(&mut self, group: PollGroup) -> usize {
+        self.groups.push(ScheduledGroup {
+            group,
+            next_due: Instant::now(),
+        });
+        self.groups.len() - 1
+    }
+
+    //Values read by other calls on the master show up too
+    pub fn snapshot(&self) -> PollSnapshot {
+        self.master.snapshot.clone()
+    }
+
+    pub fn overruns(&self) -> broadcast::Receiver<CycleOverrun> {
+        self.overruns.subscribe()
+    }
+
+    //Same as subscribing on the master
+    pub fn subscribe(&mut self, addresses: Vec<ModbusAddress>, deadband: Deadband) -> Subscription {
+        self.master.subscribe(addresses, deadband)
+    }
+
+    //Writes and other queries can be sent between polls
+    pub fn master(&mut self) -> &mut ModbusMasterConnection {
+        &mut self.master
+    }
+
+    pub async fn run(&mut self) {
+        while self.poll_next().await.is_some() {}
+    }
+
+    //Waits for the next group to be due and polls it, the group that has been due the longest goes first
+    pub async fn poll_next(&mut self) -> Option<usize> {
+        let (index, due) = self
+            .groups
+            .iter()
+            .enumerate()
+            .map(|(index, scheduled)| (index, scheduled.next_due))
+            .min_by_key(|(_, due)| *due)?;
+
+        sleep_until(due).await;
+        self.poll_group(index).await;
+
+        let finished = Instant::now();
+        let (next_due, missed_cycles) = next_due(due, self.groups[index].group.interval, finished);
+        self.groups[index].next_due = next_due;
+
+        if missed_cycles > 0 {
+            //Nobody listening for overruns is fine
+            let _ = self.overruns.send(CycleOverrun {
+                group: index,
+                due,
+                finished,
+                missed_cycles,
+            });
+        }
+
+        Some(index)
+    }
+
+    async fn poll_group(&mut self, index: usize) {
+        let reads = self.groups[index].group.reads.clone();
+
+        //Polling is background work, queries added by the user go first
+        let query_options = self.master.get_query_options();
+        self.master.set_query_options(QueryOptions {
+            priority: Some(Priority::Low),
+            ..query_options
+        });
+        let ids: Vec<_> = reads
+            .iter()
+            .map(|read| {
+                self.master.add_read_query(
+                    read.slave_id,
+                    read.address,
+                    read.ammount,
+                    read.table.get_read_function_code(),
+                )
+            })
+            .collect();
+        self.master.set_query_options(query_options);
+
+        //Failures are recorded per query, so the outcome of each read is still available
+        let _ = self
+            .master
+            .process_queued_queries(self.master.params, None)
+            .await;
+        let timestamp = SystemTime::now();
+        let mut polled_values = vec![];
+
+        for (read, id) in reads.iter().zip(ids) {
+            let outcome = match id {
+                Ok(id) => self
+                    .master
+                    .context
+                    .take_outcome(id)
+                    .unwrap_or(Err(ModbusError::Timeout)),
+                Err(err) => Err(ModbusError::InvalidQuery(err.to_string())),
+            };
+
+            for address in read.addresses() {
+                let (value, quality) = match &outcome {
+                    Ok(results) => match results.get(&address) {
+                        Some(result) => (result.value, result.quality),
+                        None => (None, Quality::ProtocolError),
+                    },
+                    Err(err) => (None, Quality::from(err)),
+                };
+
+                polled_values.push((address, value, quality));
+            }
+        }
+
+        self.master.publish(polled_values, timestamp);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::master::test_utils::connect;
+
+    #[test]
+    fn test_schedule_does_not_drift() {
+        let due = Instant::now();
+        let interval = Duration::from_millis(100);
+
+        let (next, missed_cycles) = next_due(due, interval, due + Duration::from_millis(30));
+        assert_eq!((next, missed_cycles), (due + interval, 0));
+
+        let (next, missed_cycles) = next_due(due, interval, due + Duration::from_millis(250));
+        assert_eq!((next, missed_cycles), (due + Duration::from_millis(300), 2));
+    }
+
+    #[tokio::test]
+    async fn test_overdue_group_goes_first() {
+        let master = connect().await;
+        let mut poller = Poller::new(master);
+
+        let mut fast = PollGroup::new(Duration::from_millis(20));
+        fast.add_read(1, ModbusTable::HoldingRegisters, 0, 2);
+        let mut slow = PollGroup::new(Duration::from_secs(10));
+        slow.add_read(1, ModbusTable::HoldingRegisters, 8, 4);
+
+        let fast = poller.add_group(fast);
+        let slow = poller.add_group(slow);
+
+        let mut polled = vec![];
+        for _ in 0..4 {
+            polled.push(poller.poll_next().await.unwrap());
+        }
+        assert_eq!(polled, vec![fast, slow, fast, fast]);
+
+        let snapshot = poller.snapshot();
+        let value = snapshot
+            .get(&ModbusAddress::new(1, ModbusTable::HoldingRegisters, 1))
+            .unwrap();
+        assert_eq!(value.value, Some(ModbusDataType::Register(10)));
+        assert_eq!(value.quality, Quality::Good);
+
+        //Registers past 9 don't exist on the test slave
+        let value = snapshot
+            .get(&ModbusAddress::new(1, ModbusTable::HoldingRegisters, 8))
+            .unwrap();
+        assert_eq!(
+            value.quality,
+            Quality::Exception(crate::messages::ExceptionCode::IllegalDataAddress)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_overruns_are_reported() {
+        let master = connect().await;
+        let mut poller = Poller::new(master);
+        let mut overruns = poller.overruns();
+
+        let mut group = PollGroup::new(Duration::from_millis(1));
+        group.add_read(1, ModbusTable::HoldingRegisters, 0, 1);
+        let group = poller.add_group(group);
+
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        poller.poll_next().await;
+
+        let overrun = overruns.try_recv().unwrap();
+        assert_eq!(overrun.group, group);
+        assert!(overrun.missed_cycles >= 5);
+    }
+}
@@ -0,0 +1,269 @@
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use futures::future::join_all;
+
+use crate::common::{Address, ModbusError, SlaveId};
+use crate::master::requests::request_methods;
+use crate::master::{
+    ModbusMasterConnection, ModbusMasterConnectionParams, ModbusMasterHandle, QueryOptions,
+};
+
+struct Endpoint {
+    handle: ModbusMasterHandle,
+    unit_ids: HashSet<SlaveId>,
+}
+
+//Makes the connection of an endpoint, like ModbusMasterConnection::new_rtu_over_tcp for serial gateways
+type PoolConnector = Arc<dyn Fn(SocketAddr) -> ModbusMasterConnection + Send + Sync>;
+
+//Handle to one device of a pool, every request goes to its unit id
+#[derive(Clone)]
+pub struct ModbusDeviceHandle {
+    handle: ModbusMasterHandle,
+    unit_id: SlaveId,
+}
+
+impl ModbusDeviceHandle {
+    pub fn unit_id(&self) -> SlaveId {
+        self.unit_id
+    }
+
+    //Handle to the same device whose requests use the given options
+    pub fn with_options(&self, query_options: QueryOptions) -> Self {
+        ModbusDeviceHandle {
+            handle: self.handle.with_options(query_options),
+            unit_id: self.unit_id,
+        }
+    }
+}
+
+//Same requests as the master, without the unit id
+macro_rules! device_requests {
+    ($(fn $name:ident(slave_id: SlaveId $(, $arg:ident: $ty:ty)*) -> $ret:ty;)*) => {
+        impl ModbusDeviceHandle {
+            $(
+                pub async fn $name(&self $(, $arg: $ty)*) -> Result<$ret, ModbusError> {
+                    self.handle.$name(self.unit_id $(, $arg)*).await
+                }
+            )*
+        }
+    };
+}
+
+request_methods!(device_requests);
+
+//Keeps one connection per endpoint and routes requests by endpoint and unit id. How many
+//transactions an endpoint gets in flight is set by max_simultaneous_transactions of its params,
+//serial gateways should keep it at 1. Can be cloned and changed from any task
+#[derive(Clone)]
+pub struct ModbusMasterPool {
+    endpoints: Arc<Mutex<HashMap<SocketAddr, Endpoint>>>,
+    connect: PoolConnector,
+}
+
+impl Default for ModbusMasterPool {
+    fn default() -> Self {
+        Self::with_connector(ModbusMasterConnection::new_tcp)
+    }
+}
+
+impl ModbusMasterPool {
+    //Endpoints are reached over Modbus TCP
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    //Endpoints are reached through the connections the connector makes
+    pub fn with_connector(
+        connect: impl Fn(SocketAddr) -> ModbusMasterConnection + Send + Sync + 'static,
+    ) -> Self {
+        ModbusMasterPool {
+            endpoints: Arc::default(),
+            connect: Arc::new(connect),
+        }
+    }
+
+    fn spawn_handle(
+        &self,
+        endpoint: SocketAddr,
+        params: ModbusMasterConnectionParams,
+    ) -> ModbusMasterHandle {
+        let mut master = (self.connect)(endpoint);
+        master.set_params(params);
+        ModbusMasterHandle::spawn(master)
+    }
+
+    //Replaces the connection of an endpoint that already existed, its devices are kept
+    pub fn add_endpoint(&self, endpoint: SocketAddr, params: ModbusMasterConnectionParams) {
+        let handle = self.spawn_handle(endpoint, params);
+
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let unit_ids = endpoints
+            .remove(&endpoint)
+            .map(|old| old.unit_ids)
+            .unwrap_or_default();
+        endpoints.insert(endpoint, Endpoint { handle, unit_ids });
+    }
+
+    //Endpoints that weren't added before get the default params
+    pub fn add_device(&self, endpoint: SocketAddr, unit_id: SlaveId) {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let entry = endpoints.entry(endpoint).or_insert_with(|| Endpoint {
+            handle: self.spawn_handle(endpoint, ModbusMasterConnectionParams::default()),
+            unit_ids: HashSet::new(),
+        });
+        entry.unit_ids.insert(unit_id);
+    }
+
+    //The connection of an endpoint is closed once its last device is removed
+    pub fn remove_device(&self, endpoint: SocketAddr, unit_id: SlaveId) {
+        let mut endpoints = self.endpoints.lock().unwrap();
+
+        if let Some(entry) = endpoints.get_mut(&endpoint) {
+            entry.unit_ids.remove(&unit_id);
+            if entry.unit_ids.is_empty() {
+                endpoints.remove(&endpoint);
+            }
+        }
+    }
+
+    pub fn remove_endpoint(&self, endpoint: SocketAddr) {
+        self.endpoints.lock().unwrap().remove(&endpoint);
+    }
+
+    pub fn devices(&self) -> Vec<(SocketAddr, SlaveId)> {
+        let endpoints = self.endpoints.lock().unwrap();
+        let mut devices: Vec<(SocketAddr, SlaveId)> = endpoints
+            .iter()
+            .flat_map(|(endpoint, entry)| {
+                entry
+                    .unit_ids
+                    .iter()
+                    .map(move |unit_id| (*endpoint, *unit_id))
+            })
+            .collect();
+        devices.sort();
+        devices
+    }
+
+    //Handle to the device over the connection serving its endpoint
+    pub fn route(
+        &self,
+        endpoint: SocketAddr,
+        unit_id: SlaveId,
+    ) -> Result<ModbusDeviceHandle, ModbusError> {
+        self.endpoints
+            .lock()
+            .unwrap()
+            .get(&endpoint)
+            .filter(|entry| entry.unit_ids.contains(&unit_id))
+            .map(|entry| ModbusDeviceHandle {
+                handle: entry.handle.clone(),
+                unit_id,
+            })
+            .ok_or_else(|| {
+                ModbusError::InvalidQuery(format!(
+                    "Device {} at {} isn't part of the pool",
+                    unit_id, endpoint
+                ))
+            })
+    }
+
+    //Runs the request against every device at once and collects the results by device
+    pub async fn query_all<F, Fut, T>(&self, request: F) -> HashMap<(SocketAddr, SlaveId), T>
+    where
+        F: Fn(ModbusDeviceHandle) -> Fut,
+        Fut: Future<Output = T>,
+    {
+        let routes: Vec<((SocketAddr, SlaveId), ModbusDeviceHandle)> = {
+            let endpoints = self.endpoints.lock().unwrap();
+            endpoints
+                .iter()
+                .flat_map(|(endpoint, entry)| {
+                    entry.unit_ids.iter().map(move |unit_id| {
+                        let device = ModbusDeviceHandle {
+                            handle: entry.handle.clone(),
+                            unit_id: *unit_id,
+                        };
+                        ((*endpoint, *unit_id), device)
+                    })
+                })
+                .collect()
+        };
+
+        let results = join_all(routes.iter().map(|(_, device)| request(device.clone()))).await;
+
+        routes
+            .into_iter()
+            .map(|(device, _)| device)
+            .zip(results)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::master::test_utils::{spawn_slave, MemoryCallBack};
+    use crate::slave::ModbusSlaveConnection;
+
+    #[tokio::test]
+    async fn test_requests_are_routed_by_device() {
+        let first = spawn_slave().await;
+        let second = spawn_slave().await;
+
+        let pool = ModbusMasterPool::new();
+        pool.add_device(first, 1);
+        pool.add_device(first, 2);
+        pool.add_endpoint(
+            second,
+            ModbusMasterConnectionParams {
+                max_simultaneous_transactions: 4,
+                ..Default::default()
+            },
+        );
+        pool.add_device(second, 1);
+        //Devices are listed in address order
+        let mut devices = vec![(first, 1), (first, 2), (second, 1)];
+        devices.sort();
+        assert_eq!(pool.devices(), devices);
+
+        let device = pool.route(second, 1).unwrap();
+        assert_eq!(device.unit_id(), 1);
+        assert_eq!(device.read_holding_registers(3, 1).await, Ok(vec![30]));
+        assert!(pool.route(second, 2).is_err());
+
+        let results = pool
+            .query_all(|device| async move { device.read_holding_registers(2, 1).await })
+            .await;
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[&(first, 1)], Ok(vec![20]));
+        assert_eq!(results[&(second, 1)], Ok(vec![20]));
+        //The test slaves only know about unit 1
+        assert!(results[&(first, 2)].is_err());
+
+        pool.remove_device(first, 1);
+        pool.remove_device(first, 2);
+        assert!(pool.route(first, 1).is_err());
+        assert_eq!(pool.devices(), vec![(second, 1)]);
+    }
+
+    #[tokio::test]
+    async fn test_endpoints_use_the_connector() {
+        let callback = MemoryCallBack::with_test_registers();
+        let mut slave =
+            ModbusSlaveConnection::new_udp("127.0.0.1:0".parse().unwrap(), Box::new(callback));
+        slave.bind().await.unwrap();
+        let endpoint = slave.local_addr().unwrap();
+        tokio::spawn(async move { slave.serve().await });
+
+        let pool = ModbusMasterPool::with_connector(ModbusMasterConnection::new_udp);
+        pool.add_device(endpoint, 1);
+
+        let device = pool.route(endpoint, 1).unwrap();
+        assert_eq!(device.read_holding_registers(4, 2).await, Ok(vec![40, 50]));
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use crate::common::SlaveId;
+use crate::master::context::{BitOperation, QueuedQuery};
+use crate::messages::ModbusQuery;
+
+//Higher priorities always go first, within a priority queries are sent in the order they were added
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    //Background work like polling
+    Low,
+    #[default]
+    Normal,
+    //Control writes
+    High,
+}
+
+impl Priority {
+    //Writes, including the ones built on top of register reads, go before reads
+    pub fn for_query(query: &QueuedQuery) -> Self {
+        match (&query.query, query.bit_operation) {
+            (_, Some(BitOperation::Read { .. })) => Priority::Normal,
+            (_, Some(_)) => Priority::High,
+            (ModbusQuery::ReadQuery { .. }, None) => Priority::Normal,
+            _ => Priority::High,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct QueryQueue {
+    queues: BTreeMap<Priority, VecDeque<QueuedQuery>>,
+    //Picks the unit that was served the longest ago instead of going in plain FIFO order
+    fair: bool,
+    //Turn at which each unit last got a query sent, units never served go first
+    last_served: HashMap<SlaveId, u64>,
+    turn: u64,
+}
+
+impl QueryQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_fair(&mut self, fair: bool) {
+        self.fair = fair;
+    }
+
+    pub fn push(&mut self, query: QueuedQuery) {
+        self.queues
+            .entry(query.priority)
+            .or_default()
+            .push_back(query);
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.queues.values().map(VecDeque::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.values().all(VecDeque::is_empty)
+    }
+
+    //Highest priority first, in the order they will be considered
+    pub fn iter(&self) -> impl Iterator<Item = &QueuedQuery> {
+        self.queues.values().rev().flatten()
+    }
+
+    pub fn take_all(&mut self) -> Vec<QueuedQuery> {
+        std::mem::take(&mut self.queues)
+            .into_values()
+            .rev()
+            .flatten()
+            .collect()
+    }
+
+    //Takes the next query that may be sent, the ones that can't go yet don't hold up the rest
+    pub fn pop(&mut self, can_send: impl Fn(&QueuedQuery) -> bool) -> Option<QueuedQuery> {
+        let (priority, position) = self.queues.iter().rev().find_map(|(priority, queue)| {
+            let mut candidates = queue
+                .iter()
+                .enumerate()
+                .filter(|(_, query)| can_send(query));
+
+            let position = if self.fair {
+                candidates
+                    .min_by_key(|(position, query)| (self.last_served_turn(query), *position))
+                    .map(|(position, _)| position)
+            } else {
+                candidates.next().map(|(position, _)| position)
+            };
+
+            position.map(|position| (*priority, position))
+        })?;
+
+        let query = self.queues.get_mut(&priority)?.remove(position)?;
+        self.turn += 1;
+        self.last_served
+            .insert(query.query.get_message_data().slave_id, self.turn);
+        Some(query)
+    }
+
+    fn last_served_turn(&self, query: &QueuedQuery) -> u64 {
+        let slave_id = query.query.get_message_data().slave_id;
+        self.last_served.get(&slave_id).copied().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::{ModbusDataType, ModbusTable};
+    use crate::messages::query::{ReadQueryParameters, SingleWriteQueryParameters};
+    use crate::messages::{FunctionCode, ModbusMessageData};
+    use std::cell::Cell;
+
+    fn message_data(slave_id: SlaveId, function_code: FunctionCode) -> ModbusMessageData {
+        ModbusMessageData {
+            slave_id,
+            function_code,
+            transaction_id: Cell::new(None),
+        }
+    }
+
+    fn read(slave_id: SlaveId, starting_address: u16) -> QueuedQuery {
+        let mut query = QueuedQuery::new(ModbusQuery::ReadQuery {
+            message_data: message_data(slave_id, FunctionCode::ReadMultipleHoldingRegister),
+            params: ReadQueryParameters {
+                table: ModbusTable::HoldingRegisters,
+                starting_address,
+                ammount: 1,
+            },
+        });
+        query.priority = Priority::for_query(&query);
+        query
+    }
+
+    fn write(slave_id: SlaveId, starting_address: u16) -> QueuedQuery {
+        let mut query = QueuedQuery::new(ModbusQuery::SingleWriteQuery {
+            message_data: message_data(slave_id, FunctionCode::WriteSingleHoldingRegister),
+            params: SingleWriteQueryParameters {
+                table: ModbusTable::HoldingRegisters,
+                starting_address,
+                value: ModbusDataType::Register(0),
+            },
+        });
+        query.priority = Priority::for_query(&query);
+        query
+    }
+
+    fn address(query: &QueuedQuery) -> (SlaveId, u16) {
+        let slave_id = query.query.get_message_data().slave_id;
+        match &query.query {
+            ModbusQuery::ReadQuery { params, .. } => (slave_id, params.starting_address),
+            ModbusQuery::SingleWriteQuery { params, .. } => (slave_id, params.starting_address),
+            _ => unreachable!(),
+        }
+    }
+
+    fn drain(queue: &mut QueryQueue) -> Vec<(SlaveId, u16)> {
+        std::iter::from_fn(|| queue.pop(|_| true))
+            .map(|query| address(&query))
+            .collect()
+    }
+
+    #[test]
+    fn test_writes_go_first_and_fifo_within_priority() {
+        let mut queue = QueryQueue::new();
+        queue.push(read(1, 0));
+        queue.push(read(1, 1));
+        queue.push(write(1, 2));
+        let mut background = read(1, 3);
+        background.priority = Priority::Low;
+        queue.push(background);
+        queue.push(write(1, 4));
+        queue.push(read(1, 5));
+
+        assert_eq!(queue.len(), 6);
+        assert_eq!(
+            drain(&mut queue),
+            vec![(1, 2), (1, 4), (1, 0), (1, 1), (1, 5), (1, 3)]
+        );
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_blocked_query_does_not_hold_up_others() {
+        let mut queue = QueryQueue::new();
+        queue.push(write(1, 0));
+        queue.push(read(1, 1));
+
+        let query = queue.pop(|query| address(query) != (1, 0)).unwrap();
+        assert_eq!(address(&query), (1, 1));
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn test_fair_queue_alternates_units() {
+        let mut queue = QueryQueue::new();
+        for address in 0..3 {
+            queue.push(read(1, address));
+        }
+        queue.push(read(2, 0));
+        queue.push(read(3, 0));
+
+        queue.set_fair(true);
+        assert_eq!(
+            drain(&mut queue),
+            vec![(1, 0), (2, 0), (3, 0), (1, 1), (1, 2)]
+        );
+    }
+}
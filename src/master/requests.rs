@@ -0,0 +1,646 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+
+use crate::common::{
+    Address, ModbusAddress, ModbusDataType, ModbusError, ModbusResult, ModbusTable, Quality,
+    SlaveId,
+};
+use crate::master::context::{QueryId, QueuedQuery};
+use crate::master::ModbusMasterConnection;
+use crate::messages::query::MaskWriteQueryParameters;
+use crate::messages::{FunctionCode, ModbusMessageData, ModbusQuery};
+
+pub(super) type ModbusResultMap = HashMap<ModbusAddress, ModbusResult>;
+
+pub(super) fn invalid_query(err: anyhow::Error) -> ModbusError {
+    ModbusError::InvalidQuery(err.to_string())
+}
+
+pub(super) fn addresses(
+    slave_id: SlaveId,
+    table: ModbusTable,
+    address: Address,
+    ammount: u16,
+) -> impl Iterator<Item = ModbusAddress> {
+    (address..address.saturating_add(ammount))
+        .map(move |address| ModbusAddress::new(slave_id, table, address))
+}
+
+//Late values are still the values the device sent, so stale results are read too
+pub(super) fn read_values(
+    results: &ModbusResultMap,
+    addresses: impl Iterator<Item = ModbusAddress>,
+) -> Result<Vec<ModbusDataType>, ModbusError> {
+    addresses
+        .map(|address| match results.get(&address) {
+            Some(ModbusResult {
+                value: Some(value), ..
+            }) => Ok(*value),
+            Some(ModbusResult {
+                quality: Quality::Exception(exception_code),
+                ..
+            }) => Err(ModbusError::Exception(*exception_code)),
+            _ => Err(ModbusError::Protocol(format!(
+                "No value was received for address {}",
+                address.address
+            ))),
+        })
+        .collect()
+}
+
+pub(super) fn check_written(
+    results: &ModbusResultMap,
+    addresses: impl Iterator<Item = ModbusAddress>,
+) -> Result<(), ModbusError> {
+    for address in addresses {
+        match results.get(&address) {
+            Some(result) if result.is_good() => {}
+            Some(ModbusResult {
+                quality: Quality::Exception(exception_code),
+                ..
+            }) => return Err(ModbusError::Exception(*exception_code)),
+            Some(ModbusResult {
+                quality: Quality::VerifyMismatch { expected, actual },
+                ..
+            }) => {
+                return Err(ModbusError::VerifyMismatch {
+                    address: address.address,
+                    expected: *expected,
+                    actual: *actual,
+                })
+            }
+            _ => {
+                return Err(ModbusError::Protocol(format!(
+                    "No write confirmation was received for address {}",
+                    address.address
+                )))
+            }
+        }
+    }
+    Ok(())
+}
+
+pub(super) fn to_coils(values: Vec<ModbusDataType>) -> Result<Vec<bool>, ModbusError> {
+    values
+        .into_iter()
+        .map(|value| match value {
+            ModbusDataType::Coil(value) => Ok(value),
+            ModbusDataType::Register(_) => Err(ModbusError::Protocol(
+                "Expected a coil, got a register".to_string(),
+            )),
+        })
+        .collect()
+}
+
+pub(super) fn to_registers(values: Vec<ModbusDataType>) -> Result<Vec<u16>, ModbusError> {
+    values
+        .into_iter()
+        .map(|value| match value {
+            ModbusDataType::Register(value) => Ok(value),
+            ModbusDataType::Coil(_) => Err(ModbusError::Protocol(
+                "Expected a register, got a coil".to_string(),
+            )),
+        })
+        .collect()
+}
+
+pub(super) type Enqueue =
+    Box<dyn FnOnce(&mut ModbusMasterConnection) -> anyhow::Result<QueryId> + Send>;
+type Answer<T> = Box<dyn FnOnce(&ModbusResultMap) -> Result<T, ModbusError> + Send>;
+
+//A call of the request/response API, the query it adds and how its answer is taken from the
+//results. The master and its handles run the same requests
+pub(super) struct Request<T> {
+    pub enqueue: Enqueue,
+    pub answer: Answer<T>,
+}
+
+impl<T: 'static> Request<T> {
+    fn map<U: 'static>(self, convert: fn(T) -> Result<U, ModbusError>) -> Request<U> {
+        let answer = self.answer;
+        Request {
+            enqueue: self.enqueue,
+            answer: Box::new(move |results| answer(results).and_then(convert)),
+        }
+    }
+}
+
+fn read(
+    slave_id: SlaveId,
+    table: ModbusTable,
+    address: Address,
+    ammount: u16,
+    function_code: FunctionCode,
+) -> Request<Vec<ModbusDataType>> {
+    Request {
+        enqueue: Box::new(move |master| {
+            master.add_read_query(slave_id, address, ammount, function_code)
+        }),
+        answer: Box::new(move |results| {
+            read_values(results, addresses(slave_id, table, address, ammount))
+        }),
+    }
+}
+
+fn write(
+    slave_id: SlaveId,
+    table: ModbusTable,
+    address: Address,
+    ammount: u16,
+    enqueue: Enqueue,
+) -> Request<()> {
+    Request {
+        enqueue,
+        answer: Box::new(move |results| {
+            check_written(results, addresses(slave_id, table, address, ammount))
+        }),
+    }
+}
+
+fn read_bit(
+    slave_id: SlaveId,
+    table: ModbusTable,
+    address: Address,
+    bit: u8,
+    function_code: FunctionCode,
+) -> Request<bool> {
+    Request {
+        enqueue: Box::new(move |master| {
+            master.add_read_bit_query(slave_id, address, bit, function_code)
+        }),
+        answer: Box::new(move |results| {
+            let address = ModbusAddress::new_bit(slave_id, table, address, bit);
+            let values = read_values(results, std::iter::once(address))?;
+            to_coils(values).map(|values| values[0])
+        }),
+    }
+}
+
+pub(super) fn read_coils(slave_id: SlaveId, address: Address, ammount: u16) -> Request<Vec<bool>> {
+    read(slave_id, ModbusTable::Coils, address, ammount, FunctionCode::ReadCoils).map(to_coils)
+}
+
+pub(super) fn read_discrete_inputs(
+    slave_id: SlaveId,
+    address: Address,
+    ammount: u16,
+) -> Request<Vec<bool>> {
+    read(
+        slave_id,
+        ModbusTable::DiscreteInput,
+        address,
+        ammount,
+        FunctionCode::ReadDiscreteInputs,
+    )
+    .map(to_coils)
+}
+
+pub(super) fn read_holding_registers(
+    slave_id: SlaveId,
+    address: Address,
+    ammount: u16,
+) -> Request<Vec<u16>> {
+    read(
+        slave_id,
+        ModbusTable::HoldingRegisters,
+        address,
+        ammount,
+        FunctionCode::ReadMultipleHoldingRegister,
+    )
+    .map(to_registers)
+}
+
+pub(super) fn read_input_registers(
+    slave_id: SlaveId,
+    address: Address,
+    ammount: u16,
+) -> Request<Vec<u16>> {
+    read(
+        slave_id,
+        ModbusTable::InputRegisters,
+        address,
+        ammount,
+        FunctionCode::ReadInputRegisters,
+    )
+    .map(to_registers)
+}
+
+pub(super) fn write_single_coil(slave_id: SlaveId, address: Address, value: bool) -> Request<()> {
+    let enqueue: Enqueue = Box::new(move |master| {
+        master.add_single_write_query(
+            slave_id,
+            address,
+            ModbusDataType::Coil(value),
+            FunctionCode::WriteSingleCoil,
+        )
+    });
+    write(slave_id, ModbusTable::Coils, address, 1, enqueue)
+}
+
+pub(super) fn write_single_register(slave_id: SlaveId, address: Address, value: u16) -> Request<()> {
+    let enqueue: Enqueue = Box::new(move |master| {
+        master.add_single_write_query(
+            slave_id,
+            address,
+            ModbusDataType::Register(value),
+            FunctionCode::WriteSingleHoldingRegister,
+        )
+    });
+    write(slave_id, ModbusTable::HoldingRegisters, address, 1, enqueue)
+}
+
+pub(super) fn write_multiple_coils(
+    slave_id: SlaveId,
+    address: Address,
+    values: Vec<bool>,
+) -> Request<()> {
+    let ammount = values.len() as u16;
+    let enqueue: Enqueue = Box::new(move |master| {
+        master.add_multiple_write_query(
+            slave_id,
+            address,
+            values.into_iter().map(ModbusDataType::Coil).collect(),
+            FunctionCode::WriteMultipleCoils,
+        )
+    });
+    write(slave_id, ModbusTable::Coils, address, ammount, enqueue)
+}
+
+pub(super) fn write_multiple_registers(
+    slave_id: SlaveId,
+    address: Address,
+    values: Vec<u16>,
+) -> Request<()> {
+    let ammount = values.len() as u16;
+    let enqueue: Enqueue = Box::new(move |master| {
+        master.add_multiple_write_query(
+            slave_id,
+            address,
+            values.into_iter().map(ModbusDataType::Register).collect(),
+            FunctionCode::WriteMultipleHoldingRegisters,
+        )
+    });
+    write(slave_id, ModbusTable::HoldingRegisters, address, ammount, enqueue)
+}
+
+pub(super) fn read_write_multiple_registers(
+    slave_id: SlaveId,
+    read_starting_address: Address,
+    read_ammount: u16,
+    write_starting_address: Address,
+    values: Vec<u16>,
+) -> Request<Vec<u16>> {
+    Request {
+        enqueue: Box::new(move |master| {
+            master.add_multiple_read_write_query(
+                slave_id,
+                read_starting_address,
+                read_ammount,
+                write_starting_address,
+                values.into_iter().map(ModbusDataType::Register).collect(),
+                FunctionCode::ReadWriteMultipleRegisters,
+            )
+        }),
+        answer: Box::new(move |results| {
+            let addresses = addresses(
+                slave_id,
+                ModbusTable::HoldingRegisters,
+                read_starting_address,
+                read_ammount,
+            );
+            read_values(results, addresses)
+        }),
+    }
+    .map(to_registers)
+}
+
+pub(super) fn mask_write_register(
+    slave_id: SlaveId,
+    address: Address,
+    and_mask: u16,
+    or_mask: u16,
+) -> Request<()> {
+    let enqueue: Enqueue = Box::new(move |master| {
+        Ok(master.add_mask_write_query(slave_id, address, and_mask, or_mask))
+    });
+    write(slave_id, ModbusTable::HoldingRegisters, address, 1, enqueue)
+}
+
+pub(super) fn read_holding_register_bit(
+    slave_id: SlaveId,
+    address: Address,
+    bit: u8,
+) -> Request<bool> {
+    read_bit(
+        slave_id,
+        ModbusTable::HoldingRegisters,
+        address,
+        bit,
+        FunctionCode::ReadMultipleHoldingRegister,
+    )
+}
+
+pub(super) fn read_input_register_bit(
+    slave_id: SlaveId,
+    address: Address,
+    bit: u8,
+) -> Request<bool> {
+    read_bit(
+        slave_id,
+        ModbusTable::InputRegisters,
+        address,
+        bit,
+        FunctionCode::ReadInputRegisters,
+    )
+}
+
+pub(super) fn write_holding_register_bit(
+    slave_id: SlaveId,
+    address: Address,
+    bit: u8,
+    value: bool,
+) -> Request<()> {
+    Request {
+        enqueue: Box::new(move |master| {
+            master.add_write_bit_query(slave_id, address, bit, value)
+        }),
+        answer: Box::new(move |results| {
+            let address =
+                ModbusAddress::new_bit(slave_id, ModbusTable::HoldingRegisters, address, bit);
+            check_written(results, std::iter::once(address))
+        }),
+    }
+}
+
+//Every call of the request/response API, handed to a macro that writes the methods running them.
+//The master, its handles and the devices of a pool are all generated from it
+macro_rules! request_methods {
+    ($generate:ident) => {
+        $generate! {
+            fn read_coils(slave_id: SlaveId, address: Address, ammount: u16) -> Vec<bool>;
+            fn read_discrete_inputs(slave_id: SlaveId, address: Address, ammount: u16) -> Vec<bool>;
+            fn read_holding_registers(slave_id: SlaveId, address: Address, ammount: u16) -> Vec<u16>;
+            fn read_input_registers(slave_id: SlaveId, address: Address, ammount: u16) -> Vec<u16>;
+            fn write_single_coil(slave_id: SlaveId, address: Address, value: bool) -> ();
+            fn write_single_register(slave_id: SlaveId, address: Address, value: u16) -> ();
+            fn write_multiple_coils(slave_id: SlaveId, address: Address, values: Vec<bool>) -> ();
+            fn write_multiple_registers(slave_id: SlaveId, address: Address, values: Vec<u16>) -> ();
+            fn read_write_multiple_registers(slave_id: SlaveId, read_starting_address: Address, read_ammount: u16, write_starting_address: Address, values: Vec<u16>) -> Vec<u16>;
+            fn mask_write_register(slave_id: SlaveId, address: Address, and_mask: u16, or_mask: u16) -> ();
+            fn read_holding_register_bit(slave_id: SlaveId, address: Address, bit: u8) -> bool;
+            fn read_input_register_bit(slave_id: SlaveId, address: Address, bit: u8) -> bool;
+            fn write_holding_register_bit(slave_id: SlaveId, address: Address, bit: u8, value: bool) -> ();
+        }
+    };
+}
+pub(super) use request_methods;
+
+//Every call sends its own query and waits for its answer. Queries added through the batch API
+//can go out meanwhile, their results are kept for query()
+macro_rules! master_requests {
+    ($(fn $name:ident($($arg:ident: $ty:ty),*) -> $ret:ty;)*) => {
+        impl ModbusMasterConnection {
+            $(
+                pub async fn $name(&mut self, $($arg: $ty),*) -> Result<$ret, ModbusError> {
+                    self.request($name($($arg),*)).await
+                }
+            )*
+        }
+    };
+}
+
+request_methods!(master_requests);
+
+impl ModbusMasterConnection {
+    async fn request<T: 'static>(&mut self, request: Request<T>) -> Result<T, ModbusError> {
+        let id = (request.enqueue)(self).map_err(invalid_query)?;
+        let results = self.execute(id).await?;
+        (request.answer)(&results)
+    }
+
+    pub(super) fn add_mask_write_query(
+        &mut self,
+        slave_id: SlaveId,
+        address: Address,
+        and_mask: u16,
+        or_mask: u16,
+    ) -> QueryId {
+        let query = ModbusQuery::MaskWriteQuery {
+            message_data: ModbusMessageData {
+                slave_id,
+                function_code: FunctionCode::MaskWriteRegister,
+                transaction_id: Cell::new(None),
+            },
+            params: MaskWriteQueryParameters {
+                table: ModbusTable::HoldingRegisters,
+                address,
+                and_mask,
+                or_mask,
+            },
+        };
+
+        self.enqueue(QueuedQuery::new(query))
+    }
+
+    pub fn add_mask_write_register_query(
+        &mut self,
+        slave_id: SlaveId,
+        address: Address,
+        and_mask: u16,
+        or_mask: u16,
+    ) -> anyhow::Result<()> {
+        self.add_mask_write_query(slave_id, address, and_mask, or_mask);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::ModbusSubprotocol;
+    use crate::master::test_utils::{connect, MemoryCallBack};
+    use crate::slave::ModbusSlaveConnection;
+    use tokio::time::Duration;
+    use crate::master::{ModbusMasterConnectionParams, QueryOptions};
+    use crate::messages::ExceptionCode;
+
+    #[tokio::test]
+    async fn test_read_and_write_registers() {
+        let mut master = connect().await;
+
+        assert_eq!(master.read_holding_registers(1, 2, 3).await, Ok(vec![20, 30, 40]));
+
+        master.write_multiple_registers(1, 2, vec![7, 8]).await.unwrap();
+        master.write_single_register(1, 4, 9).await.unwrap();
+
+        assert_eq!(master.read_holding_registers(1, 2, 3).await, Ok(vec![7, 8, 9]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mask_write_against_a_server() {
+        let server =
+            ModbusSlaveConnection::new_in_memory(Box::new(MemoryCallBack::with_test_registers()));
+        let mut master =
+            ModbusMasterConnection::new_in_memory(&server, ModbusSubprotocol::ModbusTCP, Duration::ZERO);
+
+        //20 is 0b10100, the low nibble is replaced and the rest kept
+        master.mask_write_register(1, 2, 0xFFF0, 0x0003).await.unwrap();
+        assert_eq!(master.read_holding_registers(1, 2, 1).await, Ok(vec![19]));
+
+        master.write_holding_register_bit(1, 3, 15, true).await.unwrap();
+        master.write_holding_register_bit(1, 3, 1, true).await.unwrap();
+        assert_eq!(master.read_holding_registers(1, 3, 1).await, Ok(vec![0x8000 | 30]));
+
+        assert_eq!(
+            master.mask_write_register(1, 50, 0, 1).await,
+            Err(ModbusError::Exception(ExceptionCode::IllegalDataAddress))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_options_for_a_single_call() {
+        use crate::master::{Backoff, RetryPolicy};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        struct BusyCallBack {
+            writes: Arc<AtomicUsize>,
+        }
+
+        #[async_trait::async_trait]
+        impl crate::slave::ModbusCallBack for BusyCallBack {
+            async fn on_read(&self, _addr: ModbusAddress) -> Result<ModbusDataType, ExceptionCode> {
+                Err(ExceptionCode::ServerDeviceBusy)
+            }
+
+            async fn on_write(&self, _addr: ModbusAddress, _value: ModbusDataType) -> Result<(), ExceptionCode> {
+                self.writes.fetch_add(1, Ordering::SeqCst);
+                Err(ExceptionCode::ServerDeviceBusy)
+            }
+        }
+
+        let writes = Arc::new(AtomicUsize::new(0));
+        let server =
+            ModbusSlaveConnection::new_in_memory(Box::new(BusyCallBack { writes: writes.clone() }));
+        let mut master =
+            ModbusMasterConnection::new_in_memory(&server, ModbusSubprotocol::ModbusTCP, Duration::ZERO);
+        master.set_params(ModbusMasterConnectionParams {
+            retry_policy: RetryPolicy::new(3, Backoff::Fixed(Duration::ZERO)),
+            ..Default::default()
+        });
+
+        let busy = Err(ModbusError::Exception(ExceptionCode::ServerDeviceBusy));
+        assert_eq!(master.write_single_register(1, 0, 5).await, busy);
+        assert_eq!(writes.swap(0, Ordering::SeqCst), 3);
+
+        //A write that mustn't be sent twice opts out of the retries on its own
+        let once = QueryOptions {
+            retry_policy: Some(RetryPolicy::none()),
+            ..Default::default()
+        };
+        assert_eq!(master.with_options(once).write_single_register(1, 0, 5).await, busy);
+        assert_eq!(writes.swap(0, Ordering::SeqCst), 1);
+
+        assert_eq!(master.get_query_options(), QueryOptions::default());
+        assert_eq!(master.write_single_register(1, 0, 5).await, busy);
+        assert_eq!(writes.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_exception_is_reported() {
+        let mut master = connect().await;
+
+        assert_eq!(
+            master.read_holding_registers(1, 8, 5).await,
+            Err(ModbusError::Exception(ExceptionCode::IllegalDataAddress))
+        );
+        assert_eq!(master.read_holding_registers(1, 0, 1).await, Ok(vec![0]));
+    }
+
+    #[tokio::test]
+    async fn test_ranges_past_the_last_address_are_refused() {
+        let mut master = connect().await;
+
+        assert!(master.add_read_holding_registers_query(1, 65500, 200).is_err());
+        assert!(master
+            .add_write_multiple_holding_registers_query(1, 65500, vec![0; 200])
+            .is_err());
+        assert!(master
+            .add_multiple_read_write_holding_registers_query(1, 0, 1, 65535, vec![0, 0])
+            .is_err());
+        assert!(matches!(
+            master.read_coils(1, 65500, 200).await,
+            Err(ModbusError::InvalidQuery(_))
+        ));
+
+        //The last address itself can be reached
+        master.add_read_holding_registers_query(1, 65336, 200).unwrap();
+        master
+            .add_write_multiple_holding_registers_query(1, 65336, vec![0; 200])
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_response_arriving_across_the_deadline_is_kept() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut master = ModbusMasterConnection::new_tcp(listener.local_addr().unwrap());
+        master.set_params(ModbusMasterConnectionParams {
+            max_response_time: Duration::from_millis(100),
+            ..Default::default()
+        });
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 12];
+            stream.read_exact(&mut request).await.unwrap();
+
+            //The second half comes after the deadline, but before the line goes silent
+            let response = [request[0], request[1], 0, 0, 0, 5, 1, 0x03, 0x02, 0x00, 10];
+            tokio::time::sleep(Duration::from_millis(80)).await;
+            stream.write_all(&response[..6]).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            stream.write_all(&response[6..]).await.unwrap();
+            stream
+        });
+
+        assert_eq!(master.read_holding_registers(1, 0, 1).await, Ok(vec![10]));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_batch_queries_are_kept_apart() {
+        let mut master = connect().await;
+
+        master.add_read_holding_registers_query(1, 0, 2).unwrap();
+        assert_eq!(master.read_holding_registers(1, 5, 1).await, Ok(vec![50]));
+
+        let results = master.query().await.unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(
+            results[&ModbusAddress::new(1, ModbusTable::HoldingRegisters, 1)].value,
+            Some(ModbusDataType::Register(10))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_batch() {
+        let mut master = connect().await;
+        master.set_params(ModbusMasterConnectionParams {
+            max_simultaneous_transactions: 4,
+            ..Default::default()
+        });
+
+        for address in 0..10 {
+            master.add_read_holding_registers_query(1, address, 1).unwrap();
+        }
+
+        let results = master.query().await.unwrap();
+        assert_eq!(results.len(), 10);
+        assert_eq!(
+            results[&ModbusAddress::new(1, ModbusTable::HoldingRegisters, 9)].value,
+            Some(ModbusDataType::Register(90))
+        );
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+use tokio::time::Duration;
+
+use crate::common::ModbusError;
+use crate::messages::ExceptionCode;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backoff {
+    Fixed(Duration),
+    //The delay doubles after every attempt, up to max
+    Exponential { initial: Duration, max: Duration },
+}
+
+impl Backoff {
+    //Delay after `attempts` failed tries, jitter is the fraction of it that is randomised
+    pub fn delay(&self, attempts: u32, jitter: f64) -> Duration {
+        let delay = match *self {
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential { initial, max } => {
+                let exponent = attempts.saturating_sub(1).min(31);
+                initial.saturating_mul(1 << exponent).min(max)
+            }
+        };
+
+        let jitter = jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return delay;
+        }
+
+        delay.mul_f64(1.0 - jitter * random_fraction())
+    }
+}
+
+//Which failures are worth sending a query again for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryOn {
+    pub timeout: bool,
+    pub connection_error: bool,
+    pub server_device_busy: bool,
+    pub acknowledge: bool,
+    pub gateway_error: bool,
+}
+
+impl RetryOn {
+    pub fn all() -> Self {
+        RetryOn {
+            timeout: true,
+            connection_error: true,
+            server_device_busy: true,
+            acknowledge: true,
+            gateway_error: true,
+        }
+    }
+
+    pub fn matches(&self, error: &ModbusError) -> bool {
+        match error {
+            ModbusError::Timeout => self.timeout,
+            ModbusError::Connection(_) => self.connection_error,
+            ModbusError::Exception(ExceptionCode::ServerDeviceBusy) => self.server_device_busy,
+            ModbusError::Exception(ExceptionCode::Acknowledge) => self.acknowledge,
+            ModbusError::Exception(
+                ExceptionCode::GatewayPathUnavailable
+                | ExceptionCode::GatewayTargetDeviceFailedToRespond,
+            ) => self.gateway_error,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    //Total times a query may be sent, 1 means it is never retried
+    pub max_attempts: u32,
+    pub backoff: Backoff,
+    //Fraction of every delay that is randomised, between 0 and 1
+    pub jitter: f64,
+    pub retry_on: RetryOn,
+}
+
+impl RetryPolicy {
+    //Queries are sent once and every failure is reported right away
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            backoff: Backoff::Fixed(Duration::ZERO),
+            jitter: 0.0,
+            retry_on: RetryOn::all(),
+        }
+    }
+
+    pub fn new(max_attempts: u32, backoff: Backoff) -> Self {
+        RetryPolicy {
+            max_attempts,
+            backoff,
+            jitter: 0.0,
+            retry_on: RetryOn::all(),
+        }
+    }
+
+    pub fn should_retry(&self, attempts: u32, error: &ModbusError) -> bool {
+        attempts < self.max_attempts && self.retry_on.matches(error)
+    }
+
+    //Time to wait before sending a query again after it was sent `attempts` times
+    pub fn delay(&self, attempts: u32) -> Duration {
+        self.backoff.delay(attempts, self.jitter)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::none()
+    }
+}
+
+//Random number in [0, 1), std hashers are randomly seeded so no extra dependency is needed
+fn random_fraction() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_exponential_backoff_is_capped() {
+        let policy = RetryPolicy::new(
+            10,
+            Backoff::Exponential {
+                initial: Duration::from_millis(100),
+                max: Duration::from_secs(1),
+            },
+        );
+
+        let delays: Vec<Duration> = (1..6).map(|attempts| policy.delay(attempts)).collect();
+
+        assert_eq!(
+            delays,
+            [100, 200, 400, 800, 1000]
+                .map(Duration::from_millis)
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn test_jitter_only_shortens_delay() {
+        let policy = RetryPolicy {
+            jitter: 0.5,
+            ..RetryPolicy::new(3, Backoff::Fixed(Duration::from_millis(100)))
+        };
+
+        for _ in 0..100 {
+            let delay = policy.delay(1);
+            assert!(delay > Duration::from_millis(50) && delay <= Duration::from_millis(100));
+        }
+    }
+
+    #[test]
+    fn test_retryable_errors() {
+        let policy = RetryPolicy {
+            retry_on: RetryOn {
+                timeout: false,
+                ..RetryOn::all()
+            },
+            ..RetryPolicy::new(2, Backoff::Fixed(Duration::ZERO))
+        };
+
+        assert!(policy.should_retry(1, &ModbusError::Exception(ExceptionCode::ServerDeviceBusy)));
+        assert!(!policy.should_retry(2, &ModbusError::Exception(ExceptionCode::ServerDeviceBusy)));
+        assert!(!policy.should_retry(1, &ModbusError::Timeout));
+        assert!(!policy.should_retry(
+            1,
+            &ModbusError::Exception(ExceptionCode::IllegalDataAddress)
+        ));
+    }
+}
@@ -0,0 +1,423 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use futures::stream::{self, StreamExt, TryStreamExt};
+
+use crate::common::{Address, ModbusError, ModbusTable, SlaveId};
+use crate::master::{
+    ModbusMasterConnection, ModbusMasterHandle, Pacing, MAX_READ_COILS, MAX_READ_REGISTERS,
+};
+use crate::messages::ExceptionCode;
+
+//What a scan looks for. Units are found with a plain read, this crate doesn't send
+//FC 17 or FC 43 so devices that only answer those aren't found
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScanOptions {
+    pub units: RangeInclusive<SlaveId>,
+    //Read each unit is probed with, any answer but a gateway exception means it's there
+    pub probe_table: ModbusTable,
+    pub probe_address: Address,
+    //Tables whose valid ranges are searched on every unit found, none only finds the units
+    pub tables: Vec<ModbusTable>,
+    pub addresses: RangeInclusive<Address>,
+    //Distance between the addresses sampled to find a range, ranges shorter than this can
+    //fall between two samples and be missed
+    pub stride: u16,
+    //Units worked on at once. Over tcp they only overlap on the wire up to the
+    //max_simultaneous_transactions of the master, serial gateways should keep it at 1
+    pub max_concurrent_units: usize,
+    //Replaces the pacing of the master while it scans, None keeps it
+    pub pacing: Option<Pacing>,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            units: 1..=247,
+            probe_table: ModbusTable::HoldingRegisters,
+            probe_address: 0,
+            tables: vec![
+                ModbusTable::Coils,
+                ModbusTable::DiscreteInput,
+                ModbusTable::HoldingRegisters,
+                ModbusTable::InputRegisters,
+            ],
+            addresses: 0..=9999,
+            stride: 50,
+            max_concurrent_units: 1,
+            pacing: None,
+        }
+    }
+}
+
+//A unit that answered the probe
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScannedUnit {
+    pub slave_id: SlaveId,
+    //Exception the probe was answered with, None if it read fine
+    pub probe_exception: Option<ExceptionCode>,
+    //Valid address ranges of every table searched. Tables the unit doesn't have give
+    //an IllegalFunction exception
+    pub tables: BTreeMap<ModbusTable, Result<Vec<RangeInclusive<Address>>, ModbusError>>,
+}
+
+//Units found by a scan, by unit id
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeviceInventory {
+    pub units: Vec<ScannedUnit>,
+}
+
+impl fmt::Display for DeviceInventory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for unit in &self.units {
+            match unit.probe_exception {
+                Some(exception_code) => writeln!(
+                    f,
+                    "Unit {} (probe answered with {:?})",
+                    unit.slave_id, exception_code
+                )?,
+                None => writeln!(f, "Unit {}", unit.slave_id)?,
+            }
+
+            for (table, ranges) in &unit.tables {
+                match ranges {
+                    Ok(ranges) if ranges.is_empty() => writeln!(f, "  {:?}: none", table)?,
+                    Ok(ranges) => {
+                        let ranges: Vec<String> = ranges
+                            .iter()
+                            .map(|range| format!("{}-{}", range.start(), range.end()))
+                            .collect();
+                        writeln!(f, "  {:?}: {}", table, ranges.join(", "))?
+                    }
+                    Err(err) => writeln!(f, "  {:?}: {}", table, err)?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn max_read_ammount(table: ModbusTable) -> u32 {
+    match table {
+        ModbusTable::Coils | ModbusTable::DiscreteInput => MAX_READ_COILS as u32,
+        ModbusTable::HoldingRegisters | ModbusTable::InputRegisters => MAX_READ_REGISTERS as u32,
+    }
+}
+
+//Finds the units behind an endpoint and the address ranges they answer. The requests go
+//through a handle, so they follow the retry policy and pacing of the master
+pub struct Scanner {
+    handle: ModbusMasterHandle,
+    options: ScanOptions,
+}
+
+impl Scanner {
+    pub fn new(mut master: ModbusMasterConnection, options: ScanOptions) -> Self {
+        if let Some(pacing) = options.pacing {
+            let mut params = master.get_params();
+            params.pacing = pacing;
+            master.set_params(params);
+        }
+
+        Scanner {
+            handle: ModbusMasterHandle::spawn(master),
+            options,
+        }
+    }
+
+    //Losing the connection ends the scan, reconnecting is left to the reconnect policy
+    pub async fn scan(&self) -> Result<DeviceInventory, ModbusError> {
+        let units: Vec<Option<ScannedUnit>> = stream::iter(self.options.units.clone())
+            .map(|slave_id| self.scan_unit(slave_id))
+            .buffered(self.options.max_concurrent_units.max(1))
+            .try_collect()
+            .await?;
+
+        Ok(DeviceInventory {
+            units: units.into_iter().flatten().collect(),
+        })
+    }
+
+    //None when nothing answered for the unit
+    pub async fn scan_unit(&self, slave_id: SlaveId) -> Result<Option<ScannedUnit>, ModbusError> {
+        let probe = self
+            .read(
+                slave_id,
+                self.options.probe_table,
+                self.options.probe_address,
+                1,
+            )
+            .await;
+
+        let probe_exception = match probe {
+            Ok(()) => None,
+            Err(ModbusError::Exception(
+                ExceptionCode::GatewayPathUnavailable
+                | ExceptionCode::GatewayTargetDeviceFailedToRespond,
+            )) => return Ok(None),
+            Err(ModbusError::Exception(exception_code)) => Some(exception_code),
+            Err(err @ ModbusError::Connection(_)) => return Err(err),
+            Err(_) => return Ok(None),
+        };
+
+        let mut tables = BTreeMap::new();
+        for table in &self.options.tables {
+            let ranges = self.find_ranges(slave_id, *table).await;
+            if let Err(err @ ModbusError::Connection(_)) = ranges {
+                return Err(err);
+            }
+            tables.insert(*table, ranges);
+        }
+
+        Ok(Some(ScannedUnit {
+            slave_id,
+            probe_exception,
+            tables,
+        }))
+    }
+
+    //Samples the addresses every stride. From each valid sample the start and end of its range
+    //are binary searched with reads over several addresses, which fail with IllegalDataAddress
+    //as soon as one of them isn't valid
+    async fn find_ranges(
+        &self,
+        slave_id: SlaveId,
+        table: ModbusTable,
+    ) -> Result<Vec<RangeInclusive<Address>>, ModbusError> {
+        let max_ammount = max_read_ammount(table);
+        let stride = (self.options.stride as u32).clamp(1, max_ammount);
+        let first = *self.options.addresses.start() as u32;
+        let last = *self.options.addresses.end() as u32;
+
+        let mut ranges = vec![];
+        //Lowest address a range found from the next sample can start at
+        let mut lowest = first;
+        let mut sample = first;
+
+        while sample <= last {
+            if !self.readable(slave_id, table, sample, sample).await? {
+                lowest = sample + 1;
+                sample += stride;
+                continue;
+            }
+
+            let mut low = lowest.max(sample + 1 - stride.min(sample + 1));
+            let mut high = sample;
+            while low < high {
+                let middle = (low + high) / 2;
+                if self.readable(slave_id, table, middle, sample).await? {
+                    high = middle;
+                } else {
+                    low = middle + 1;
+                }
+            }
+            let start = low;
+
+            let end = self.find_range_end(slave_id, table, sample, last).await?;
+            ranges.push(start as Address..=end as Address);
+
+            //The address after the range isn't valid, or is past the last one
+            lowest = end + 2;
+            sample = end + 2;
+        }
+
+        Ok(ranges)
+    }
+
+    //Last valid address of the range going through the one given, which must be valid
+    async fn find_range_end(
+        &self,
+        slave_id: SlaveId,
+        table: ModbusTable,
+        mut from: u32,
+        last: u32,
+    ) -> Result<u32, ModbusError> {
+        let max_ammount = max_read_ammount(table);
+
+        loop {
+            let limit = (from + max_ammount - 1).min(last);
+            let mut low = from;
+            let mut high = limit;
+            while low < high {
+                let middle = (low + high).div_ceil(2);
+                if self.readable(slave_id, table, from, middle).await? {
+                    low = middle;
+                } else {
+                    high = middle - 1;
+                }
+            }
+
+            if low < limit || limit == last {
+                return Ok(low);
+            }
+            if !self.readable(slave_id, table, limit + 1, limit + 1).await? {
+                return Ok(limit);
+            }
+            from = limit + 1;
+        }
+    }
+
+    async fn readable(
+        &self,
+        slave_id: SlaveId,
+        table: ModbusTable,
+        first: u32,
+        last: u32,
+    ) -> Result<bool, ModbusError> {
+        let ammount = (last - first + 1) as u16;
+        match self.read(slave_id, table, first as Address, ammount).await {
+            Ok(()) => Ok(true),
+            Err(ModbusError::Exception(ExceptionCode::IllegalDataAddress)) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn read(
+        &self,
+        slave_id: SlaveId,
+        table: ModbusTable,
+        address: Address,
+        ammount: u16,
+    ) -> Result<(), ModbusError> {
+        let handle = &self.handle;
+        match table {
+            ModbusTable::Coils => handle
+                .read_coils(slave_id, address, ammount)
+                .await
+                .map(drop),
+            ModbusTable::DiscreteInput => handle
+                .read_discrete_inputs(slave_id, address, ammount)
+                .await
+                .map(drop),
+            ModbusTable::HoldingRegisters => handle
+                .read_holding_registers(slave_id, address, ammount)
+                .await
+                .map(drop),
+            ModbusTable::InputRegisters => handle
+                .read_input_registers(slave_id, address, ammount)
+                .await
+                .map(drop),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::{ModbusAddress, ModbusDataType, ModbusSubprotocol};
+    use crate::slave::{ModbusCallBack, ModbusSlaveConnection};
+    use tokio::time::{Duration, Instant};
+
+    //Unit 1 has a few ranges and no input registers, unit 2 has nothing at all and
+    //the gateway can't reach any other unit
+    struct SiteCallBack;
+
+    fn valid(addr: &ModbusAddress) -> bool {
+        match addr.table {
+            ModbusTable::Coils => addr.address <= 40,
+            ModbusTable::DiscreteInput => (150..=400).contains(&addr.address),
+            ModbusTable::HoldingRegisters => {
+                addr.address <= 9 || (100..=119).contains(&addr.address) || addr.address == 333
+            }
+            ModbusTable::InputRegisters => false,
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ModbusCallBack for SiteCallBack {
+        async fn on_read(&self, addr: ModbusAddress) -> Result<ModbusDataType, ExceptionCode> {
+            match addr.slave_id {
+                1 if addr.table == ModbusTable::InputRegisters => {
+                    Err(ExceptionCode::IllegalFunction)
+                }
+                1 if valid(&addr) && addr.table == ModbusTable::HoldingRegisters => {
+                    Ok(ModbusDataType::Register(0))
+                }
+                1 if valid(&addr) => Ok(ModbusDataType::Coil(false)),
+                1 | 2 => Err(ExceptionCode::IllegalDataAddress),
+                _ => Err(ExceptionCode::GatewayTargetDeviceFailedToRespond),
+            }
+        }
+
+        async fn on_write(
+            &self,
+            _addr: ModbusAddress,
+            _value: ModbusDataType,
+        ) -> Result<(), ExceptionCode> {
+            Err(ExceptionCode::IllegalFunction)
+        }
+    }
+
+    fn site_master() -> ModbusMasterConnection {
+        let server = ModbusSlaveConnection::new_in_memory(Box::new(SiteCallBack));
+        ModbusMasterConnection::new_in_memory(&server, ModbusSubprotocol::ModbusTCP, Duration::ZERO)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_inventory_of_a_site() {
+        let scanner = Scanner::new(
+            site_master(),
+            ScanOptions {
+                units: 1..=4,
+                addresses: 0..=999,
+                max_concurrent_units: 2,
+                ..Default::default()
+            },
+        );
+        let inventory = scanner.scan().await.unwrap();
+
+        assert_eq!(inventory.units.len(), 2);
+        let unit = &inventory.units[0];
+        assert_eq!(unit.probe_exception, None);
+        assert_eq!(unit.tables[&ModbusTable::Coils], Ok(vec![0..=40]));
+        assert_eq!(
+            unit.tables[&ModbusTable::DiscreteInput],
+            Ok(vec![150..=400])
+        );
+        //333 lies on no sample and is missed
+        assert_eq!(
+            unit.tables[&ModbusTable::HoldingRegisters],
+            Ok(vec![0..=9, 100..=119])
+        );
+        assert_eq!(
+            unit.tables[&ModbusTable::InputRegisters],
+            Err(ModbusError::Exception(ExceptionCode::IllegalFunction))
+        );
+
+        let unit = &inventory.units[1];
+        assert_eq!(unit.slave_id, 2);
+        assert_eq!(
+            unit.probe_exception,
+            Some(ExceptionCode::IllegalDataAddress)
+        );
+        assert!(unit.tables.values().all(|ranges| *ranges == Ok(vec![])));
+
+        assert_eq!(
+            inventory.to_string().lines().take(3).collect::<Vec<_>>(),
+            vec!["Unit 1", "  DiscreteInput: 150-400", "  Coils: 0-40"]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_scan_is_paced() {
+        let scanner = Scanner::new(
+            site_master(),
+            ScanOptions {
+                units: 1..=5,
+                tables: vec![],
+                max_concurrent_units: 5,
+                pacing: Some(Pacing {
+                    min_request_gap: Duration::from_millis(100),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        );
+
+        let started = Instant::now();
+        let inventory = scanner.scan().await.unwrap();
+        assert_eq!(inventory.units.len(), 2);
+        assert!(started.elapsed() >= Duration::from_millis(400));
+    }
+}
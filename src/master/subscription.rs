@@ -0,0 +1,238 @@
+use std::collections::{HashMap, HashSet};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+
+use futures::Stream;
+use tokio::sync::mpsc;
+
+use crate::common::{ModbusAddress, ModbusDataType};
+use crate::master::poller::{PollSnapshot, PolledValue};
+
+//How much a register has to move before a change is reported, coils are reported on every flip
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Deadband {
+    None,
+    Absolute(u16),
+    //Relative to the last reported value
+    Percent(f64),
+}
+
+impl Deadband {
+    pub fn is_exceeded(&self, old: ModbusDataType, new: ModbusDataType) -> bool {
+        match (old, new) {
+            (ModbusDataType::Register(old), ModbusDataType::Register(new)) => {
+                let delta = old.abs_diff(new);
+                match *self {
+                    Deadband::None => delta > 0,
+                    Deadband::Absolute(band) => delta > band,
+                    Deadband::Percent(percent) => delta as f64 > old as f64 * percent / 100.0,
+                }
+            }
+            _ => old != new,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChangeEvent {
+    pub address: ModbusAddress,
+    //None the first time a value is seen
+    pub old_value: Option<ModbusDataType>,
+    pub new_value: ModbusDataType,
+    pub timestamp: SystemTime,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SubscriptionEvent {
+    //Sent once when subscribing, holds what was already known about the addresses
+    Snapshot(HashMap<ModbusAddress, PolledValue>),
+    Change(ChangeEvent),
+}
+
+//Stream of events for a set of addresses, it ends when the master is dropped
+pub struct Subscription {
+    receiver: mpsc::UnboundedReceiver<SubscriptionEvent>,
+}
+
+impl Stream for Subscription {
+    type Item = SubscriptionEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+pub(super) struct Subscriber {
+    addresses: HashSet<ModbusAddress>,
+    deadband: Deadband,
+    //Values the deadband is measured against, only updated when a change is reported
+    last_values: HashMap<ModbusAddress, ModbusDataType>,
+    sender: mpsc::UnboundedSender<SubscriptionEvent>,
+}
+
+impl Subscriber {
+    pub(super) fn new(
+        addresses: Vec<ModbusAddress>,
+        deadband: Deadband,
+        snapshot: &PollSnapshot,
+    ) -> (Self, Subscription) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let known: HashMap<ModbusAddress, PolledValue> = addresses
+            .iter()
+            .filter_map(|address| {
+                snapshot
+                    .get(address)
+                    .map(|polled| (address.clone(), polled))
+            })
+            .collect();
+        let last_values = known
+            .iter()
+            .filter_map(|(address, polled)| polled.value.map(|value| (address.clone(), value)))
+            .collect();
+
+        //The receiver is still alive here, so this can't fail
+        let _ = sender.send(SubscriptionEvent::Snapshot(known));
+
+        let subscriber = Subscriber {
+            addresses: addresses.into_iter().collect(),
+            deadband,
+            last_values,
+            sender,
+        };
+
+        (subscriber, Subscription { receiver })
+    }
+
+    pub(super) fn notify(
+        &mut self,
+        address: &ModbusAddress,
+        new_value: ModbusDataType,
+        timestamp: SystemTime,
+    ) {
+        if !self.addresses.contains(address) {
+            return;
+        }
+
+        let old_value = self.last_values.get(address).copied();
+        if old_value.is_some_and(|old_value| !self.deadband.is_exceeded(old_value, new_value)) {
+            return;
+        }
+
+        self.last_values.insert(address.clone(), new_value);
+        //A dropped subscription is found by is_closed
+        let _ = self.sender.send(SubscriptionEvent::Change(ChangeEvent {
+            address: address.clone(),
+            old_value,
+            new_value,
+            timestamp,
+        }));
+    }
+
+    pub(super) fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::ModbusTable;
+    use crate::master::test_utils::connect;
+    use crate::master::{PollGroup, Poller};
+    use futures::{FutureExt, StreamExt};
+    use std::time::Duration;
+
+    #[test]
+    fn test_deadband() {
+        let register = ModbusDataType::Register;
+
+        assert!(!Deadband::Absolute(5).is_exceeded(register(100), register(105)));
+        assert!(Deadband::Absolute(5).is_exceeded(register(100), register(94)));
+        assert!(!Deadband::Percent(10.0).is_exceeded(register(200), register(220)));
+        assert!(Deadband::Percent(10.0).is_exceeded(register(200), register(179)));
+        assert!(Deadband::Absolute(5)
+            .is_exceeded(ModbusDataType::Coil(false), ModbusDataType::Coil(true)));
+    }
+
+    #[tokio::test]
+    async fn test_subscription_reports_changes_past_deadband() {
+        let master = connect().await;
+        let mut poller = Poller::new(master);
+        let mut group = PollGroup::new(Duration::from_millis(1));
+        group.add_read(1, ModbusTable::HoldingRegisters, 1, 2);
+        poller.add_group(group);
+
+        let first = ModbusAddress::new(1, ModbusTable::HoldingRegisters, 1);
+        let second = ModbusAddress::new(1, ModbusTable::HoldingRegisters, 2);
+        let mut subscription =
+            poller.subscribe(vec![first.clone(), second.clone()], Deadband::Absolute(5));
+        assert_eq!(
+            subscription.next().now_or_never(),
+            Some(Some(SubscriptionEvent::Snapshot(HashMap::new())))
+        );
+
+        poller.poll_next().await;
+        for _ in 0..2 {
+            match subscription.next().now_or_never() {
+                Some(Some(SubscriptionEvent::Change(change))) => assert_eq!(change.old_value, None),
+                event => panic!("Expected a change, got {:?}", event),
+            }
+        }
+
+        poller
+            .master()
+            .write_single_register(1, 1, 14)
+            .await
+            .unwrap();
+        poller
+            .master()
+            .write_single_register(1, 2, 50)
+            .await
+            .unwrap();
+        poller.poll_next().await;
+
+        match subscription.next().now_or_never() {
+            Some(Some(SubscriptionEvent::Change(change))) => {
+                assert_eq!(change.address, second);
+                assert_eq!(change.old_value, Some(ModbusDataType::Register(20)));
+                assert_eq!(change.new_value, ModbusDataType::Register(50));
+            }
+            event => panic!("Expected a change, got {:?}", event),
+        }
+        assert!(subscription.next().now_or_never().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_master_subscriptions_see_every_read() {
+        let mut master = connect().await;
+        let address = ModbusAddress::new(1, ModbusTable::HoldingRegisters, 4);
+        let mut subscription = master.subscribe(vec![address.clone()], Deadband::None);
+        let dropped = master.subscribe(vec![address], Deadband::None);
+        assert!(matches!(
+            subscription.next().now_or_never(),
+            Some(Some(SubscriptionEvent::Snapshot(_)))
+        ));
+
+        //Forgotten even though nothing it asked for was read
+        drop(dropped);
+        master.read_holding_registers(1, 0, 1).await.unwrap();
+        assert_eq!(master.subscribers.len(), 1);
+
+        master.read_holding_registers(1, 3, 2).await.unwrap();
+        master.write_single_register(1, 4, 7).await.unwrap();
+        master.add_read_holding_registers_query(1, 4, 1).unwrap();
+        master.query().await.unwrap();
+
+        for new_value in [40, 7] {
+            match subscription.next().now_or_never() {
+                Some(Some(SubscriptionEvent::Change(change))) => {
+                    assert_eq!(change.new_value, ModbusDataType::Register(new_value))
+                }
+                event => panic!("Expected a change, got {:?}", event),
+            }
+        }
+        assert!(subscription.next().now_or_never().is_none());
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use tokio::time::Instant;
+use tokio_serial::{SerialPort, SerialStream};
+
+use crate::codec::ModbusSerialize;
+use crate::common::{ModbusAddress, ModbusDataType, ModbusSubprotocol, ModbusTable, SlaveId};
+use crate::communication::{ModbusSocket, RtuPort, SerialSettings};
+use crate::master::ModbusMasterConnection;
+use crate::messages::{ExceptionCode, ModbusQuery};
+use crate::slave::{ModbusCallBack, ModbusSlaveConnection};
+
+pub struct MemoryCallBack {
+    pub values: Mutex<HashMap<ModbusAddress, ModbusDataType>>,
+}
+
+#[async_trait::async_trait]
+impl ModbusCallBack for MemoryCallBack {
+    async fn on_read(&self, addr: ModbusAddress) -> Result<ModbusDataType, ExceptionCode> {
+        self.values
+            .lock()
+            .unwrap()
+            .get(&addr)
+            .copied()
+            .ok_or(ExceptionCode::IllegalDataAddress)
+    }
+
+    async fn on_write(
+        &self,
+        addr: ModbusAddress,
+        value: ModbusDataType,
+    ) -> Result<(), ExceptionCode> {
+        //Like most devices it doesn't take bits of registers, mask writes fall back to the register
+        if addr.bit.is_some() {
+            return Err(ExceptionCode::IllegalDataAddress);
+        }
+        self.values.lock().unwrap().insert(addr, value);
+        Ok(())
+    }
+}
+
+impl MemoryCallBack {
+    //Holding registers 0 to 9 of unit 1, each one holding ten times its address
+    pub fn with_test_registers() -> Self {
+        let values = (0..10)
+            .map(|address| {
+                (
+                    ModbusAddress::new(1, ModbusTable::HoldingRegisters, address),
+                    ModbusDataType::Register(address * 10),
+                )
+            })
+            .collect();
+
+        MemoryCallBack {
+            values: Mutex::new(values),
+        }
+    }
+}
+
+//Serves the test registers over tcp on a free port
+pub async fn spawn_slave() -> SocketAddr {
+    let callback = MemoryCallBack::with_test_registers();
+
+    let mut slave =
+        ModbusSlaveConnection::new_tcp("127.0.0.1:0".parse().unwrap(), Box::new(callback));
+    slave.bind().await.unwrap();
+    let address = slave.local_addr().unwrap();
+    tokio::spawn(async move { slave.serve().await });
+
+    address
+}
+
+pub async fn connect() -> ModbusMasterConnection {
+    ModbusMasterConnection::new_tcp(spawn_slave().await)
+}
+
+//Pseudo terminal standing in for a serial line, the path is the end a connection opens. Reading
+//the pty fails while that end is closed, so it must be opened before the pty is used
+pub fn pty_pair() -> (SerialStream, String) {
+    let (pty, line) = SerialStream::pair().unwrap();
+    let path = line.name().unwrap();
+
+    (pty, path)
+}
+
+//Serves the test registers over a pty, the master is connected to its other end
+pub async fn connect_rtu(settings: SerialSettings) -> ModbusMasterConnection {
+    let (pty, path) = pty_pair();
+    let mut master = ModbusMasterConnection::new_rtu(&path, settings);
+    master.comm.connect().await.unwrap();
+
+    let mut pty = RtuPort::new(pty, &settings);
+    tokio::spawn(async move {
+        ModbusSlaveConnection::handle_connection(
+            Arc::new(MemoryCallBack::with_test_registers()),
+            &mut pty,
+            ModbusSubprotocol::ModbusRTU,
+            Arc::new(None),
+            None,
+        )
+        .await
+    });
+
+    master
+}
+
+//Answers every request right away from the test registers and remembers when it was sent,
+//nothing touches the network so it can be used with paused time
+#[derive(Clone)]
+pub struct RecordingSocket {
+    requests: Arc<Mutex<Vec<(Instant, SlaveId)>>>,
+    responses: Arc<Mutex<VecDeque<Vec<u8>>>>,
+    callback: Arc<MemoryCallBack>,
+}
+
+impl RecordingSocket {
+    pub fn new() -> Self {
+        RecordingSocket {
+            requests: Arc::default(),
+            responses: Arc::default(),
+            callback: Arc::new(MemoryCallBack::with_test_registers()),
+        }
+    }
+
+    pub fn requests(&self) -> Vec<(Instant, SlaveId)> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl ModbusSocket for RecordingSocket {
+    async fn read(&mut self) -> Result<Vec<u8>> {
+        let response = self.responses.lock().unwrap().pop_front();
+        match response {
+            Some(response) => Ok(response),
+            None => std::future::pending().await,
+        }
+    }
+
+    async fn readable(&mut self) -> Result<()> {
+        if self.responses.lock().unwrap().is_empty() {
+            std::future::pending::<()>().await;
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, data: Vec<u8>) -> Result<()> {
+        for query in ModbusQuery::deserialize(data, ModbusSubprotocol::ModbusTCP)? {
+            let slave_id = query.get_message_data().slave_id;
+            self.requests
+                .lock()
+                .unwrap()
+                .push((Instant::now(), slave_id));
+
+            let response =
+                ModbusSlaveConnection::handle_query(self.callback.clone(), query).await?;
+            let response = response.serialize(ModbusSubprotocol::ModbusTCP)?;
+            self.responses.lock().unwrap().push_back(response);
+        }
+        Ok(())
+    }
+}
+
+//Master that talks to a recording socket instead of the network
+pub fn connect_recording() -> (ModbusMasterConnection, RecordingSocket) {
+    let socket = RecordingSocket::new();
+    let mut master = ModbusMasterConnection::new_tcp("127.0.0.1:502".parse().unwrap());
+    master.comm.comm = Some(Box::new(socket.clone()));
+
+    (master, socket)
+}
@@ -0,0 +1,347 @@
+use crate::common::{ModbusDataType, ModbusError};
+use crate::messages::{ModbusQuery, ModbusResponse};
+
+fn mismatch(
+    what: &str,
+    expected: impl std::fmt::Debug,
+    actual: impl std::fmt::Debug,
+) -> ModbusError {
+    ModbusError::Protocol(format!(
+        "Response {} doesn't match the query, expected {:?} got {:?}",
+        what, expected, actual
+    ))
+}
+
+fn check<T: PartialEq + std::fmt::Debug>(
+    what: &str,
+    expected: T,
+    actual: T,
+) -> Result<(), ModbusError> {
+    if expected != actual {
+        return Err(mismatch(what, expected, actual));
+    }
+    Ok(())
+}
+
+//Coils come packed in bytes, so up to 7 padding bits are sent after the last one
+fn check_read_count(ammount: u16, values: &[ModbusDataType]) -> Result<(), ModbusError> {
+    let expected = match values.first() {
+        Some(ModbusDataType::Coil(_)) => (ammount as usize).div_ceil(8) * 8,
+        _ => ammount as usize,
+    };
+    check("value count", expected, values.len())
+}
+
+//Checks that the response answers the query that was sent, anything else is a protocol error
+pub(super) fn validate_response(
+    query: &ModbusQuery,
+    response: &ModbusResponse,
+) -> Result<(), ModbusError> {
+    let query_data = query.get_message_data();
+    let response_data = response.get_message_data();
+
+    check("unit id", query_data.slave_id, response_data.slave_id)?;
+    check(
+        "function code",
+        query_data.function_code,
+        response_data.function_code,
+    )?;
+
+    match (query, response) {
+        (_, ModbusResponse::Error { .. }) => Ok(()),
+        (
+            ModbusQuery::ReadQuery { params, .. },
+            ModbusResponse::ReadResponse {
+                params: response, ..
+            },
+        ) => check_read_count(params.ammount, &response.values),
+        (
+            ModbusQuery::MultipleReadWriteQuery { params, .. },
+            ModbusResponse::ReadResponse {
+                params: response, ..
+            },
+        ) => check_read_count(params.read_ammount, &response.values),
+        (
+            ModbusQuery::SingleWriteQuery { params, .. },
+            ModbusResponse::SingleWriteResponse {
+                params: response, ..
+            },
+        ) => {
+            check("address", params.starting_address, response.address)?;
+            check("value", params.value, response.value)
+        }
+        (
+            ModbusQuery::MultipleWriteQuery { params, .. },
+            ModbusResponse::MultipleWriteResponse {
+                params: response, ..
+            },
+        ) => {
+            check("address", params.starting_address, response.address)?;
+            check("quantity", params.values.len(), response.ammount as usize)
+        }
+        (
+            ModbusQuery::MaskWriteQuery { params, .. },
+            ModbusResponse::MaskWriteResponse {
+                params: response, ..
+            },
+        ) => {
+            check("address", params.address, response.address)?;
+            check(
+                "masks",
+                (params.and_mask, params.or_mask),
+                (response.and_mask, response.or_mask),
+            )
+        }
+        _ => Err(ModbusError::Protocol(
+            "Response type doesn't match the query".to_owned(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::ModbusTable;
+    use crate::messages::query::{
+        MaskWriteQueryParameters, MultipleWriteQueryParameters, ReadQueryParameters,
+        SingleWriteQueryParameters,
+    };
+    use crate::messages::response::{
+        MaskWriteResponseParameters, MultipleWriteResponse, ReadResponseParameters,
+        SingleWriteResponseParameters,
+    };
+    use crate::messages::{ExceptionCode, FunctionCode, ModbusMessageData};
+    use std::cell::Cell;
+
+    fn message_data(slave_id: u8, function_code: FunctionCode) -> ModbusMessageData {
+        ModbusMessageData {
+            slave_id,
+            function_code,
+            transaction_id: Cell::new(Some(1)),
+        }
+    }
+
+    fn read_query(function_code: FunctionCode, table: ModbusTable, ammount: u16) -> ModbusQuery {
+        ModbusQuery::ReadQuery {
+            message_data: message_data(1, function_code),
+            params: ReadQueryParameters {
+                table,
+                starting_address: 0,
+                ammount,
+            },
+        }
+    }
+
+    fn read_response(
+        slave_id: u8,
+        function_code: FunctionCode,
+        table: ModbusTable,
+        values: Vec<ModbusDataType>,
+    ) -> ModbusResponse {
+        ModbusResponse::ReadResponse {
+            message_data: message_data(slave_id, function_code),
+            params: ReadResponseParameters { table, values },
+        }
+    }
+
+    fn registers(count: usize) -> Vec<ModbusDataType> {
+        vec![ModbusDataType::Register(0); count]
+    }
+
+    fn is_protocol_error(result: Result<(), ModbusError>) -> bool {
+        matches!(result, Err(ModbusError::Protocol(_)))
+    }
+
+    #[test]
+    fn test_matching_read_is_accepted() {
+        let query = read_query(
+            FunctionCode::ReadMultipleHoldingRegister,
+            ModbusTable::HoldingRegisters,
+            3,
+        );
+        let response = read_response(
+            1,
+            FunctionCode::ReadMultipleHoldingRegister,
+            ModbusTable::HoldingRegisters,
+            registers(3),
+        );
+        assert_eq!(validate_response(&query, &response), Ok(()));
+
+        //10 coils take two bytes
+        let query = read_query(FunctionCode::ReadCoils, ModbusTable::Coils, 10);
+        let response = read_response(
+            1,
+            FunctionCode::ReadCoils,
+            ModbusTable::Coils,
+            vec![ModbusDataType::Coil(true); 16],
+        );
+        assert_eq!(validate_response(&query, &response), Ok(()));
+    }
+
+    #[test]
+    fn test_unit_id_mismatch() {
+        let query = read_query(
+            FunctionCode::ReadMultipleHoldingRegister,
+            ModbusTable::HoldingRegisters,
+            1,
+        );
+        let response = read_response(
+            2,
+            FunctionCode::ReadMultipleHoldingRegister,
+            ModbusTable::HoldingRegisters,
+            registers(1),
+        );
+        assert!(is_protocol_error(validate_response(&query, &response)));
+    }
+
+    #[test]
+    fn test_function_code_mismatch() {
+        let query = read_query(
+            FunctionCode::ReadMultipleHoldingRegister,
+            ModbusTable::HoldingRegisters,
+            1,
+        );
+        let response = read_response(
+            1,
+            FunctionCode::ReadInputRegisters,
+            ModbusTable::InputRegisters,
+            registers(1),
+        );
+        assert!(is_protocol_error(validate_response(&query, &response)));
+
+        let exception = ModbusResponse::Error {
+            message_data: message_data(1, FunctionCode::ReadCoils),
+            exception_code: ExceptionCode::IllegalDataAddress,
+        };
+        assert!(is_protocol_error(validate_response(&query, &exception)));
+    }
+
+    #[test]
+    fn test_read_count_mismatch() {
+        let query = read_query(
+            FunctionCode::ReadMultipleHoldingRegister,
+            ModbusTable::HoldingRegisters,
+            2,
+        );
+        for count in [1, 3] {
+            let response = read_response(
+                1,
+                FunctionCode::ReadMultipleHoldingRegister,
+                ModbusTable::HoldingRegisters,
+                registers(count),
+            );
+            assert!(is_protocol_error(validate_response(&query, &response)));
+        }
+
+        let query = read_query(FunctionCode::ReadCoils, ModbusTable::Coils, 8);
+        let response = read_response(
+            1,
+            FunctionCode::ReadCoils,
+            ModbusTable::Coils,
+            vec![ModbusDataType::Coil(false); 16],
+        );
+        assert!(is_protocol_error(validate_response(&query, &response)));
+    }
+
+    #[test]
+    fn test_single_write_echo_mismatch() {
+        let query = ModbusQuery::SingleWriteQuery {
+            message_data: message_data(1, FunctionCode::WriteSingleHoldingRegister),
+            params: SingleWriteQueryParameters {
+                table: ModbusTable::HoldingRegisters,
+                starting_address: 4,
+                value: ModbusDataType::Register(40),
+            },
+        };
+        let response = |address, value| ModbusResponse::SingleWriteResponse {
+            message_data: message_data(1, FunctionCode::WriteSingleHoldingRegister),
+            params: SingleWriteResponseParameters {
+                table: ModbusTable::HoldingRegisters,
+                address,
+                value: ModbusDataType::Register(value),
+            },
+        };
+
+        assert_eq!(validate_response(&query, &response(4, 40)), Ok(()));
+        assert!(is_protocol_error(validate_response(
+            &query,
+            &response(5, 40)
+        )));
+        assert!(is_protocol_error(validate_response(
+            &query,
+            &response(4, 41)
+        )));
+    }
+
+    #[test]
+    fn test_multiple_write_mismatch() {
+        let query = ModbusQuery::MultipleWriteQuery {
+            message_data: message_data(1, FunctionCode::WriteMultipleHoldingRegisters),
+            params: MultipleWriteQueryParameters {
+                table: ModbusTable::HoldingRegisters,
+                starting_address: 2,
+                values: registers(3),
+            },
+        };
+        let response = |address, ammount| ModbusResponse::MultipleWriteResponse {
+            message_data: message_data(1, FunctionCode::WriteMultipleHoldingRegisters),
+            params: MultipleWriteResponse {
+                table: ModbusTable::HoldingRegisters,
+                address,
+                ammount,
+            },
+        };
+
+        assert_eq!(validate_response(&query, &response(2, 3)), Ok(()));
+        assert!(is_protocol_error(validate_response(
+            &query,
+            &response(3, 3)
+        )));
+        assert!(is_protocol_error(validate_response(
+            &query,
+            &response(2, 2)
+        )));
+    }
+
+    #[test]
+    fn test_mask_write_echo_mismatch() {
+        let query = ModbusQuery::MaskWriteQuery {
+            message_data: message_data(1, FunctionCode::MaskWriteRegister),
+            params: MaskWriteQueryParameters {
+                table: ModbusTable::HoldingRegisters,
+                address: 1,
+                and_mask: 0xF0F0,
+                or_mask: 0x0101,
+            },
+        };
+        let response = |and_mask| ModbusResponse::MaskWriteResponse {
+            message_data: message_data(1, FunctionCode::MaskWriteRegister),
+            params: MaskWriteResponseParameters {
+                table: ModbusTable::HoldingRegisters,
+                address: 1,
+                and_mask,
+                or_mask: 0x0101,
+            },
+        };
+
+        assert_eq!(validate_response(&query, &response(0xF0F0)), Ok(()));
+        assert!(is_protocol_error(validate_response(
+            &query,
+            &response(0xFFFF)
+        )));
+    }
+
+    #[test]
+    fn test_response_type_mismatch() {
+        let query = read_query(FunctionCode::ReadCoils, ModbusTable::Coils, 1);
+        let response = ModbusResponse::SingleWriteResponse {
+            message_data: message_data(1, FunctionCode::ReadCoils),
+            params: SingleWriteResponseParameters {
+                table: ModbusTable::Coils,
+                address: 0,
+                value: ModbusDataType::Coil(true),
+            },
+        };
+        assert!(is_protocol_error(validate_response(&query, &response)));
+    }
+}
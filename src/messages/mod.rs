@@ -4,6 +4,7 @@ use std::cell::Cell;
 pub mod query;
 pub mod response;
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Clone, Copy, PartialEq, Debug, TryFromPrimitive)]
 #[repr(u8)]
 pub enum FunctionCode {
@@ -29,7 +30,7 @@ pub enum FunctionCode {
     NoFunctionCode = 0xFF,
 }
 
-#[derive(Clone, Copy, PartialEq, Debug, TryFromPrimitive)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, TryFromPrimitive)]
 #[repr(u8)]
 pub enum ExceptionCode {
     IllegalFunction = 1,
@@ -2,6 +2,7 @@ use crate::messages::{ModbusMessageData, FunctionCode};
 use crate::common::{ModbusDataType, ModbusTable};
 use crate::codec::ModbusSerialize;
 
+mod pdu;
 mod rtu;
 mod rtu_over_tcp;
 mod tcp;
@@ -33,6 +34,15 @@ pub struct MultipleReadWriteQueryParameters {
     pub values: Vec<ModbusDataType>,
 }
 
+#[derive(Clone, PartialEq, Debug)]
+pub struct MaskWriteQueryParameters {
+    pub table: ModbusTable,
+    pub address: u16,
+    pub and_mask: u16,
+    pub or_mask: u16,
+}
+
+#[allow(clippy::enum_variant_names)]
 #[derive(Clone, PartialEq, Debug)]
 pub enum ModbusQuery {
     ReadQuery {
@@ -51,6 +61,10 @@ pub enum ModbusQuery {
         message_data: ModbusMessageData,
         params: MultipleReadWriteQueryParameters,
     },
+    MaskWriteQuery {
+        message_data: ModbusMessageData,
+        params: MaskWriteQueryParameters,
+    },
 }
 
 impl ModbusSerialize for ModbusQuery {}
@@ -74,6 +88,10 @@ impl ModbusQuery {
                 message_data,
                 params: _params,
             } => message_data,
+            ModbusQuery::MaskWriteQuery {
+                message_data,
+                params: _params,
+            } => message_data,
         }
     }
 }
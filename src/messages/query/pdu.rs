@@ -0,0 +1,305 @@
+use super::*;
+
+use anyhow::{anyhow, Result};
+use byteorder::{BigEndian, ReadBytesExt};
+use std::io::Cursor;
+
+//The pdu is the function code and its data, what every subprotocol wraps in its own framing
+impl ModbusQuery {
+    pub(crate) fn serialize_pdu(&self) -> Result<Vec<u8>> {
+        let mut pdu = vec![];
+
+        //Function code
+        pdu.push(self.get_message_data().function_code as u8);
+
+        match self {
+            ModbusQuery::ReadQuery { params, .. } => {
+                //Starting Address
+                pdu.extend_from_slice(&params.starting_address.to_be_bytes());
+
+                //Ammount
+                pdu.extend_from_slice(&params.ammount.to_be_bytes());
+            }
+            ModbusQuery::SingleWriteQuery { params, .. } => {
+                //Address
+                pdu.extend_from_slice(&params.starting_address.to_be_bytes());
+
+                //Value
+                let value = params.value.get_representation();
+                pdu.extend_from_slice(&value.to_be_bytes());
+            }
+            ModbusQuery::MultipleWriteQuery { params, .. } => {
+                //Starting Address
+                pdu.extend_from_slice(&params.starting_address.to_be_bytes());
+
+                //Ammount
+                let ammount = params.values.len() as u16;
+                pdu.extend_from_slice(&ammount.to_be_bytes());
+
+                //Values
+                let values = crate::codec::utils::serialize_values(params.values.clone())?;
+                pdu.extend_from_slice(&values);
+            }
+            ModbusQuery::MultipleReadWriteQuery { params, .. } => {
+                //Read Starting Address
+                pdu.extend_from_slice(&params.read_starting_address.to_be_bytes());
+
+                //Read Ammount
+                pdu.extend_from_slice(&params.read_ammount.to_be_bytes());
+
+                //Write Starting Address
+                pdu.extend_from_slice(&params.write_starting_address.to_be_bytes());
+
+                //Write Ammount
+                let write_ammount = params.values.len() as u16;
+                pdu.extend_from_slice(&write_ammount.to_be_bytes());
+
+                //Write values
+                let values = crate::codec::utils::serialize_values(params.values.clone())?;
+                pdu.extend_from_slice(&values);
+            }
+            ModbusQuery::MaskWriteQuery { params, .. } => {
+                //Reference Address
+                pdu.extend_from_slice(&params.address.to_be_bytes());
+
+                //And Mask
+                pdu.extend_from_slice(&params.and_mask.to_be_bytes());
+
+                //Or Mask
+                pdu.extend_from_slice(&params.or_mask.to_be_bytes());
+            }
+        };
+        Ok(pdu)
+    }
+
+    pub(crate) fn deserialize_pdu(
+        mut message_data: ModbusMessageData,
+        pdu: Vec<u8>,
+    ) -> Result<Self> {
+        let mut pdu = Cursor::new(pdu);
+
+        message_data.function_code = FunctionCode::try_from(pdu.read_u8()?)?;
+
+        let query = match message_data.function_code {
+            FunctionCode::ReadCoils
+            | FunctionCode::ReadDiscreteInputs
+            | FunctionCode::ReadInputRegisters
+            | FunctionCode::ReadMultipleHoldingRegister => ModbusQuery::ReadQuery {
+                params: deserialize_read_query(&message_data, pdu)?,
+                message_data,
+            },
+            FunctionCode::WriteSingleCoil | FunctionCode::WriteSingleHoldingRegister => {
+                ModbusQuery::SingleWriteQuery {
+                    params: deserialize_single_write_query(&message_data, pdu)?,
+                    message_data,
+                }
+            }
+            FunctionCode::WriteMultipleCoils | FunctionCode::WriteMultipleHoldingRegisters => {
+                ModbusQuery::MultipleWriteQuery {
+                    params: deserialize_multiple_write_query(&message_data, pdu)?,
+                    message_data,
+                }
+            }
+            FunctionCode::ReadWriteMultipleRegisters => ModbusQuery::MultipleReadWriteQuery {
+                params: deserialize_multiple_read_write_query(&message_data, pdu)?,
+                message_data,
+            },
+            FunctionCode::MaskWriteRegister => ModbusQuery::MaskWriteQuery {
+                params: deserialize_mask_write_query(&message_data, pdu)?,
+                message_data,
+            },
+            function_code => {
+                return Err(anyhow!("Unsupported function code {:?}", function_code));
+            }
+        };
+
+        Ok(query)
+    }
+
+    //Length of a pdu from its first bytes, None until there are enough of them to tell
+    pub(crate) fn pdu_length(data: &[u8]) -> Option<usize> {
+        let function_code = FunctionCode::try_from(*data.first()?).ok()?;
+
+        match function_code {
+            FunctionCode::ReadCoils
+            | FunctionCode::ReadDiscreteInputs
+            | FunctionCode::ReadMultipleHoldingRegister
+            | FunctionCode::ReadInputRegisters
+            | FunctionCode::WriteSingleCoil
+            | FunctionCode::WriteSingleHoldingRegister => Some(5),
+            FunctionCode::WriteMultipleCoils | FunctionCode::WriteMultipleHoldingRegisters => {
+                Some(6 + *data.get(5)? as usize)
+            }
+            FunctionCode::MaskWriteRegister => Some(7),
+            FunctionCode::ReadWriteMultipleRegisters => Some(10 + *data.get(9)? as usize),
+            _ => None,
+        }
+    }
+}
+
+fn deserialize_read_query(
+    message_data: &ModbusMessageData,
+    mut data: Cursor<Vec<u8>>,
+) -> Result<ReadQueryParameters> {
+    let table = ModbusTable::get_table_from_function_code(message_data.function_code)
+        .ok_or_else(|| anyhow!("Function code doesn't address any table"))?;
+
+    let starting_address = data.read_u16::<BigEndian>()?;
+
+    let ammount = data.read_u16::<BigEndian>()?;
+
+    let position = data.position() as usize;
+
+    let len = data.get_ref().len();
+
+    if position != len {
+        return Err(anyhow!(
+            "Read query too long, {} too many bytes",
+            len - position
+        ));
+    }
+
+    Ok(ReadQueryParameters {
+        table,
+        starting_address,
+        ammount,
+    })
+}
+
+fn deserialize_single_write_query(
+    message_data: &ModbusMessageData,
+    mut data: Cursor<Vec<u8>>,
+) -> Result<SingleWriteQueryParameters> {
+    let table = ModbusTable::get_table_from_function_code(message_data.function_code)
+        .ok_or_else(|| anyhow!("Function code doesn't address any table"))?;
+
+    let starting_address = data.read_u16::<BigEndian>()?;
+
+    let raw_value = data.read_u16::<BigEndian>()?;
+
+    let position = data.position() as usize;
+
+    let len = data.get_ref().len();
+
+    if position != len {
+        return Err(anyhow!(
+            "Single Write query too long, {} too many bytes",
+            len - position
+        ));
+    }
+
+    let value = match table {
+        ModbusTable::Coils | ModbusTable::DiscreteInput => {
+            ModbusDataType::coil_from_representation(raw_value)?
+        }
+        ModbusTable::HoldingRegisters | ModbusTable::InputRegisters => {
+            ModbusDataType::Register(raw_value)
+        }
+    };
+
+    Ok(SingleWriteQueryParameters {
+        table,
+        starting_address,
+        value,
+    })
+}
+
+fn deserialize_multiple_write_query(
+    message_data: &ModbusMessageData,
+    mut data: Cursor<Vec<u8>>,
+) -> Result<MultipleWriteQueryParameters> {
+    let table = ModbusTable::get_table_from_function_code(message_data.function_code)
+        .ok_or_else(|| anyhow!("Function code doesn't address any table"))?;
+
+    let starting_address = data.read_u16::<BigEndian>()?;
+
+    let ammount = data.read_u16::<BigEndian>()?;
+
+    let values = crate::codec::utils::deserialize_values(table, Some(ammount), &mut data)?;
+
+    let position = data.position() as usize;
+
+    let len = data.get_ref().len();
+
+    if position != len {
+        return Err(anyhow!(
+            "Multiple Write query too long, {} too many bytes",
+            len - position
+        ));
+    }
+
+    Ok(MultipleWriteQueryParameters {
+        table,
+        starting_address,
+        values,
+    })
+}
+
+fn deserialize_multiple_read_write_query(
+    message_data: &ModbusMessageData,
+    mut data: Cursor<Vec<u8>>,
+) -> Result<MultipleReadWriteQueryParameters> {
+    let table = ModbusTable::get_table_from_function_code(message_data.function_code)
+        .ok_or_else(|| anyhow!("Function code doesn't address any table"))?;
+
+    let read_starting_address = data.read_u16::<BigEndian>()?;
+
+    let read_ammount = data.read_u16::<BigEndian>()?;
+
+    let write_starting_address = data.read_u16::<BigEndian>()?;
+
+    let write_ammount = data.read_u16::<BigEndian>()?;
+
+    let values = crate::codec::utils::deserialize_values(table, Some(write_ammount), &mut data)?;
+
+    let position = data.position() as usize;
+
+    let len = data.get_ref().len();
+
+    if position != len {
+        return Err(anyhow!(
+            "Multiple Write query too long, {} too many bytes",
+            len - position
+        ));
+    }
+
+    Ok(MultipleReadWriteQueryParameters {
+        table,
+        read_starting_address,
+        read_ammount,
+        write_starting_address,
+        values,
+    })
+}
+
+fn deserialize_mask_write_query(
+    message_data: &ModbusMessageData,
+    mut data: Cursor<Vec<u8>>,
+) -> Result<MaskWriteQueryParameters> {
+    let table = ModbusTable::get_table_from_function_code(message_data.function_code)
+        .ok_or_else(|| anyhow!("Function code doesn't address any table"))?;
+
+    let address = data.read_u16::<BigEndian>()?;
+
+    let and_mask = data.read_u16::<BigEndian>()?;
+
+    let or_mask = data.read_u16::<BigEndian>()?;
+
+    let position = data.position() as usize;
+
+    let len = data.get_ref().len();
+
+    if position != len {
+        return Err(anyhow!(
+            "Mask Write query too long, {} too many bytes",
+            len - position
+        ));
+    }
+
+    Ok(MaskWriteQueryParameters {
+        table,
+        address,
+        and_mask,
+        or_mask,
+    })
+}
@@ -1,14 +1,95 @@
-use crate::messages::ModbusQuery;
 use crate::codec::rtu::ModbusRtuSerialize;
+use crate::messages::{FunctionCode, ModbusMessageData, ModbusQuery};
 
-use anyhow::{Result, anyhow};
+use anyhow::Result;
+use std::cell::Cell;
 
 impl ModbusRtuSerialize for ModbusQuery {
-    fn rtu_deserialize(_data: Vec<u8>) -> Result<Vec<Self>> {
-        Err(anyhow!("Not implemented"))
+    //Frames that don't hold a query this crate understands are skipped
+    fn rtu_deserialize(data: Vec<u8>) -> Result<Vec<Self>> {
+        let queries = crate::codec::rtu::deserialize_frames(&data, ModbusQuery::pdu_length)
+            .into_iter()
+            .filter_map(|(slave_id, pdu)| {
+                let message_data = ModbusMessageData {
+                    slave_id,
+                    function_code: FunctionCode::NoFunctionCode,
+                    transaction_id: Cell::new(None),
+                };
+
+                ModbusQuery::deserialize_pdu(message_data, pdu).ok()
+            })
+            .collect();
+
+        Ok(queries)
     }
 
     fn rtu_serialize(&self) -> Result<Vec<u8>> {
-        Err(anyhow!("Not implemented"))
+        let pdu = self.serialize_pdu()?;
+
+        Ok(crate::codec::rtu::serialize_frame(
+            self.get_message_data().slave_id,
+            &pdu,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::codec::ModbusSerialize;
+    use crate::common::{ModbusDataType, ModbusSubprotocol, ModbusTable};
+    use crate::messages::query;
+
+    #[test]
+    fn test_serialization_deserialization_rtu_queries() {
+        let input = vec![
+            ModbusQuery::ReadQuery {
+                message_data: ModbusMessageData {
+                    slave_id: 0x11,
+                    function_code: FunctionCode::ReadMultipleHoldingRegister,
+                    transaction_id: Cell::new(None),
+                },
+                params: query::ReadQueryParameters {
+                    table: ModbusTable::HoldingRegisters,
+                    starting_address: 0x006B,
+                    ammount: 3,
+                },
+            },
+            ModbusQuery::MultipleWriteQuery {
+                message_data: ModbusMessageData {
+                    slave_id: 2,
+                    function_code: FunctionCode::WriteMultipleCoils,
+                    transaction_id: Cell::new(None),
+                },
+                params: query::MultipleWriteQueryParameters {
+                    table: ModbusTable::Coils,
+                    starting_address: 19,
+                    values: vec![ModbusDataType::Coil(true); 10],
+                },
+            },
+            ModbusQuery::MultipleReadWriteQuery {
+                message_data: ModbusMessageData {
+                    slave_id: 3,
+                    function_code: FunctionCode::ReadWriteMultipleRegisters,
+                    transaction_id: Cell::new(None),
+                },
+                params: query::MultipleReadWriteQueryParameters {
+                    table: ModbusTable::HoldingRegisters,
+                    read_starting_address: 3,
+                    read_ammount: 6,
+                    write_starting_address: 14,
+                    values: vec![ModbusDataType::Register(0x00FF); 3],
+                },
+            },
+        ];
+
+        let mut bytes = vec![];
+        for query in &input {
+            bytes.extend_from_slice(&query.serialize(ModbusSubprotocol::ModbusRTU).unwrap());
+        }
+        assert_eq!(bytes[..8], [0x11, 0x03, 0x00, 0x6B, 0x00, 0x03, 0x76, 0x87]);
+
+        let output = ModbusQuery::deserialize(bytes, ModbusSubprotocol::ModbusRTU).unwrap();
+        assert_eq!(input, output);
     }
-}
\ No newline at end of file
+}
@@ -1,14 +1,16 @@
-use crate::messages::ModbusQuery;
+use crate::codec::rtu::ModbusRtuSerialize;
 use crate::codec::rtu_over_tcp::ModbusRtuOverTcpSerialize;
+use crate::messages::ModbusQuery;
 
-use anyhow::{Result, anyhow};
+use anyhow::Result;
 
+//Same frames as on a serial line, only carried over a tcp stream
 impl ModbusRtuOverTcpSerialize for ModbusQuery {
-    fn rtu_over_tcp_deserialize(_data: Vec<u8>) -> Result<Vec<Self>> {
-        Err(anyhow!("Not implemented"))
+    fn rtu_over_tcp_deserialize(data: Vec<u8>) -> Result<Vec<Self>> {
+        ModbusQuery::rtu_deserialize(data)
     }
 
     fn rtu_over_tcp_serialize(&self) -> Result<Vec<u8>> {
-        Err(anyhow!("Not implemented"))
+        self.rtu_serialize()
     }
-}
\ No newline at end of file
+}
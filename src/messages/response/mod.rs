@@ -2,6 +2,7 @@ use crate::common::{ModbusDataType, ModbusTable};
 use crate::messages::{FunctionCode, ExceptionCode, ModbusMessageData};
 use crate::codec::ModbusSerialize;
 
+mod pdu;
 mod rtu;
 mod rtu_over_tcp;
 mod tcp;
@@ -26,6 +27,14 @@ pub struct MultipleWriteResponse {
     pub ammount: u16,
 }
 
+#[derive(Clone, PartialEq, Debug)]
+pub struct MaskWriteResponseParameters {
+    pub table: ModbusTable,
+    pub address: u16,
+    pub and_mask: u16,
+    pub or_mask: u16,
+}
+
 #[derive(Clone, PartialEq, Debug)]
 pub enum ModbusResponse {
     ReadResponse {
@@ -40,6 +49,10 @@ pub enum ModbusResponse {
         message_data: ModbusMessageData,
         params: MultipleWriteResponse,
     },
+    MaskWriteResponse {
+        message_data: ModbusMessageData,
+        params: MaskWriteResponseParameters,
+    },
 
     Error {
         message_data: ModbusMessageData,
@@ -64,6 +77,10 @@ impl ModbusResponse {
                 message_data,
                 params: _params,
             } => message_data,
+            ModbusResponse::MaskWriteResponse {
+                message_data,
+                params: _params,
+            } => message_data,
             ModbusResponse::Error {
                 message_data,
                 exception_code: _exception_code,
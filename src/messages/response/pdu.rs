@@ -0,0 +1,235 @@
+use super::*;
+
+use anyhow::{anyhow, Result};
+use byteorder::{BigEndian, ReadBytesExt};
+
+//The pdu is the function code and its data, what every subprotocol wraps in its own framing
+impl ModbusResponse {
+    pub(crate) fn serialize_pdu(&self) -> Result<Vec<u8>> {
+        let mut pdu = vec![];
+
+        match self {
+            ModbusResponse::ReadResponse {
+                message_data,
+                params,
+            } => {
+                pdu.push(message_data.function_code as u8);
+
+                let values = crate::codec::utils::serialize_values(params.values.clone())?;
+
+                pdu.extend_from_slice(&values);
+            }
+            ModbusResponse::SingleWriteResponse {
+                message_data,
+                params,
+            } => {
+                pdu.push(message_data.function_code as u8);
+
+                pdu.extend_from_slice(&params.address.to_be_bytes());
+
+                let value = params.value.get_representation();
+
+                pdu.extend_from_slice(&value.to_be_bytes());
+            }
+            ModbusResponse::MultipleWriteResponse {
+                message_data,
+                params,
+            } => {
+                pdu.push(message_data.function_code as u8);
+
+                pdu.extend_from_slice(&params.address.to_be_bytes());
+
+                pdu.extend_from_slice(&params.ammount.to_be_bytes());
+            }
+            ModbusResponse::MaskWriteResponse {
+                message_data,
+                params,
+            } => {
+                pdu.push(message_data.function_code as u8);
+
+                pdu.extend_from_slice(&params.address.to_be_bytes());
+
+                pdu.extend_from_slice(&params.and_mask.to_be_bytes());
+
+                pdu.extend_from_slice(&params.or_mask.to_be_bytes());
+            }
+            ModbusResponse::Error {
+                message_data,
+                exception_code,
+            } => {
+                pdu.push(message_data.function_code as u8 + 0x80);
+
+                pdu.push(*exception_code as u8);
+            }
+        };
+        Ok(pdu)
+    }
+
+    pub(crate) fn deserialize_pdu(
+        mut message_data: ModbusMessageData,
+        pdu: Vec<u8>,
+    ) -> Result<Self> {
+        let mut pdu = std::io::Cursor::new(pdu);
+
+        let raw_function_code = pdu.read_u8()?;
+
+        //Is in error range
+        if raw_function_code > 0x80 {
+            message_data.function_code = FunctionCode::try_from(raw_function_code - 0x80)?;
+            return deserialize_error_response(message_data, pdu);
+        }
+
+        message_data.function_code = FunctionCode::try_from(raw_function_code)?;
+
+        match message_data.function_code {
+            FunctionCode::WriteSingleCoil | FunctionCode::WriteSingleHoldingRegister => {
+                deserialize_single_write_response(message_data, pdu)
+            }
+            FunctionCode::ReadCoils
+            | FunctionCode::ReadDiscreteInputs
+            | FunctionCode::ReadMultipleHoldingRegister
+            | FunctionCode::ReadInputRegisters
+            | FunctionCode::ReadWriteMultipleRegisters => {
+                deserialize_read_response(message_data, pdu)
+            }
+            FunctionCode::WriteMultipleCoils | FunctionCode::WriteMultipleHoldingRegisters => {
+                deserialize_multiple_write_response(message_data, pdu)
+            }
+            FunctionCode::MaskWriteRegister => deserialize_mask_write_response(message_data, pdu),
+            function_code => Err(anyhow!("Unsupported function code {:?}", function_code)),
+        }
+    }
+
+    //Length of a pdu from its first bytes, None until there are enough of them to tell
+    pub(crate) fn pdu_length(data: &[u8]) -> Option<usize> {
+        let raw_function_code = *data.first()?;
+
+        if raw_function_code > 0x80 {
+            return Some(2);
+        }
+
+        match FunctionCode::try_from(raw_function_code).ok()? {
+            FunctionCode::ReadCoils
+            | FunctionCode::ReadDiscreteInputs
+            | FunctionCode::ReadMultipleHoldingRegister
+            | FunctionCode::ReadInputRegisters
+            | FunctionCode::ReadWriteMultipleRegisters => Some(2 + *data.get(1)? as usize),
+            FunctionCode::WriteSingleCoil
+            | FunctionCode::WriteSingleHoldingRegister
+            | FunctionCode::WriteMultipleCoils
+            | FunctionCode::WriteMultipleHoldingRegisters => Some(5),
+            FunctionCode::MaskWriteRegister => Some(7),
+            _ => None,
+        }
+    }
+}
+
+fn deserialize_error_response(
+    message_data: ModbusMessageData,
+    mut data: std::io::Cursor<Vec<u8>>,
+) -> Result<ModbusResponse> {
+    let exception_code = ExceptionCode::try_from(data.read_u8()?)?;
+
+    Ok(ModbusResponse::Error {
+        message_data,
+        exception_code,
+    })
+}
+
+fn deserialize_single_write_response(
+    message_data: ModbusMessageData,
+    mut data: std::io::Cursor<Vec<u8>>,
+) -> Result<ModbusResponse> {
+    let table = ModbusTable::get_table_from_function_code(message_data.function_code)
+        .ok_or_else(|| anyhow!("Function code doesn't address any table"))?;
+
+    let address = data.read_u16::<BigEndian>()?;
+
+    let raw_value = data.read_u16::<BigEndian>()?;
+
+    let value = match table {
+        ModbusTable::Coils | ModbusTable::DiscreteInput => {
+            ModbusDataType::coil_from_representation(raw_value)?
+        }
+        ModbusTable::InputRegisters | ModbusTable::HoldingRegisters => {
+            ModbusDataType::Register(raw_value)
+        }
+    };
+
+    let params = SingleWriteResponseParameters {
+        table,
+        address,
+        value,
+    };
+
+    Ok(ModbusResponse::SingleWriteResponse {
+        message_data,
+        params,
+    })
+}
+
+fn deserialize_read_response(
+    message_data: ModbusMessageData,
+    mut data: std::io::Cursor<Vec<u8>>,
+) -> Result<ModbusResponse> {
+    let table = ModbusTable::get_table_from_function_code(message_data.function_code)
+        .ok_or_else(|| anyhow!("Function code doesn't address any table"))?;
+
+    let values = crate::codec::utils::deserialize_values(table, None, &mut data)?;
+
+    let params = ReadResponseParameters { table, values };
+
+    Ok(ModbusResponse::ReadResponse {
+        message_data,
+        params,
+    })
+}
+
+fn deserialize_multiple_write_response(
+    message_data: ModbusMessageData,
+    mut data: std::io::Cursor<Vec<u8>>,
+) -> Result<ModbusResponse> {
+    let table = ModbusTable::get_table_from_function_code(message_data.function_code)
+        .ok_or_else(|| anyhow!("Function code doesn't address any table"))?;
+
+    let address = data.read_u16::<BigEndian>()?;
+
+    let ammount = data.read_u16::<BigEndian>()?;
+
+    let params = MultipleWriteResponse {
+        table,
+        address,
+        ammount,
+    };
+
+    Ok(ModbusResponse::MultipleWriteResponse {
+        message_data,
+        params,
+    })
+}
+
+fn deserialize_mask_write_response(
+    message_data: ModbusMessageData,
+    mut data: std::io::Cursor<Vec<u8>>,
+) -> Result<ModbusResponse> {
+    let table = ModbusTable::get_table_from_function_code(message_data.function_code)
+        .ok_or_else(|| anyhow!("Function code doesn't address any table"))?;
+
+    let address = data.read_u16::<BigEndian>()?;
+
+    let and_mask = data.read_u16::<BigEndian>()?;
+
+    let or_mask = data.read_u16::<BigEndian>()?;
+
+    let params = MaskWriteResponseParameters {
+        table,
+        address,
+        and_mask,
+        or_mask,
+    };
+
+    Ok(ModbusResponse::MaskWriteResponse {
+        message_data,
+        params,
+    })
+}
@@ -1,15 +1,91 @@
-use crate::messages::ModbusResponse;
 use crate::codec::rtu::ModbusRtuSerialize;
+use crate::messages::{FunctionCode, ModbusMessageData, ModbusResponse};
 
-use anyhow::{anyhow, Result};
+use anyhow::Result;
+use std::cell::Cell;
 
-impl ModbusRtuSerialize for ModbusResponse
-{
-    fn rtu_deserialize(_data: Vec<u8>) -> Result<Vec<Self>> {
-        Err(anyhow!("Not implemented!"))
+impl ModbusRtuSerialize for ModbusResponse {
+    //Frames that don't hold a response this crate understands are skipped
+    fn rtu_deserialize(data: Vec<u8>) -> Result<Vec<Self>> {
+        let responses = crate::codec::rtu::deserialize_frames(&data, ModbusResponse::pdu_length)
+            .into_iter()
+            .filter_map(|(slave_id, pdu)| {
+                let message_data = ModbusMessageData {
+                    slave_id,
+                    function_code: FunctionCode::NoFunctionCode,
+                    transaction_id: Cell::new(None),
+                };
+
+                ModbusResponse::deserialize_pdu(message_data, pdu).ok()
+            })
+            .collect();
+
+        Ok(responses)
     }
 
     fn rtu_serialize(&self) -> Result<Vec<u8>> {
-        Err(anyhow!("Not implemented!"))
+        let pdu = self.serialize_pdu()?;
+
+        Ok(crate::codec::rtu::serialize_frame(
+            self.get_message_data().slave_id,
+            &pdu,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::codec::ModbusSerialize;
+    use crate::common::{ModbusDataType, ModbusSubprotocol, ModbusTable};
+    use crate::messages::{response, ExceptionCode};
+
+    #[test]
+    fn test_serialization_deserialization_rtu_responses() {
+        let input = vec![
+            ModbusResponse::ReadResponse {
+                message_data: ModbusMessageData {
+                    slave_id: 7,
+                    function_code: FunctionCode::ReadMultipleHoldingRegister,
+                    transaction_id: Cell::new(None),
+                },
+                params: response::ReadResponseParameters {
+                    table: ModbusTable::HoldingRegisters,
+                    values: vec![
+                        ModbusDataType::Register(0x1234),
+                        ModbusDataType::Register(5),
+                    ],
+                },
+            },
+            ModbusResponse::Error {
+                message_data: ModbusMessageData {
+                    slave_id: 7,
+                    function_code: FunctionCode::WriteSingleCoil,
+                    transaction_id: Cell::new(None),
+                },
+                exception_code: ExceptionCode::IllegalDataAddress,
+            },
+            ModbusResponse::MaskWriteResponse {
+                message_data: ModbusMessageData {
+                    slave_id: 9,
+                    function_code: FunctionCode::MaskWriteRegister,
+                    transaction_id: Cell::new(None),
+                },
+                params: response::MaskWriteResponseParameters {
+                    table: ModbusTable::HoldingRegisters,
+                    address: 4,
+                    and_mask: 0x00F2,
+                    or_mask: 0x0025,
+                },
+            },
+        ];
+
+        let mut bytes = vec![];
+        for response in &input {
+            bytes.extend_from_slice(&response.serialize(ModbusSubprotocol::ModbusRTU).unwrap());
+        }
+
+        let output = ModbusResponse::deserialize(bytes, ModbusSubprotocol::ModbusRTU).unwrap();
+        assert_eq!(input, output);
     }
-}
\ No newline at end of file
+}
@@ -3,8 +3,7 @@ use std::io::Read;
 use crate::codec::tcp::ModbusTcpSerialize;
 
 use super::*;
-use anyhow::{anyhow, Result};
-use byteorder::{BigEndian, ReadBytesExt};
+use anyhow::Result;
 
 impl ModbusTcpSerialize for ModbusResponse {
     fn tcp_deserialize(data: Vec<u8>) -> Result<Vec<Self>> {
@@ -15,54 +14,16 @@ impl ModbusTcpSerialize for ModbusResponse {
         let mut size_left = data.get_ref().len() - data.position() as usize;
 
         while size_left > 0 {
-            let (mut message_data, length) = crate::codec::tcp::deserialize_mbap(&mut data)?;
+            let (message_data, length) = crate::codec::tcp::deserialize_mbap(&mut data)?;
 
             let mut message_body = vec![0u8; length as usize];
 
             data.read_exact(&mut message_body)?;
 
-            let mut message_body = std::io::Cursor::new(message_body);
-
-            let raw_function_code = message_body.read_u8()?;
-
-            //Is in error range
-            if raw_function_code > 0x80 {
-                message_data.function_code = FunctionCode::try_from(raw_function_code - 0x80)?;
-                if let Ok(response) = deserialize_error_response(message_data, message_body) {
-                    result.push(response);
-                }
-            } else {
-                message_data.function_code = FunctionCode::try_from(raw_function_code)?;
-
-                match message_data.function_code {
-                    FunctionCode::WriteSingleCoil | FunctionCode::WriteSingleHoldingRegister => {
-                        if let Ok(response) =
-                            deserialize_single_write_response(message_data, message_body)
-                        {
-                            result.push(response);
-                        }
-                    }
-                    FunctionCode::ReadCoils
-                    | FunctionCode::ReadDiscreteInputs
-                    | FunctionCode::ReadMultipleHoldingRegister
-                    | FunctionCode::ReadInputRegisters
-                    | FunctionCode::ReadWriteMultipleRegisters => {
-                        if let Ok(response) = deserialize_read_response(message_data, message_body)
-                        {
-                            result.push(response);
-                        }
-                    }
-                    FunctionCode::WriteMultipleCoils
-                    | FunctionCode::WriteMultipleHoldingRegisters => {
-                        if let Ok(response) =
-                            deserialize_multiple_write_response(message_data, message_body)
-                        {
-                            result.push(response);
-                        }
-                    }
-                    _ => {}
-                }
+            if let Ok(response) = ModbusResponse::deserialize_pdu(message_data, message_body) {
+                result.push(response);
             }
+
             size_left = data.get_ref().len() - data.position() as usize;
         }
 
@@ -71,176 +32,19 @@ impl ModbusTcpSerialize for ModbusResponse {
 
     fn tcp_serialize(&self) -> Result<Vec<u8>> {
         let mut result = vec![];
-        match self {
-            ModbusResponse::ReadResponse {
-                message_data,
-                params,
-            } => {
-                let mut read_response = vec![];
 
-                read_response.push(message_data.function_code as u8);
+        let pdu = self.serialize_pdu()?;
 
-                let values = crate::codec::utils::serialize_values(params.values.clone())?;
+        let mbap =
+            crate::codec::tcp::serialize_mbap(self.get_message_data(), pdu.len() as u16 + 1)?;
 
-                read_response.extend_from_slice(&values);
-
-                let mbap = crate::codec::tcp::serialize_mbap(
-                    message_data,
-                    read_response.len() as u16 + 1,
-                )?;
-
-                result.extend_from_slice(&mbap);
-                result.extend_from_slice(&read_response);
-            }
-            ModbusResponse::SingleWriteResponse {
-                message_data,
-                params,
-            } => {
-                let mut single_write_response = vec![];
+        result.extend_from_slice(&mbap);
+        result.extend_from_slice(&pdu);
 
-                single_write_response.push(message_data.function_code as u8);
-
-                single_write_response.extend_from_slice(&params.address.to_be_bytes());
-
-                let value = params.value.get_representation();
-
-                single_write_response.extend_from_slice(&value.to_be_bytes());
-
-                let mbap = crate::codec::tcp::serialize_mbap(
-                    message_data,
-                    single_write_response.len() as u16 + 1,
-                )?;
-
-                result.extend_from_slice(&mbap);
-                result.extend_from_slice(&single_write_response);
-            }
-            ModbusResponse::MultipleWriteResponse {
-                message_data,
-                params,
-            } => {
-                let mut multiple_write_response = vec![];
-
-                multiple_write_response.push(message_data.function_code as u8);
-
-                multiple_write_response.extend_from_slice(&params.address.to_be_bytes());
-
-                multiple_write_response.extend_from_slice(&params.ammount.to_be_bytes());
-
-                let mbap = crate::codec::tcp::serialize_mbap(
-                    message_data,
-                    multiple_write_response.len() as u16 + 1,
-                )?;
-
-                result.extend_from_slice(&mbap);
-                result.extend_from_slice(&multiple_write_response);
-            }
-            ModbusResponse::Error {
-                message_data,
-                exception_code,
-            } => {
-                let mut error_response = vec![];
-
-                error_response.push(message_data.function_code as u8 + 0x80);
-
-                error_response.push(*exception_code as u8);
-
-                let mbap = crate::codec::tcp::serialize_mbap(
-                    message_data,
-                    error_response.len() as u16 + 1,
-                )?;
-
-                result.extend_from_slice(&mbap);
-                result.extend_from_slice(&error_response);
-            }
-        };
         Ok(result)
     }
 }
 
-fn deserialize_error_response(
-    message_data: ModbusMessageData,
-    mut data: std::io::Cursor<Vec<u8>>,
-) -> Result<ModbusResponse> {
-    let exception_code = ExceptionCode::try_from(data.read_u8()?)?;
-
-    Ok(ModbusResponse::Error {
-        message_data,
-        exception_code,
-    })
-}
-
-fn deserialize_single_write_response(
-    message_data: ModbusMessageData,
-    mut data: std::io::Cursor<Vec<u8>>,
-) -> Result<ModbusResponse> {
-    let table = ModbusTable::get_table_from_function_code(message_data.function_code)
-        .ok_or_else(|| anyhow!("Function code doesn't address any table"))?;
-
-    let address = data.read_u16::<BigEndian>()?;
-
-    let raw_value = data.read_u16::<BigEndian>()?;
-
-    let value = match table {
-        ModbusTable::Coils | ModbusTable::DiscreteInput => {
-            ModbusDataType::coil_from_representation(raw_value)?
-        }
-        ModbusTable::InputRegisters | ModbusTable::HoldingRegisters => {
-            ModbusDataType::Register(raw_value)
-        }
-    };
-
-    let params = SingleWriteResponseParameters {
-        table,
-        address,
-        value,
-    };
-
-    Ok(ModbusResponse::SingleWriteResponse {
-        message_data,
-        params,
-    })
-}
-
-fn deserialize_read_response(
-    message_data: ModbusMessageData,
-    mut data: std::io::Cursor<Vec<u8>>,
-) -> Result<ModbusResponse> {
-    let table = ModbusTable::get_table_from_function_code(message_data.function_code)
-        .ok_or_else(|| anyhow!("Function code doesn't address any table"))?;
-
-    let values = crate::codec::utils::deserialize_values(table, None, &mut data)?;
-
-    let params = ReadResponseParameters { table, values };
-
-    Ok(ModbusResponse::ReadResponse {
-        message_data,
-        params,
-    })
-}
-
-fn deserialize_multiple_write_response(
-    message_data: ModbusMessageData,
-    mut data: std::io::Cursor<Vec<u8>>,
-) -> Result<ModbusResponse> {
-    let table = ModbusTable::get_table_from_function_code(message_data.function_code)
-        .ok_or_else(|| anyhow!("Function code doesn't address any table"))?;
-
-    let address = data.read_u16::<BigEndian>()?;
-
-    let ammount = data.read_u16::<BigEndian>()?;
-
-    let params = MultipleWriteResponse {
-        table,
-        address,
-        ammount,
-    };
-
-    Ok(ModbusResponse::MultipleWriteResponse {
-        message_data,
-        params,
-    })
-}
-
 #[cfg(test)]
 mod test {
     use super::*;
@@ -350,4 +154,23 @@ mod test {
 
         test_response_serialization(input);
     }
+
+    #[test]
+    fn test_serialization_deserialization_mask_write_response() {
+        let input = vec![ModbusResponse::MaskWriteResponse {
+            message_data: ModbusMessageData {
+                slave_id: 17,
+                function_code: FunctionCode::MaskWriteRegister,
+                transaction_id: Cell::new(Some(12)),
+            },
+            params: response::MaskWriteResponseParameters {
+                table: ModbusTable::HoldingRegisters,
+                address: 0x0004,
+                and_mask: 0x00F2,
+                or_mask: 0x0025,
+            },
+        }];
+
+        test_response_serialization(input);
+    }
 }
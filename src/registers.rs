@@ -0,0 +1,452 @@
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use anyhow::anyhow;
+
+use crate::common::{Address, ModbusAddress, ModbusDataType, ModbusError, ModbusTable, SlaveId};
+use crate::master::{
+    ModbusMasterConnection, MAX_READ_COILS, MAX_READ_REGISTERS, MAX_WRITE_COILS,
+    MAX_WRITE_REGISTERS,
+};
+use crate::messages::ExceptionCode;
+use crate::slave::ModbusCallBack;
+
+pub use tweakable_modbus_derive::ModbusRegisters;
+
+//Where the bytes of a value spanning several registers go, A being the most significant one
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RegisterOrder {
+    #[default]
+    ABCD,
+    CDAB,
+    BADC,
+    DCBA,
+}
+
+impl RegisterOrder {
+    //Both swaps undo themselves, so the same arrangement encodes and decodes
+    fn arrange(self, mut words: Vec<u16>) -> Vec<u16> {
+        if matches!(self, RegisterOrder::CDAB | RegisterOrder::DCBA) {
+            words.reverse();
+        }
+        if matches!(self, RegisterOrder::BADC | RegisterOrder::DCBA) {
+            words.iter_mut().for_each(|word| *word = word.swap_bytes());
+        }
+        words
+    }
+}
+
+impl FromStr for RegisterOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(order: &str) -> Result<Self, Self::Err> {
+        match order.to_ascii_uppercase().as_str() {
+            "ABCD" => Ok(RegisterOrder::ABCD),
+            "CDAB" => Ok(RegisterOrder::CDAB),
+            "BADC" => Ok(RegisterOrder::BADC),
+            "DCBA" => Ok(RegisterOrder::DCBA),
+            _ => Err(anyhow!(
+                "{} isn't an order, use ABCD, CDAB, BADC or DCBA",
+                order
+            )),
+        }
+    }
+}
+
+//Numbers that are stored in consecutive registers
+pub trait RegisterValue: Sized {
+    const REGISTERS: u16;
+
+    fn to_registers(&self, order: RegisterOrder) -> Vec<u16>;
+
+    //Missing registers are taken as zero
+    fn from_registers(registers: &[u16], order: RegisterOrder) -> Self;
+}
+
+macro_rules! register_value {
+    ($($ty:ty),*) => {
+        $(
+            impl RegisterValue for $ty {
+                const REGISTERS: u16 = (std::mem::size_of::<$ty>() / 2) as u16;
+
+                fn to_registers(&self, order: RegisterOrder) -> Vec<u16> {
+                    let words = self
+                        .to_be_bytes()
+                        .chunks(2)
+                        .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                        .collect();
+                    order.arrange(words)
+                }
+
+                fn from_registers(registers: &[u16], order: RegisterOrder) -> Self {
+                    let words = (0..Self::REGISTERS as usize)
+                        .map(|index| registers.get(index).copied().unwrap_or(0))
+                        .collect();
+                    let mut bytes = [0u8; std::mem::size_of::<$ty>()];
+                    for (pair, word) in bytes.chunks_mut(2).zip(order.arrange(words)) {
+                        pair.copy_from_slice(&word.to_be_bytes());
+                    }
+                    <$ty>::from_be_bytes(bytes)
+                }
+            }
+        )*
+    };
+}
+
+register_value!(u16, i16, u32, i32, u64, i64, f32, f64);
+
+//Value of a field as it is on the wire
+#[derive(Clone, Debug, PartialEq)]
+pub enum RawValue {
+    Registers(Vec<u16>),
+    Bit(bool),
+}
+
+impl RawValue {
+    pub fn encode<V: RegisterValue>(value: &V, order: RegisterOrder) -> Self {
+        RawValue::Registers(value.to_registers(order))
+    }
+
+    pub fn decode<V: RegisterValue>(&self, order: RegisterOrder) -> V {
+        match self {
+            RawValue::Registers(registers) => V::from_registers(registers, order),
+            RawValue::Bit(bit) => V::from_registers(&[*bit as u16], order),
+        }
+    }
+
+    pub fn bit(&self) -> bool {
+        match self {
+            RawValue::Registers(registers) => registers.first().is_some_and(|word| *word != 0),
+            RawValue::Bit(bit) => *bit,
+        }
+    }
+}
+
+//Location of one field of a register map, count is 1 for coils and discrete inputs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RegisterField {
+    pub table: ModbusTable,
+    pub address: Address,
+    pub count: u16,
+}
+
+impl RegisterField {
+    fn end(&self) -> u32 {
+        self.address as u32 + self.count as u32
+    }
+}
+
+//Struct whose fields live in the tables of a device, usually made with #[derive(ModbusRegisters)].
+//Fields are identified by their position in FIELDS
+pub trait ModbusRegisters: Sized {
+    const FIELDS: &'static [RegisterField];
+
+    //Gets a value for every field, in the order of FIELDS
+    fn from_raw(values: Vec<RawValue>) -> Self;
+
+    fn raw(&self, field: usize) -> RawValue;
+
+    fn set_raw(&mut self, field: usize, value: RawValue);
+}
+
+fn is_bit_table(table: ModbusTable) -> bool {
+    matches!(table, ModbusTable::Coils | ModbusTable::DiscreteInput)
+}
+
+//Fields of a table with nothing between them, read or written with a single request
+#[derive(Debug, PartialEq)]
+struct Run {
+    table: ModbusTable,
+    address: Address,
+    count: u16,
+    fields: Vec<usize>,
+}
+
+fn plan_runs(fields: &[RegisterField], write: bool) -> Vec<Run> {
+    let mut order: Vec<usize> = (0..fields.len())
+        .filter(|index| {
+            !write
+                || matches!(
+                    fields[*index].table,
+                    ModbusTable::Coils | ModbusTable::HoldingRegisters
+                )
+        })
+        .collect();
+    order.sort_by_key(|index| (fields[*index].table, fields[*index].address));
+
+    let mut runs: Vec<Run> = vec![];
+    for index in order {
+        let field = fields[index];
+        let limit = match (is_bit_table(field.table), write) {
+            (true, false) => MAX_READ_COILS,
+            (true, true) => MAX_WRITE_COILS,
+            (false, false) => MAX_READ_REGISTERS,
+            (false, true) => MAX_WRITE_REGISTERS,
+        };
+
+        if let Some(run) = runs.last_mut() {
+            let end = field.end().max(run.address as u32 + run.count as u32);
+            if run.table == field.table
+                && field.address as u32 <= run.address as u32 + run.count as u32
+                && end - run.address as u32 <= limit as u32
+            {
+                run.count = (end - run.address as u32) as u16;
+                run.fields.push(index);
+                continue;
+            }
+        }
+
+        runs.push(Run {
+            table: field.table,
+            address: field.address,
+            count: field.count,
+            fields: vec![index],
+        });
+    }
+
+    runs
+}
+
+//Reads every field with as few requests as the layout allows
+pub async fn read_registers<T: ModbusRegisters>(
+    master: &mut ModbusMasterConnection,
+    slave_id: SlaveId,
+) -> Result<T, ModbusError> {
+    let mut values = vec![None; T::FIELDS.len()];
+
+    for run in plan_runs(T::FIELDS, false) {
+        let (address, count) = (run.address, run.count);
+        let words: Vec<u16> = match run.table {
+            ModbusTable::Coils => to_words(master.read_coils(slave_id, address, count).await?),
+            ModbusTable::DiscreteInput => to_words(
+                master
+                    .read_discrete_inputs(slave_id, address, count)
+                    .await?,
+            ),
+            ModbusTable::HoldingRegisters => {
+                master
+                    .read_holding_registers(slave_id, address, count)
+                    .await?
+            }
+            ModbusTable::InputRegisters => {
+                master
+                    .read_input_registers(slave_id, address, count)
+                    .await?
+            }
+        };
+
+        for index in run.fields {
+            let field = T::FIELDS[index];
+            let offset = (field.address - run.address) as usize;
+            let registers = words
+                .get(offset..offset + field.count as usize)
+                .ok_or_else(|| ModbusError::Protocol("Response is shorter than asked".into()))?;
+
+            values[index] = Some(match is_bit_table(field.table) {
+                true => RawValue::Bit(registers[0] != 0),
+                false => RawValue::Registers(registers.to_vec()),
+            });
+        }
+    }
+
+    //Every field belongs to exactly one run
+    Ok(T::from_raw(values.into_iter().flatten().collect()))
+}
+
+fn to_words(bits: Vec<bool>) -> Vec<u16> {
+    bits.into_iter().map(u16::from).collect()
+}
+
+//Writes every coil and holding register field, the read only tables are left alone
+pub async fn write_registers<T: ModbusRegisters>(
+    registers: &T,
+    master: &mut ModbusMasterConnection,
+    slave_id: SlaveId,
+) -> Result<(), ModbusError> {
+    for run in plan_runs(T::FIELDS, true) {
+        let mut words = vec![0u16; run.count as usize];
+        for index in run.fields {
+            let offset = (T::FIELDS[index].address - run.address) as usize;
+            let value = match registers.raw(index) {
+                RawValue::Registers(value) => value,
+                RawValue::Bit(bit) => vec![bit as u16],
+            };
+            for (word, value) in words[offset..].iter_mut().zip(value) {
+                *word = value;
+            }
+        }
+
+        match run.table {
+            ModbusTable::Coils => {
+                let bits = words.into_iter().map(|word| word != 0).collect();
+                master
+                    .write_multiple_coils(slave_id, run.address, bits)
+                    .await?
+            }
+            _ => {
+                master
+                    .write_multiple_registers(slave_id, run.address, words)
+                    .await?
+            }
+        }
+    }
+
+    Ok(())
+}
+
+//Field holding the address and the position of the address within it
+fn find_field(fields: &[RegisterField], addr: &ModbusAddress) -> Option<(usize, usize)> {
+    fields.iter().enumerate().find_map(|(index, field)| {
+        let offset = addr.address.checked_sub(field.address)?;
+        (field.table == addr.table && offset < field.count).then_some((index, offset as usize))
+    })
+}
+
+//A server backed by a register map, the application keeps a clone to see what masters wrote.
+//Every unit id gets the same map
+#[async_trait::async_trait]
+impl<T: ModbusRegisters + Send + 'static> ModbusCallBack for Arc<Mutex<T>> {
+    async fn on_read(&self, addr: ModbusAddress) -> Result<ModbusDataType, ExceptionCode> {
+        let (index, offset) =
+            find_field(T::FIELDS, &addr).ok_or(ExceptionCode::IllegalDataAddress)?;
+        let registers = self.lock().unwrap_or_else(PoisonError::into_inner);
+
+        match registers.raw(index) {
+            RawValue::Bit(bit) => Ok(ModbusDataType::Coil(bit)),
+            RawValue::Registers(words) => words
+                .get(offset)
+                .map(|word| ModbusDataType::Register(*word))
+                .ok_or(ExceptionCode::IllegalDataAddress),
+        }
+    }
+
+    async fn on_write(
+        &self,
+        addr: ModbusAddress,
+        value: ModbusDataType,
+    ) -> Result<(), ExceptionCode> {
+        let (index, offset) =
+            find_field(T::FIELDS, &addr).ok_or(ExceptionCode::IllegalDataAddress)?;
+        let mut registers = self.lock().unwrap_or_else(PoisonError::into_inner);
+
+        //A register of a wider value only changes its own part of it
+        let raw = match (registers.raw(index), value) {
+            (RawValue::Bit(_), ModbusDataType::Coil(bit)) => RawValue::Bit(bit),
+            (RawValue::Registers(mut words), ModbusDataType::Register(word))
+                if offset < words.len() =>
+            {
+                words[offset] = word;
+                RawValue::Registers(words)
+            }
+            _ => return Err(ExceptionCode::IllegalDataValue),
+        };
+        registers.set_raw(index, raw);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::ModbusSubprotocol;
+    use crate::slave::ModbusSlaveConnection;
+    use tokio::time::Duration;
+
+    #[derive(ModbusRegisters, Clone, Debug, PartialEq)]
+    struct Drive {
+        #[modbus(holding, addr = 100, ty = "f32", order = "CDAB")]
+        speed: f32,
+        #[modbus(holding, addr = 102)]
+        ramp: u16,
+        #[modbus(holding, addr = 110, ty = "i32")]
+        position: i64,
+        #[modbus(input, addr = 0, order = "DCBA")]
+        temperature: f32,
+        #[modbus(coil, addr = 5)]
+        run: bool,
+        #[modbus(coil, addr = 6)]
+        reverse: bool,
+    }
+
+    fn drive() -> Drive {
+        Drive {
+            speed: 12.5,
+            ramp: 3,
+            position: -40,
+            temperature: 21.0,
+            run: true,
+            reverse: false,
+        }
+    }
+
+    #[test]
+    fn test_register_orders() {
+        let value: u32 = 0x11223344;
+        let orders = [
+            (RegisterOrder::ABCD, [0x1122, 0x3344]),
+            (RegisterOrder::CDAB, [0x3344, 0x1122]),
+            (RegisterOrder::BADC, [0x2211, 0x4433]),
+            (RegisterOrder::DCBA, [0x4433, 0x2211]),
+        ];
+
+        for (order, registers) in orders {
+            assert_eq!(value.to_registers(order), registers);
+            assert_eq!(u32::from_registers(&registers, order), value);
+        }
+        assert_eq!(
+            "cdab".parse::<RegisterOrder>().unwrap(),
+            RegisterOrder::CDAB
+        );
+        assert!("ACBD".parse::<RegisterOrder>().is_err());
+        assert_eq!(f64::REGISTERS, 4);
+        assert_eq!(i16::from_registers(&[0xFFFE], RegisterOrder::ABCD), -2);
+    }
+
+    #[test]
+    fn test_contiguous_fields_share_a_request() {
+        let ranges = |runs: Vec<Run>| {
+            runs.into_iter()
+                .map(|run| (run.table, run.address, run.count))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            ranges(plan_runs(Drive::FIELDS, false)),
+            vec![
+                (ModbusTable::Coils, 5, 2),
+                (ModbusTable::InputRegisters, 0, 2),
+                (ModbusTable::HoldingRegisters, 100, 3),
+                (ModbusTable::HoldingRegisters, 110, 2),
+            ]
+        );
+        assert_eq!(plan_runs(Drive::FIELDS, true).len(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_derived_map_on_both_ends() {
+        let shared = Arc::new(Mutex::new(drive()));
+        let server = ModbusSlaveConnection::new_in_memory(Box::new(shared.clone()));
+        let mut master = ModbusMasterConnection::new_in_memory(
+            &server,
+            ModbusSubprotocol::ModbusTCP,
+            Duration::ZERO,
+        );
+
+        assert_eq!(Drive::read(&mut master, 1).await, Ok(drive()));
+
+        let changed = Drive {
+            speed: -3.25,
+            position: 70000,
+            reverse: true,
+            ..drive()
+        };
+        changed.write(&mut master, 1).await.unwrap();
+        assert_eq!(*shared.lock().unwrap(), changed);
+
+        //Only the high word of the speed, the low word is kept
+        master.write_single_register(1, 101, 0x4120).await.unwrap();
+        assert_eq!(shared.lock().unwrap().speed, f32::from_bits(0x41200000));
+        assert!(master.write_single_register(1, 104, 1).await.is_err());
+    }
+}
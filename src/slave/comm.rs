@@ -1,33 +1,130 @@
 use anyhow::{anyhow, Result};
 use std::net::{SocketAddr};
-use tokio::net::TcpListener;
-use crate::communication::AddressingInfo;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::net::{TcpListener, UdpSocket};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use crate::common::ModbusSubprotocol;
+use crate::communication::{AddressingInfo, RtuPort, SerialSettings, StreamAcceptor};
+#[cfg(unix)]
+use crate::communication::{bind_unix, PeerCredentials};
+
+//Decides whether a client on a Unix socket is served, the connection is closed right away otherwise
+#[cfg(unix)]
+pub type PeerAuthorization = Arc<dyn Fn(&PeerCredentials) -> bool + Send + Sync>;
 
 pub struct ModbusSlaveCommunicationInfo {
     pub listener: Option<TcpListener>,
-    addressing_info: AddressingInfo
+    pub serial_port: Option<RtuPort>,
+    pub udp_socket: Option<Arc<UdpSocket>>,
+    #[cfg(unix)]
+    pub unix_listener: Option<UnixListener>,
+    #[cfg(unix)]
+    pub authorize_peer: Option<PeerAuthorization>,
+    pub acceptor: Option<(StreamAcceptor, ModbusSubprotocol)>,
+    //Acceptors come ready to use and have nothing to bind
+    addressing_info: Option<AddressingInfo>
 }
 
 impl ModbusSlaveCommunicationInfo {
+    fn new(addressing_info: Option<AddressingInfo>) -> Self
+    {
+        ModbusSlaveCommunicationInfo {
+            listener: None,
+            serial_port: None,
+            udp_socket: None,
+            #[cfg(unix)]
+            unix_listener: None,
+            #[cfg(unix)]
+            authorize_peer: None,
+            acceptor: None,
+            addressing_info,
+        }
+    }
+
     pub fn new_tcp(address: SocketAddr) -> Self
     {
-        let addressing_info = AddressingInfo::TcpConnection { address };
+        Self::new(Some(AddressingInfo::TcpConnection { address }))
+    }
+
+    pub fn new_rtu(device: String, settings: SerialSettings) -> Self
+    {
+        Self::new(Some(AddressingInfo::RtuConnection { device, settings }))
+    }
 
-        ModbusSlaveCommunicationInfo { listener: None, addressing_info}
+    pub fn new_udp(address: SocketAddr) -> Self
+    {
+        Self::new(Some(AddressingInfo::UdpConnection { address }))
+    }
+
+    #[cfg(unix)]
+    pub fn new_unix(path: PathBuf) -> Self
+    {
+        Self::new(Some(AddressingInfo::UnixConnection { path }))
+    }
+
+    //Links are made by in memory masters, there's nothing to bind or listen on
+    pub fn new_in_memory() -> Self
+    {
+        Self::new(None)
+    }
+
+    pub fn new_acceptor(acceptor: StreamAcceptor, subprotocol: ModbusSubprotocol) -> Self
+    {
+        ModbusSlaveCommunicationInfo { acceptor: Some((acceptor, subprotocol)), ..Self::new(None) }
     }
 
     pub async fn bind(& mut self) -> Result<()>
     {
-       if let AddressingInfo::TcpConnection { address } = self.addressing_info {
-            self.listener = Some(TcpListener::bind(address).await?);
+        let Some(addressing_info) = &self.addressing_info else {
             return Ok(());
-       }
-       
-       return Err(anyhow!("Rtu is not supported"));
+        };
+
+        match addressing_info {
+            AddressingInfo::TcpConnection { address } => {
+                self.listener = Some(TcpListener::bind(address).await?);
+            }
+            AddressingInfo::RtuConnection { device, settings } => {
+                self.serial_port = Some(settings.open(device)?);
+            }
+            AddressingInfo::UdpConnection { address } => {
+                self.udp_socket = Some(Arc::new(UdpSocket::bind(address).await?));
+            }
+            #[cfg(unix)]
+            AddressingInfo::UnixConnection { path } => {
+                self.unix_listener = Some(bind_unix(path)?);
+            }
+            AddressingInfo::InMemory { .. } | AddressingInfo::Stream { .. } => {
+                return Err(anyhow!("In memory and stream links are made by the master"));
+            }
+        }
+
+        Ok(())
+    }
+
+    //Lets a server asked for port 0 tell where it ended up
+    pub fn local_addr(& self) -> Result<SocketAddr>
+    {
+        if let Some(listener) = &self.listener {
+            return Ok(listener.local_addr()?);
+        }
+
+        if let Some(udp_socket) = &self.udp_socket {
+            return Ok(udp_socket.local_addr()?);
+        }
+
+        Err(anyhow!("Not bound to a network address"))
     }
 
     pub fn is_bound(& self) -> bool
     {
-        return self.listener.is_some()
+        #[cfg(unix)]
+        if self.unix_listener.is_some() {
+            return true;
+        }
+
+        self.listener.is_some() || self.serial_port.is_some() || self.udp_socket.is_some() || self.acceptor.is_some()
     }
-}
\ No newline at end of file
+}
@@ -1,20 +1,27 @@
 use crate::{
     codec::ModbusSerialize,
     common::{ModbusAddress, ModbusDataType, ModbusSubprotocol, SlaveId},
-    communication::ModbusSocket,
+    communication::{
+        recv_datagram, stream_acceptor, AsyncStream, MemoryConnector, MemorySocket, ModbusSocket,
+        SerialSettings,
+    },
     messages::{
+        query::MaskWriteQueryParameters,
         response::{self, ReadResponseParameters},
         ExceptionCode, ModbusQuery, ModbusResponse,
     },
     slave::comm::ModbusSlaveCommunicationInfo,
 };
-use anyhow::Result;
+#[cfg(unix)]
+use crate::communication::PeerCredentials;
+use anyhow::{anyhow, Result};
+use futures::{Stream, StreamExt};
+use tokio::net::UdpSocket;
 use std::{collections::HashSet, time::Duration};
 use std::{
     net::{IpAddr, SocketAddr},
     sync::Arc,
 };
-use tokio::net::TcpStream;
 
 mod comm;
 
@@ -37,19 +44,13 @@ impl ModbusSlaveConnectionParameters {
         allowed_ip_address: Option<Vec<IpAddr>>,
         connection_time_to_live: Duration,
     ) -> Self {
-        let allowed_slaves = match allowed_slaves {
-            Some(allowed_slaves) => Some(allowed_slaves.into_iter().collect::<HashSet<SlaveId>>()),
-            None => None,
-        };
+        let allowed_slaves =
+            allowed_slaves.map(|allowed_slaves| allowed_slaves.into_iter().collect::<HashSet<SlaveId>>());
 
         let allowed_slaves = Arc::new(allowed_slaves);
 
-        let allowed_ip_address = match allowed_ip_address {
-            Some(allowed_ip_address) => {
-                Some(allowed_ip_address.into_iter().collect::<HashSet<IpAddr>>())
-            }
-            None => None,
-        };
+        let allowed_ip_address = allowed_ip_address
+            .map(|allowed_ip_address| allowed_ip_address.into_iter().collect::<HashSet<IpAddr>>());
 
         Self {
             allowed_slaves,
@@ -75,6 +76,146 @@ impl ModbusSlaveConnection {
         ModbusSlaveConnection { comm,  callback}
     }
 
+    //Only reached by masters made with ModbusMasterConnection::new_in_memory, there is nothing to
+    //bind and serving it fails
+    pub fn new_in_memory(callback: Box<dyn ModbusCallBack>) -> Self {
+        let comm = ModbusSlaveCommunicationInfo::new_in_memory();
+
+        let callback = Arc::from(callback);
+        ModbusSlaveConnection { comm,  callback}
+    }
+
+    //Serves the serial line for as long as the port works, allowed_slaves picks the unit ids
+    //answered on a shared bus and the ip and time to live parameters don't apply
+    pub fn new_rtu(
+        device: &str,
+        settings: SerialSettings,
+        callback: Box<dyn ModbusCallBack>,
+    ) -> Self {
+        let comm = ModbusSlaveCommunicationInfo::new_rtu(device.to_string(), settings);
+
+        let callback = Arc::from(callback);
+        ModbusSlaveConnection { comm,  callback}
+    }
+
+    //Modbus TCP framing over datagrams, the time to live doesn't apply as there are no connections
+    pub fn new_udp(
+        address: SocketAddr,
+        callback: Box<dyn ModbusCallBack>,
+    ) -> Self {
+        let comm = ModbusSlaveCommunicationInfo::new_udp(address);
+
+        let callback = Arc::from(callback);
+        ModbusSlaveConnection { comm,  callback}
+    }
+
+    //Modbus TCP framing over a local socket, allowed_ip_address doesn't apply and clients are
+    //told apart by their credentials instead
+    #[cfg(unix)]
+    pub fn new_unix(
+        path: impl AsRef<std::path::Path>,
+        callback: Box<dyn ModbusCallBack>,
+    ) -> Self {
+        let comm = ModbusSlaveCommunicationInfo::new_unix(path.as_ref().to_path_buf());
+
+        let callback = Arc::from(callback);
+        ModbusSlaveConnection { comm,  callback}
+    }
+
+    //Called for every client of a Unix socket server before it's served
+    #[cfg(unix)]
+    pub fn authorize_peers(
+        &mut self,
+        authorize: impl Fn(&PeerCredentials) -> bool + Send + Sync + 'static,
+    ) {
+        self.comm.authorize_peer = Some(Arc::new(authorize));
+    }
+
+    //Serves every stream the acceptor yields, like Unix socket or tunnel connections. There are no
+    //peer addresses so allowed_ip_address doesn't apply
+    pub fn new_with_acceptor<A, S, E>(
+        incoming: A,
+        subprotocol: ModbusSubprotocol,
+        callback: Box<dyn ModbusCallBack>,
+    ) -> Self
+    where
+        A: Stream<Item = Result<S, E>> + Send + 'static,
+        S: AsyncStream + 'static,
+        E: Into<anyhow::Error> + 'static,
+    {
+        let comm = ModbusSlaveCommunicationInfo::new_acceptor(stream_acceptor(incoming), subprotocol);
+
+        let callback = Arc::from(callback);
+        ModbusSlaveConnection { comm,  callback}
+    }
+
+    //Links made by the connector are served right away with every unit answered, the server doesn't
+    //have to be bound or serving
+    pub(crate) fn in_memory_connector(
+        &self,
+        subprotocol: ModbusSubprotocol,
+        latency: Duration,
+    ) -> MemoryConnector {
+        let callback = self.callback.clone();
+
+        Arc::new(move || {
+            let (client, mut server) = MemorySocket::pair(latency);
+            let callback = callback.clone();
+
+            tokio::spawn(async move {
+                let _ = ModbusSlaveConnection::handle_connection(
+                    callback,
+                    &mut server,
+                    subprotocol,
+                    Arc::new(None),
+                    None,
+                )
+                .await;
+            });
+
+            client
+        })
+    }
+
+    //Every bit the and mask clears is handed to the callback on its own. A callback that doesn't
+    //take bits of the register gets it read and written back whole, which isn't atomic: two masters
+    //mask writing the register at once can lose each other's bits
+    async fn mask_write(
+        context: &dyn ModbusCallBack,
+        slave_id: SlaveId,
+        params: &MaskWriteQueryParameters,
+    ) -> Result<(), ExceptionCode> {
+        let bit_write = |bit: u8| {
+            let address = ModbusAddress::new_bit(slave_id, params.table, params.address, bit);
+            let value = ModbusDataType::Coil(params.or_mask & (1 << bit) != 0);
+            context.on_write(address, value)
+        };
+        let mut changed = (0..16u8).filter(|bit| params.and_mask & (1 << bit) == 0);
+
+        //How the first bit is taken tells whether the callback handles bits
+        if let Some(first) = changed.next() {
+            match bit_write(first).await {
+                Ok(()) => {
+                    for bit in changed {
+                        bit_write(bit).await?;
+                    }
+                    return Ok(());
+                }
+                Err(
+                    ExceptionCode::IllegalFunction
+                    | ExceptionCode::IllegalDataAddress
+                    | ExceptionCode::IllegalDataValue,
+                ) => {}
+                Err(exception_code) => return Err(exception_code),
+            }
+        }
+
+        let address = ModbusAddress::new(slave_id, params.table, params.address);
+        let current = context.on_read(address.clone()).await?.get_representation();
+        let value = (current & params.and_mask) | (params.or_mask & !params.and_mask);
+        context.on_write(address, ModbusDataType::Register(value)).await
+    }
+
     pub async fn handle_query(
         context: Arc<dyn ModbusCallBack>,
         query: ModbusQuery,
@@ -88,6 +229,7 @@ impl ModbusSlaveConnection {
                     table: params.table,
                     address: params.starting_address,
                     slave_id: message_data.slave_id,
+                    bit: None,
                 };
 
                 let result = context.on_write(address, params.value).await;
@@ -105,20 +247,21 @@ impl ModbusSlaveConnection {
                     value: params.value,
                 };
 
-                return Ok(ModbusResponse::SingleWriteResponse {
+                Ok(ModbusResponse::SingleWriteResponse {
                     message_data,
                     params,
-                });
+                })
             }
             ModbusQuery::MultipleWriteQuery {
                 message_data,
                 params,
             } => {
-                let mut results = vec![];
+                let mut ammount = 0u16;
                 let mut address = ModbusAddress {
                     table: params.table,
                     address: params.starting_address,
                     slave_id: message_data.slave_id,
+                    bit: None,
                 };
 
                 for value in params.values {
@@ -131,20 +274,20 @@ impl ModbusSlaveConnection {
                         });
                     }
 
-                    results.push(result.unwrap());
+                    ammount += 1;
                     address.address += 1;
                 }
 
                 let params = response::MultipleWriteResponse {
                     table: params.table,
                     address: params.starting_address,
-                    ammount: results.len() as u16,
+                    ammount,
                 };
 
-                return Ok(ModbusResponse::MultipleWriteResponse {
+                Ok(ModbusResponse::MultipleWriteResponse {
                     message_data,
                     params,
-                });
+                })
             }
             ModbusQuery::ReadQuery {
                 message_data,
@@ -156,6 +299,7 @@ impl ModbusSlaveConnection {
                     table: params.table,
                     address: params.starting_address,
                     slave_id: message_data.slave_id,
+                    bit: None,
                 };
 
                 for _index in 0..params.ammount {
@@ -177,10 +321,10 @@ impl ModbusSlaveConnection {
                     values: results,
                 };
 
-                return Ok(ModbusResponse::ReadResponse {
+                Ok(ModbusResponse::ReadResponse {
                     message_data,
                     params,
-                });
+                })
             }
             ModbusQuery::MultipleReadWriteQuery {
                 message_data,
@@ -192,6 +336,7 @@ impl ModbusSlaveConnection {
                     table: params.table,
                     address: params.write_starting_address,
                     slave_id: message_data.slave_id,
+                    bit: None,
                 };
 
                 for value in params.values {
@@ -209,6 +354,7 @@ impl ModbusSlaveConnection {
                     table: params.table,
                     address: params.read_starting_address,
                     slave_id: message_data.slave_id,
+                    bit: None,
                 };
 
                 for _index in 0..params.read_ammount {
@@ -235,50 +381,137 @@ impl ModbusSlaveConnection {
                     params,
                 })
             }
+            ModbusQuery::MaskWriteQuery {
+                message_data,
+                params,
+            } => {
+                let written = Self::mask_write(context.as_ref(), message_data.slave_id, &params).await;
+
+                if let Err(exception_code) = written {
+                    return Ok(ModbusResponse::Error {
+                        message_data,
+                        exception_code,
+                    });
+                }
+
+                let params = response::MaskWriteResponseParameters {
+                    table: params.table,
+                    address: params.address,
+                    and_mask: params.and_mask,
+                    or_mask: params.or_mask,
+                };
+
+                Ok(ModbusResponse::MaskWriteResponse {
+                    message_data,
+                    params,
+                })
+            }
         }
     }
 
-    pub async fn handle_connection(
+    //Without a time to live the connection is served until the socket fails
+    pub async fn handle_connection<S: ModbusSocket + ?Sized>(
         callback: Arc<dyn ModbusCallBack>,
-        mut socket: TcpStream,
+        socket: &mut S,
+        subprotocol: ModbusSubprotocol,
         allowed_slaves: Arc<Option<HashSet<SlaveId>>>,
-        connection_time_to_live: Duration,
+        connection_time_to_live: Option<Duration>,
     ) -> Result<()> {
         loop {
-            let bytes = match tokio::time::timeout(connection_time_to_live, socket.read()).await {
-                Ok(Ok(bytes)) => bytes,
-                Ok(Err(err)) => {
-                    return Err(err);
-                }
-                Err(_) => {
-                    break;
+            let bytes = match connection_time_to_live {
+                Some(connection_time_to_live) => {
+                    match tokio::time::timeout(connection_time_to_live, socket.read()).await {
+                        Ok(bytes) => bytes?,
+                        Err(_) => {
+                            break;
+                        }
+                    }
                 }
+                None => socket.read().await?,
             };
 
             if bytes.is_empty() {
                 continue;
             }
 
-            let queries =
-                crate::messages::ModbusQuery::deserialize(bytes, ModbusSubprotocol::ModbusTCP)?;
+            let responses =
+                Self::handle_frame(callback.clone(), bytes, subprotocol, &allowed_slaves).await?;
 
-            for query in queries {
-                if let Some(allowed_slaves) = allowed_slaves.as_ref() {
-                    if !allowed_slaves.contains(&query.get_message_data().slave_id) {
-                        continue;
-                    }
+            for response in responses {
+                socket.write(response).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    //Answers every query in a received frame, queries for units that aren't served get no response
+    async fn handle_frame(
+        callback: Arc<dyn ModbusCallBack>,
+        bytes: Vec<u8>,
+        subprotocol: ModbusSubprotocol,
+        allowed_slaves: &Option<HashSet<SlaveId>>,
+    ) -> Result<Vec<Vec<u8>>> {
+        let queries = crate::messages::ModbusQuery::deserialize(bytes, subprotocol)?;
+        let mut responses = vec![];
+
+        for query in queries {
+            let slave_id = query.get_message_data().slave_id;
+            //Serial broadcasts go to every unit on the bus and are never answered
+            let is_broadcast = subprotocol == ModbusSubprotocol::ModbusRTU && slave_id == 0;
+
+            if let Some(allowed_slaves) = allowed_slaves.as_ref() {
+                if !is_broadcast && !allowed_slaves.contains(&slave_id) {
+                    continue;
                 }
+            }
 
-                let response = Self::handle_query(callback.clone(), query).await?;
-                ModbusSocket::write(
-                    &mut socket,
-                    response.serialize(ModbusSubprotocol::ModbusTCP)?,
-                )
-                .await?;
+            let response = Self::handle_query(callback.clone(), query).await?;
+            if is_broadcast {
+                continue;
             }
+
+            responses.push(response.serialize(subprotocol)?);
         }
 
-        Ok(())
+        Ok(responses)
+    }
+
+    //Every datagram is answered on its own task, the reply goes back to the address it came from
+    async fn serve_udp(
+        callback: Arc<dyn ModbusCallBack>,
+        socket: Arc<UdpSocket>,
+        params: ModbusSlaveConnectionParameters,
+    ) -> Result<()> {
+        loop {
+            let (bytes, source) = recv_datagram(&socket).await?;
+
+            if let Some(allowed_ip_address) = params.allowed_ip_address.as_ref() {
+                if !allowed_ip_address.contains(&source.ip()) {
+                    continue;
+                }
+            }
+
+            let callback = callback.clone();
+            let socket = socket.clone();
+            let allowed_slaves = params.allowed_slaves.clone();
+
+            //A malformed datagram is dropped without affecting the others
+            tokio::spawn(async move {
+                let responses = ModbusSlaveConnection::handle_frame(
+                    callback,
+                    bytes,
+                    ModbusSubprotocol::ModbusTCP,
+                    &allowed_slaves,
+                )
+                .await?;
+
+                for response in responses {
+                    socket.send_to(&response, source).await?;
+                }
+                anyhow::Ok(())
+            });
+        }
     }
 
     pub async fn bind(&mut self) -> Result<()> {
@@ -287,13 +520,61 @@ impl ModbusSlaveConnection {
         }
         Ok(())
     }
+
+    //Address of a bound tcp or udp server, binding port 0 gets a free one
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.comm.local_addr()
+    }
+
     pub async fn server_with_parameters(
         &mut self,
         params: ModbusSlaveConnectionParameters,
     ) -> Result<()> {
         self.bind().await?;
 
-        let listener = self.comm.listener.as_ref().unwrap();
+        if let Some(serial_port) = self.comm.serial_port.as_mut() {
+            return ModbusSlaveConnection::handle_connection(
+                self.callback.clone(),
+                serial_port,
+                ModbusSubprotocol::ModbusRTU,
+                params.allowed_slaves.clone(),
+                None,
+            )
+            .await;
+        }
+
+        if let Some(udp_socket) = self.comm.udp_socket.clone() {
+            return Self::serve_udp(self.callback.clone(), udp_socket, params).await;
+        }
+
+        if let Some((acceptor, subprotocol)) = self.comm.acceptor.as_mut() {
+            while let Some(socket) = acceptor.next().await {
+                Self::spawn_connection(self.callback.clone(), socket?, *subprotocol, &params);
+            }
+            return Ok(());
+        }
+
+        #[cfg(unix)]
+        if let Some(unix_listener) = self.comm.unix_listener.as_ref() {
+            loop {
+                let (socket, _) = unix_listener.accept().await?;
+
+                if let Some(authorize_peer) = self.comm.authorize_peer.as_ref() {
+                    let authorized = PeerCredentials::of(&socket).is_ok_and(|peer| authorize_peer(&peer));
+                    if !authorized {
+                        continue;
+                    }
+                }
+
+                Self::spawn_connection(self.callback.clone(), socket, ModbusSubprotocol::ModbusTCP, &params);
+            }
+        }
+
+        let listener = self
+            .comm
+            .listener
+            .as_ref()
+            .ok_or_else(|| anyhow!("Nothing to serve, in memory servers are only reached by their masters"))?;
         loop {
             let (socket, addr) = listener.accept().await?;
 
@@ -307,26 +588,195 @@ impl ModbusSlaveConnection {
                 continue;
             }
 
-            let allowed_slaves = params.allowed_slaves.clone();
-            let callback = self.callback.clone();
-            let connection_time_to_live = params.connection_time_to_live.clone();
-
-            tokio::spawn(async move {
-                ModbusSlaveConnection::handle_connection(
-                    callback,
-                    socket,
-                    allowed_slaves,
-                    connection_time_to_live,
-                )
-                .await
-                .unwrap()
-            });
+            Self::spawn_connection(self.callback.clone(), socket, ModbusSubprotocol::ModbusTCP, &params);
         }
     }
 
+    //A broken connection only ends its own task
+    fn spawn_connection<S: ModbusSocket + 'static>(
+        callback: Arc<dyn ModbusCallBack>,
+        mut socket: S,
+        subprotocol: ModbusSubprotocol,
+        params: &ModbusSlaveConnectionParameters,
+    ) {
+        let allowed_slaves = params.allowed_slaves.clone();
+        let connection_time_to_live = params.connection_time_to_live;
+
+        tokio::spawn(async move {
+            let _ = ModbusSlaveConnection::handle_connection(
+                callback,
+                &mut socket,
+                subprotocol,
+                allowed_slaves,
+                Some(connection_time_to_live),
+            )
+            .await;
+        });
+    }
+
     pub fn serve(&mut self) -> impl std::future::Future<Output = Result<()>> + '_ {
         let params = ModbusSlaveConnectionParameters::new(None, None, Duration::from_secs(10));
 
         self.server_with_parameters(params)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::codec::rtu::serialize_frame;
+    use crate::communication::RtuPort;
+    use crate::master::test_utils::{pty_pair, MemoryCallBack};
+
+
+    #[tokio::test]
+    async fn test_mask_write_hands_bits_to_the_callback() {
+        use crate::common::ModbusTable;
+        use crate::messages::{FunctionCode, ModbusMessageData};
+        use std::sync::Mutex;
+
+        struct BitCallBack(Mutex<Vec<(ModbusAddress, ModbusDataType)>>);
+
+        #[async_trait::async_trait]
+        impl ModbusCallBack for BitCallBack {
+            async fn on_read(&self, _addr: ModbusAddress) -> Result<ModbusDataType, ExceptionCode> {
+                Err(ExceptionCode::IllegalFunction)
+            }
+
+            async fn on_write(&self, addr: ModbusAddress, value: ModbusDataType) -> Result<(), ExceptionCode> {
+                self.0.lock().unwrap().push((addr, value));
+                Ok(())
+            }
+        }
+
+        let callback = Arc::new(BitCallBack(Mutex::new(vec![])));
+        let query = ModbusQuery::MaskWriteQuery {
+            message_data: ModbusMessageData {
+                slave_id: 1,
+                function_code: FunctionCode::MaskWriteRegister,
+                transaction_id: std::cell::Cell::new(None),
+            },
+            params: MaskWriteQueryParameters {
+                table: ModbusTable::HoldingRegisters,
+                address: 7,
+                and_mask: !(1 << 3 | 1 << 12),
+                or_mask: 1 << 3,
+            },
+        };
+
+        let response = ModbusSlaveConnection::handle_query(callback.clone(), query).await.unwrap();
+        assert!(matches!(response, ModbusResponse::MaskWriteResponse { .. }));
+
+        //Only the bits cleared in the and mask, without reading the register
+        let bit = |bit, value| (ModbusAddress::new_bit(1, ModbusTable::HoldingRegisters, 7, bit), ModbusDataType::Coil(value));
+        assert_eq!(*callback.0.lock().unwrap(), vec![bit(3, true), bit(12, false)]);
+        assert_eq!(callback.0.lock().unwrap()[0].0.bit, Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_rtu_slave_over_pty() {
+        let (pty, path) = pty_pair();
+        let settings = SerialSettings::new(19200);
+
+        let callback = MemoryCallBack::with_test_registers();
+        let mut slave = ModbusSlaveConnection::new_rtu(&path, settings, Box::new(callback));
+        slave.bind().await.unwrap();
+        let params = ModbusSlaveConnectionParameters::new(Some(vec![1]), None, Duration::ZERO);
+        tokio::spawn(async move { slave.server_with_parameters(params).await });
+        let mut pty = RtuPort::new(pty, &settings);
+
+        //Holding register 2 of unit 1
+        pty.write(serialize_frame(1, &[0x03, 0x00, 0x02, 0x00, 0x01]))
+            .await
+            .unwrap();
+        assert_eq!(pty.read().await.unwrap(), serialize_frame(1, &[0x03, 0x02, 0x00, 20]));
+
+        //Neither broadcasts nor other units on the bus get an answer, so the first one is for the last read
+        pty.write(serialize_frame(0, &[0x06, 0x00, 0x02, 0x00, 0x07]))
+            .await
+            .unwrap();
+        pty.write(serialize_frame(2, &[0x03, 0x00, 0x02, 0x00, 0x01]))
+            .await
+            .unwrap();
+        pty.write(serialize_frame(1, &[0x03, 0x00, 0x03, 0x00, 0x01]))
+            .await
+            .unwrap();
+        assert_eq!(pty.read().await.unwrap(), serialize_frame(1, &[0x03, 0x02, 0x00, 30]));
+    }
+
+    #[tokio::test]
+    async fn test_udp_slave_filters_every_datagram() {
+        let callback = MemoryCallBack::with_test_registers();
+        let mut slave = ModbusSlaveConnection::new_udp("127.0.0.1:0".parse().unwrap(), Box::new(callback));
+        slave.bind().await.unwrap();
+        let address = slave.local_addr().unwrap();
+        let allowed_ip_address = Some(vec!["127.0.0.1".parse().unwrap()]);
+        let params = ModbusSlaveConnectionParameters::new(None, allowed_ip_address, Duration::ZERO);
+        tokio::spawn(async move { slave.server_with_parameters(params).await });
+
+        //Holding register 2 of unit 1
+        let query = [0, 1, 0, 0, 0, 6, 1, 0x03, 0x00, 0x02, 0x00, 0x01];
+        let mut buffer = [0u8; 64];
+
+        let denied = UdpSocket::bind("127.0.0.2:0").await.unwrap();
+        denied.send_to(&query, address).await.unwrap();
+        let answer = tokio::time::timeout(Duration::from_millis(200), denied.recv(&mut buffer)).await;
+        assert!(answer.is_err());
+
+        let allowed = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        allowed.send_to(&query, address).await.unwrap();
+        let n = allowed.recv(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..n], &[0, 1, 0, 0, 0, 5, 1, 0x03, 0x02, 0x00, 20]);
+    }
+
+    #[cfg(unix)]
+    async fn unix_server(name: &str, authorize: impl Fn(&PeerCredentials) -> bool + Send + Sync + 'static) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("tweakable-modbus-{}-{}.sock", std::process::id(), name));
+
+        let mut slave = ModbusSlaveConnection::new_unix(&path, Box::new(MemoryCallBack::with_test_registers()));
+        slave.authorize_peers(authorize);
+        slave.bind().await.unwrap();
+        tokio::spawn(async move { slave.serve().await });
+
+        path
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket_peers_are_identified() {
+        use crate::master::ModbusMasterConnection;
+        use std::sync::Mutex;
+
+        let peers = Arc::new(Mutex::new(vec![]));
+        let seen = peers.clone();
+        let path = unix_server("identified", move |peer| {
+            seen.lock().unwrap().push(*peer);
+            true
+        })
+        .await;
+
+        let mut master = ModbusMasterConnection::new_unix(&path);
+        assert_eq!(master.read_holding_registers(1, 2, 1).await, Ok(vec![20]));
+
+        let peers = peers.lock().unwrap();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].pid, Some(std::process::id() as i32));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unauthorized_unix_peers_are_dropped() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let path = unix_server("unauthorized", |_| false).await;
+
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream.write_all(&[0, 1, 0, 0, 0, 6, 1, 0x03, 0x00, 0x02, 0x00, 0x01]).await.unwrap();
+        let mut buffer = [0u8; 64];
+        //Closed without an answer, unread data makes it a reset
+        let answer = AsyncReadExt::read(&mut stream, &mut buffer).await;
+        assert!(matches!(answer, Ok(0) | Err(_)));
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,242 @@
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::quote;
+use syn::{
+    parse_macro_input, Data, DeriveInput, Error, Fields, Ident, LitInt, LitStr, Result, Type,
+};
+
+//Layout of one field as given by its #[modbus(...)] attribute
+struct ModbusField {
+    ident: Ident,
+    ty: Type,
+    table: Ident,
+    address: LitInt,
+    //Type the value has on the wire when it isn't the one of the field
+    wire_ty: Option<Type>,
+    order: Ident,
+}
+
+impl ModbusField {
+    fn parse(field: &syn::Field) -> Result<Self> {
+        let ident = field
+            .ident
+            .clone()
+            .ok_or_else(|| Error::new_spanned(field, "Fields need names"))?;
+        let attribute = field
+            .attrs
+            .iter()
+            .find(|attribute| attribute.path().is_ident("modbus"))
+            .ok_or_else(|| {
+                Error::new_spanned(field, "Every field needs a #[modbus(...)] attribute")
+            })?;
+
+        let mut table = None;
+        let mut address = None;
+        let mut wire_ty = None;
+        let mut order = Ident::new("ABCD", Span::call_site());
+
+        attribute.parse_nested_meta(|meta| {
+            let table_name = match &meta.path {
+                path if path.is_ident("holding") => Some("HoldingRegisters"),
+                path if path.is_ident("input") => Some("InputRegisters"),
+                path if path.is_ident("coil") => Some("Coils"),
+                path if path.is_ident("discrete") => Some("DiscreteInput"),
+                _ => None,
+            };
+
+            if let Some(table_name) = table_name {
+                if table.is_some() {
+                    return Err(meta.error("Only one table can be given"));
+                }
+                table = Some(Ident::new(table_name, Span::call_site()));
+            } else if meta.path.is_ident("addr") {
+                address = Some(meta.value()?.parse::<LitInt>()?);
+            } else if meta.path.is_ident("ty") {
+                let name: LitStr = meta.value()?.parse()?;
+                wire_ty = Some(name.parse::<Type>()?);
+            } else if meta.path.is_ident("order") {
+                let name: LitStr = meta.value()?.parse()?;
+                if !["ABCD", "CDAB", "BADC", "DCBA"].contains(&name.value().as_str()) {
+                    return Err(Error::new_spanned(
+                        name,
+                        "Order must be ABCD, CDAB, BADC or DCBA",
+                    ));
+                }
+                order = Ident::new(&name.value(), name.span());
+            } else {
+                return Err(
+                    meta.error("Expected holding, input, coil, discrete, addr, ty or order")
+                );
+            }
+            Ok(())
+        })?;
+
+        let table = table.ok_or_else(|| {
+            Error::new_spanned(
+                attribute,
+                "The table is missing, one of holding, input, coil or discrete",
+            )
+        })?;
+        let address = address
+            .ok_or_else(|| Error::new_spanned(attribute, "The address is missing, addr = ..."))?;
+
+        Ok(ModbusField {
+            ident,
+            ty: field.ty.clone(),
+            table,
+            address,
+            wire_ty,
+            order,
+        })
+    }
+
+    fn is_bit(&self) -> bool {
+        self.table == "Coils" || self.table == "DiscreteInput"
+    }
+
+    fn wire_ty(&self) -> &Type {
+        self.wire_ty.as_ref().unwrap_or(&self.ty)
+    }
+
+    //Casts only when the wire type differs from the field type
+    fn cast(&self, value: TokenStream2, to: &Type) -> TokenStream2 {
+        match &self.wire_ty {
+            Some(_) => quote!((#value as #to)),
+            None => value,
+        }
+    }
+
+    fn layout(&self, krate: &TokenStream2) -> TokenStream2 {
+        let table = &self.table;
+        let address = &self.address;
+        let wire_ty = self.wire_ty();
+        let count = match self.is_bit() {
+            true => quote!(1),
+            false => quote!(<#wire_ty as #krate::registers::RegisterValue>::REGISTERS),
+        };
+
+        quote! {
+            #krate::registers::RegisterField {
+                table: #krate::ModbusTable::#table,
+                address: #address,
+                count: #count,
+            }
+        }
+    }
+
+    fn decode(&self, raw: TokenStream2, krate: &TokenStream2) -> TokenStream2 {
+        if self.is_bit() {
+            return quote!(#raw.bit());
+        }
+
+        let order = &self.order;
+        let wire_ty = self.wire_ty();
+        let value = quote!(#raw.decode::<#wire_ty>(#krate::registers::RegisterOrder::#order));
+        self.cast(value, &self.ty)
+    }
+
+    fn encode(&self, krate: &TokenStream2) -> TokenStream2 {
+        let ident = &self.ident;
+        if self.is_bit() {
+            return quote!(#krate::registers::RawValue::Bit(self.#ident));
+        }
+
+        let order = &self.order;
+        let value = self.cast(quote!(self.#ident), self.wire_ty());
+        quote!(#krate::registers::RawValue::encode(&#value, #krate::registers::RegisterOrder::#order))
+    }
+}
+
+//Maps the fields of a struct onto the tables of a device:
+//#[modbus(holding | input | coil | discrete, addr = 100, ty = "f32", order = "CDAB")]
+//ty is the type on the wire and defaults to the field type, order only matters for values wider than a register
+#[proc_macro_derive(ModbusRegisters, attributes(modbus))]
+pub fn derive_modbus_registers(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: DeriveInput) -> Result<TokenStream2> {
+    let Data::Struct(data) = &input.data else {
+        return Err(Error::new_spanned(
+            &input,
+            "ModbusRegisters can only be derived for structs",
+        ));
+    };
+    let Fields::Named(named) = &data.fields else {
+        return Err(Error::new_spanned(
+            &input,
+            "ModbusRegisters needs a struct with named fields",
+        ));
+    };
+
+    let fields = named
+        .named
+        .iter()
+        .map(ModbusField::parse)
+        .collect::<Result<Vec<_>>>()?;
+
+    let krate = quote!(::tweakable_modbus);
+    let name = &input.ident;
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+
+    let layouts = fields.iter().map(|field| field.layout(&krate));
+    let indexes: Vec<usize> = (0..fields.len()).collect();
+    let idents: Vec<&Ident> = fields.iter().map(|field| &field.ident).collect();
+    let from_raw = fields
+        .iter()
+        .zip(&indexes)
+        .map(|(field, index)| field.decode(quote!(values[#index]), &krate));
+    let set_raw = fields
+        .iter()
+        .map(|field| field.decode(quote!(value), &krate));
+    let encode = fields.iter().map(|field| field.encode(&krate));
+
+    Ok(quote! {
+        impl #impl_generics #krate::registers::ModbusRegisters for #name #type_generics #where_clause {
+            const FIELDS: &'static [#krate::registers::RegisterField] = &[#(#layouts),*];
+
+            fn from_raw(values: ::std::vec::Vec<#krate::registers::RawValue>) -> Self {
+                Self {
+                    #(#idents: #from_raw,)*
+                }
+            }
+
+            fn raw(&self, field: usize) -> #krate::registers::RawValue {
+                match field {
+                    #(#indexes => #encode,)*
+                    _ => panic!("{} has no field {}", stringify!(#name), field),
+                }
+            }
+
+            fn set_raw(&mut self, field: usize, value: #krate::registers::RawValue) {
+                match field {
+                    #(#indexes => self.#idents = #set_raw,)*
+                    _ => panic!("{} has no field {}", stringify!(#name), field),
+                }
+            }
+        }
+
+        impl #impl_generics #name #type_generics #where_clause {
+            //Reads the whole struct, fields next to each other in a table share a request
+            pub async fn read(
+                master: &mut #krate::ModbusMasterConnection,
+                slave_id: u8,
+            ) -> ::std::result::Result<Self, #krate::ModbusError> {
+                #krate::registers::read_registers(master, slave_id).await
+            }
+
+            //Writes the coil and holding register fields
+            pub async fn write(
+                &self,
+                master: &mut #krate::ModbusMasterConnection,
+                slave_id: u8,
+            ) -> ::std::result::Result<(), #krate::ModbusError> {
+                #krate::registers::write_registers(self, master, slave_id).await
+            }
+        }
+    })
+}